@@ -1,10 +1,15 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::channels::DeviceValues;
 use crate::storage::Insert;
 use crate::{definitions::{AggregatorAction, TransportAction}, storage::SqliteStorageAction};
 use chrono::Utc;
 use serde_json::json;
 
+// Messages that storage did not store in time are sent without rowid and can not be acknowledged
+const STORAGE_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
 // TODO:
 // Collect statistics about amount of datapoints per channel
 // or somethings like how fast channel reads x number of devices
@@ -12,19 +17,22 @@ use serde_json::json;
 pub struct Aggregator {
     aggregator_rx: Receiver<AggregatorAction>,
     storage_tx: Sender<SqliteStorageAction>,
-    transport_tx: Sender<TransportAction>
+    transport_tx: Sender<TransportAction>,
+    device_values: DeviceValues
 }
 
 impl Aggregator {
     pub fn new(
         aggregator_rx: Receiver<AggregatorAction>,
         storage_tx: Sender<SqliteStorageAction>,
-        transport_tx: Sender<TransportAction>
+        transport_tx: Sender<TransportAction>,
+        device_values: DeviceValues
     ) -> Self {
         Self {
             aggregator_rx,
             storage_tx,
-            transport_tx
+            transport_tx,
+            device_values
         }
     }
 
@@ -45,6 +53,16 @@ impl Aggregator {
                                 // Take device name from either of the attributes or timeseries
                                 let device_name = timeseries.0;
 
+                                // Latest values are served by server channels, telemetry is in chronological order
+                                {
+                                    let mut device_values = self.device_values.lock().unwrap_or_else(|e| e.into_inner());
+                                    let values = device_values.entry(device_name.clone()).or_default();
+                                    values.extend(attributes.1.iter().map(|(key, value)| (key.clone(), value.clone())));
+                                    for telemetry in &timeseries.1 {
+                                        values.extend(telemetry.values.iter().map(|(key, value)| (key.clone(), value.clone())));
+                                    }
+                                }
+
                                 let timeseries_message = json!({
                                     &device_name: timeseries.1
                                 }).to_string();
@@ -55,29 +73,77 @@ impl Aggregator {
                                 log::trace!("JSON Attributes: {:?}", attributes_message);
                                 log::trace!("JSON Timeseries: {:?}", timeseries_message);
                                 
-                                // This is unsafe as fuck
-                                match self.storage_tx.send(SqliteStorageAction::InsertBoth(Insert{
+                                let insert = Insert{
+                                    rowid: None,
                                     ts,
                                     device_name,
                                     timeseries_message: Some(timeseries_message.clone()),
                                     attributes_message: Some(attributes_message.clone())
                                 // String
-                                })) {
-                                    Ok(_) => log::trace!("Sent messages: {} and {} to storage!", attributes_message, timeseries_message),
+                                };
+                                // Message is stored first, transport acknowledges it by its rowid once published
+                                let (stored_tx, stored_rx) = mpsc::channel();
+                                let insert = match self.storage_tx.send(SqliteStorageAction::InsertBoth { insert: insert.clone(), reply: stored_tx }) {
+                                    Ok(_) => match stored_rx.recv_timeout(STORAGE_REPLY_TIMEOUT) {
+                                        Ok(stored) => {
+                                            log::trace!("Stored messages: {} and {}", attributes_message, timeseries_message);
+                                            stored
+                                        },
+                                        Err(e) => {
+                                            log::error!("Storage did not store messages: {} and {}... {:?}", attributes_message, timeseries_message, e);
+                                            insert
+                                        }
+                                    },
                                     Err(e) => {
-                                        log::error!("Error sending messages: {} and {} to SqliteStorage!... {:?}", attributes_message, timeseries_message, e)
+                                        log::error!("Error sending messages: {} and {} to SqliteStorage!... {:?}", attributes_message, timeseries_message, e);
+                                        insert
                                     }
-                                }
-                                // As are these...
-                                match self.transport_tx.send(TransportAction::SendTimeseries(timeseries_message.clone())) {
-                                    Ok(_) => log::debug!("SentTimeseries to transport with message: {}", timeseries_message),
+                                };
+                                // As is this...
+                                match self.transport_tx.send(TransportAction::SendBoth(insert)) {
+                                    Ok(_) => log::debug!("SentBoth to transport with messages: {} and {}", attributes_message, timeseries_message),
                                     Err(e) => log::error!("Error while sending a message to trasport channel: {:?}",e)
                                 };
-                                match self.transport_tx.send(TransportAction::SendAttributes(attributes_message.clone())) {
-                                    Ok(_) => log::debug!("SentAttributes to transport with message: {}", attributes_message),
+                            },
+                            AggregatorAction::SendRpcResponse(response) => {
+                                // ThingsBoard gateway RPC reply format
+                                let data = match response.result {
+                                    Ok(_) => json!({"success": true}),
+                                    Err(e) => json!({"success": false, "error": e})
+                                };
+                                let rpc_message = json!({
+                                    "device": response.device,
+                                    "id": response.id,
+                                    "data": data
+                                }).to_string();
+                                match self.transport_tx.send(TransportAction::SendRpcResponse(rpc_message.clone())) {
+                                    Ok(_) => log::debug!("SentRpcResponse to transport with message: {}", rpc_message),
+                                    Err(e) => log::error!("Error while sending a message to trasport channel: {:?}",e)
+                                };
+                            },
+                            AggregatorAction::DeviceConnected(device_name, device_type) => {
+                                // Device type is applied by ThingsBoard only when it creates the device
+                                let connect_message = match device_type {
+                                    Some(device_type) => json!({"device": device_name, "type": device_type}),
+                                    None => json!({"device": device_name})
+                                }.to_string();
+                                match self.transport_tx.send(TransportAction::SendConnect(connect_message.clone())) {
+                                    Ok(_) => log::debug!("SentConnect to transport with message: {}", connect_message),
                                     Err(e) => log::error!("Error while sending a message to trasport channel: {:?}",e)
                                 };
                             },
+                            AggregatorAction::DeviceDisconnected(device_name) => {
+                                self.device_values.lock().unwrap_or_else(|e| e.into_inner()).remove(&device_name);
+                                let disconnect_message = json!({"device": device_name}).to_string();
+                                match self.transport_tx.send(TransportAction::SendDisconnect(disconnect_message.clone())) {
+                                    Ok(_) => log::debug!("SentDisconnect to transport with message: {}", disconnect_message),
+                                    Err(e) => log::error!("Error while sending a message to trasport channel: {:?}",e)
+                                };
+                            },
+                            AggregatorAction::Stop => {
+                                log::info!("Stopping aggregator...");
+                                break;
+                            },
                             // AggregatorAction::SendStatistics(stats) => {}
                         };
                     }
@@ -0,0 +1,444 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender, RecvTimeoutError, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+
+use crate::definitions::AggregatorAction;
+use super::{Channel, ChannelAction, ChannelStatus, DeviceRoutes, DeviceValues};
+use super::modbus::{ModbusClientTcpConfig, ModbusClientRtuConfig, ModbusClientRtuSocketConfig, ModbusRegisterMap, ModbusServerTcpConfig, ModbusServerUnit, ModbusSlave};
+use super::modbus::tcp::ModbusTcpChannel;
+use super::modbus::rtu::ModbusRtuChannel;
+use super::modbus::socket::{ModbusRtuSocketChannel, RtuSocketProtocol};
+use super::modbus::server::ModbusTcpServerChannel;
+
+// How often are channel threads checked for panics
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Backoff before restarting a panicked channel doubles with every failure up to the max
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+// Channel that panics after running at least this long starts with the shortest backoff again
+const STABLE_RUN: Duration = Duration::from_secs(600);
+// Channel blocked in a long serial or libmodbus call is not waited for longer, it exits on its own
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parsed channel config with register maps of its slaves, variants are named after ChannelType
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
+pub enum ChannelSetup {
+    ModbusTcp(ModbusClientTcpConfig, HashMap<ModbusSlave, ModbusRegisterMap>),
+    ModbusRtu(ModbusClientRtuConfig, HashMap<ModbusSlave, ModbusRegisterMap>),
+    ModbusRtuOverTcp(ModbusClientRtuSocketConfig, HashMap<ModbusSlave, ModbusRegisterMap>),
+    ModbusUdp(ModbusClientRtuSocketConfig, HashMap<ModbusSlave, ModbusRegisterMap>),
+    ModbusTcpServer(ModbusServerTcpConfig, HashMap<ModbusServerUnit, ModbusRegisterMap>)
+}
+
+impl ChannelSetup {
+    /// Slaves polled by the channel, server units are not routed RPC requests
+    pub fn slaves(&self) -> &[ModbusSlave] {
+        match self {
+            ChannelSetup::ModbusTcp(config, _) => &config.slaves,
+            ChannelSetup::ModbusRtu(config, _) => &config.slaves,
+            ChannelSetup::ModbusRtuOverTcp(config, _) | ChannelSetup::ModbusUdp(config, _) => &config.slaves,
+            ChannelSetup::ModbusTcpServer(_, _) => &[]
+        }
+    }
+
+    fn run(self, aggregator_tx: Sender<AggregatorAction>, channel_rx: Receiver<ChannelAction>, device_values: DeviceValues) -> JoinHandle<()> {
+        match self {
+            ChannelSetup::ModbusTcp(config, register_maps) => ModbusTcpChannel::new(config, register_maps, aggregator_tx, channel_rx).run(),
+            ChannelSetup::ModbusRtu(config, register_maps) => ModbusRtuChannel::new(config, register_maps, aggregator_tx, channel_rx).run(),
+            ChannelSetup::ModbusRtuOverTcp(config, register_maps) => {
+                ModbusRtuSocketChannel::new(RtuSocketProtocol::Tcp, config, register_maps, aggregator_tx, channel_rx).run()
+            },
+            ChannelSetup::ModbusUdp(config, register_maps) => {
+                ModbusRtuSocketChannel::new(RtuSocketProtocol::Udp, config, register_maps, aggregator_tx, channel_rx).run()
+            },
+            ChannelSetup::ModbusTcpServer(config, register_maps) => {
+                ModbusTcpServerChannel::new(config, register_maps, device_values, aggregator_tx, channel_rx).run()
+            }
+        }
+    }
+}
+
+/// State of a channel as tracked by ChannelController
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelState {
+    pub name: String,
+    pub status: ChannelStatus,
+    pub last_error: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub stopped_at: Option<DateTime<Utc>>,
+    pub restarts: u32
+}
+
+/// Commands for ChannelController, channels are identified by name
+/// eg: path of their channel config
+pub enum ControllerAction {
+    // Starts the channel, running channel with the same name is stopped first
+    Start(String, ChannelSetup),
+    // Stops the channel if it is running and starts it with the new setup eg: after its config changed
+    Restart(String, ChannelSetup),
+    // Stops the channel and forgets it
+    Remove(String),
+    Status(Sender<Vec<ChannelState>>),
+    // Stops all channels, replies once they exited
+    StopAll(Sender<()>)
+}
+
+struct RunningChannel {
+    channel_tx: Sender<ChannelAction>,
+    // Receives panic message of the channel thread once it exits, None when it exited normally
+    exited_rx: Receiver<Option<String>>,
+    started: Instant
+}
+
+struct ControlledChannel {
+    setup: ChannelSetup,
+    state: ChannelState,
+    running: Option<RunningChannel>,
+    failures: u32,
+    restart_at: Option<Instant>
+}
+
+/// Owns threads of all channels, restarts channels that panicked with backoff
+/// and keeps device routes of running channels
+pub struct ChannelController {
+    controller_rx: Receiver<ControllerAction>,
+    aggregator_tx: Sender<AggregatorAction>,
+    device_routes: DeviceRoutes,
+    // Name of the channel whose route is registered for the device
+    route_owners: HashMap<String, String>,
+    device_values: DeviceValues,
+    channels: HashMap<String, ControlledChannel>
+}
+
+impl ChannelController {
+    pub fn new(
+        controller_rx: Receiver<ControllerAction>,
+        aggregator_tx: Sender<AggregatorAction>,
+        device_routes: DeviceRoutes,
+        device_values: DeviceValues
+    ) -> Self {
+        Self {
+            controller_rx,
+            aggregator_tx,
+            device_routes,
+            route_owners: HashMap::new(),
+            device_values,
+            channels: HashMap::new()
+        }
+    }
+
+    pub fn run(mut self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            loop {
+                let timeout = self.channels.values()
+                    .filter_map(|channel| channel.restart_at)
+                    .map(|restart_at| restart_at.saturating_duration_since(Instant::now()))
+                    .fold(CHECK_INTERVAL, Duration::min);
+                match self.controller_rx.recv_timeout(timeout) {
+                    Ok(action) => self.process(action),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => {
+                        log::info!("Controller channel closed, stopping all channels...");
+                        self.stop_all();
+                        break;
+                    }
+                }
+                self.check_exited();
+                self.restart_due();
+            }
+        })
+    }
+
+    fn process(&mut self, action: ControllerAction) {
+        match action {
+            ControllerAction::Restart(name, setup) => match self.channels.contains_key(&name) {
+                true => self.process(ControllerAction::Start(name, setup)),
+                false => log::warn!("Can not restart unknown channel: {}", name)
+            },
+            ControllerAction::Start(name, setup) => {
+                let restarts = match self.channels.remove(&name) {
+                    Some(mut channel) => {
+                        log::info!("Restarting channel {} with new setup", name);
+                        self.stop_channel(&mut channel);
+                        channel.state.restarts + 1
+                    },
+                    None => 0
+                };
+                let mut channel = ControlledChannel {
+                    setup,
+                    state: ChannelState {
+                        name: name.clone(),
+                        status: ChannelStatus::Stopped,
+                        last_error: None,
+                        started_at: None,
+                        stopped_at: None,
+                        restarts
+                    },
+                    running: None,
+                    failures: 0,
+                    restart_at: None
+                };
+                self.start_channel(&mut channel);
+                self.channels.insert(name, channel);
+            },
+            ControllerAction::Remove(name) => match self.channels.remove(&name) {
+                Some(mut channel) => {
+                    log::info!("Removing channel {}", name);
+                    self.stop_channel(&mut channel);
+                },
+                None => log::warn!("Can not remove unknown channel: {}", name)
+            },
+            ControllerAction::Status(reply) => {
+                let mut states: Vec<ChannelState> = self.channels.values().map(|channel| channel.state.clone()).collect();
+                states.sort_by(|a, b| a.name.cmp(&b.name));
+                if let Err(e) = reply.send(states) {
+                    log::error!("Error replying with channel states: {:?}", e);
+                }
+            },
+            ControllerAction::StopAll(reply) => {
+                self.stop_all();
+                if let Err(e) = reply.send(()) {
+                    log::error!("Error replying to stop of all channels: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn start_channel(&mut self, channel: &mut ControlledChannel) {
+        let (channel_tx, channel_rx) = mpsc::channel::<ChannelAction>();
+        register_device_routes(&self.device_routes, &mut self.route_owners, &channel.state.name, channel.setup.slaves(), channel_tx.clone());
+        let handle = channel.setup.clone().run(self.aggregator_tx.clone(), channel_rx, self.device_values.clone());
+        // Channel threads are joined here, so that controller only has to poll exited_rx
+        let (exited_tx, exited_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let panic_message = handle.join().err().map(panic_message);
+            exited_tx.send(panic_message).ok();
+        });
+        log::info!("Started channel {}", channel.state.name);
+        channel.state.status = ChannelStatus::Running;
+        channel.state.started_at = Some(Utc::now());
+        channel.restart_at = None;
+        channel.running = Some(RunningChannel {
+            channel_tx,
+            exited_rx,
+            started: Instant::now()
+        });
+    }
+
+    /// Waits until the channel finishes its current poll, at most STOP_TIMEOUT
+    fn stop_channel(&mut self, channel: &mut ControlledChannel) {
+        if let Some(running) = self.signal_stop(channel) {
+            await_stopped(channel, running, Instant::now() + STOP_TIMEOUT);
+        }
+    }
+
+    /// Sends Stop to the channel, returns it when it was running
+    fn signal_stop(&mut self, channel: &mut ControlledChannel) -> Option<RunningChannel> {
+        channel.restart_at = None;
+        let running = match channel.running.take() {
+            Some(running) => running,
+            None => {
+                channel.state.status = ChannelStatus::Stopped;
+                return None;
+            }
+        };
+        remove_device_routes(&self.device_routes, &mut self.route_owners, &channel.state.name, channel.setup.slaves());
+        if let Err(e) = running.channel_tx.send(ChannelAction::Stop) {
+            log::warn!("Channel {} is not running: {:?}", channel.state.name, e);
+        }
+        Some(running)
+    }
+
+    /// All channels are stopped at once, so that they share one STOP_TIMEOUT
+    fn stop_all(&mut self) {
+        let names: Vec<String> = self.channels.keys().cloned().collect();
+        let mut stopping = Vec::new();
+        for name in names {
+            if let Some(mut channel) = self.channels.remove(&name) {
+                let running = self.signal_stop(&mut channel);
+                stopping.push((name, channel, running));
+            }
+        }
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        for (name, mut channel, running) in stopping {
+            if let Some(running) = running {
+                await_stopped(&mut channel, running, deadline);
+            }
+            self.channels.insert(name, channel);
+        }
+    }
+
+    /// Channels that panicked are scheduled for restart
+    fn check_exited(&mut self) {
+        for channel in self.channels.values_mut() {
+            let exited = match &channel.running {
+                Some(running) => match running.exited_rx.try_recv() {
+                    Ok(panic_message) => Some((panic_message, running.started.elapsed())),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => Some((Some(String::from("Channel monitor exited")), running.started.elapsed()))
+                },
+                None => None
+            };
+            let (panic_message, ran_for) = match exited {
+                Some(exited) => exited,
+                None => continue
+            };
+            channel.running = None;
+            channel.state.stopped_at = Some(Utc::now());
+            remove_device_routes(&self.device_routes, &mut self.route_owners, &channel.state.name, channel.setup.slaves());
+            match panic_message {
+                Some(panic_message) => {
+                    if ran_for >= STABLE_RUN {
+                        channel.failures = 0;
+                    }
+                    let backoff = restart_backoff(channel.failures);
+                    channel.failures += 1;
+                    log::error!("Channel {} panicked: {}, restarting in {:?}", channel.state.name, panic_message, backoff);
+                    channel.state.status = ChannelStatus::Error;
+                    channel.state.last_error = Some(panic_message);
+                    channel.restart_at = Some(Instant::now() + backoff);
+                },
+                None => {
+                    log::warn!("Channel {} exited", channel.state.name);
+                    channel.state.status = ChannelStatus::Stopped;
+                }
+            }
+        }
+    }
+
+    fn restart_due(&mut self) {
+        let now = Instant::now();
+        let due: Vec<String> = self.channels.iter()
+            .filter(|(_, channel)| channel.restart_at.is_some_and(|restart_at| restart_at <= now))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in due {
+            if let Some(mut channel) = self.channels.remove(&name) {
+                channel.state.restarts += 1;
+                self.start_channel(&mut channel);
+                self.channels.insert(name, channel);
+            }
+        }
+    }
+}
+
+/// Channel that does not exit until the deadline is left running, it got Stop and exits once it is unblocked
+fn await_stopped(channel: &mut ControlledChannel, running: RunningChannel, deadline: Instant) {
+    match running.exited_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(Some(panic_message)) => {
+            log::error!("Channel {} panicked while stopping: {}", channel.state.name, panic_message);
+            channel.state.last_error = Some(panic_message);
+        },
+        Err(RecvTimeoutError::Timeout) => {
+            log::error!("Channel {} did not stop in time, it is no longer waited for", channel.state.name);
+            channel.state.last_error = Some(String::from("Did not stop in time"));
+        },
+        Ok(None) | Err(RecvTimeoutError::Disconnected) => {}
+    }
+    log::info!("Stopped channel {}", channel.state.name);
+    channel.state.status = ChannelStatus::Stopped;
+    channel.state.stopped_at = Some(Utc::now());
+}
+
+fn restart_backoff(failures: u32) -> Duration {
+    RESTART_BACKOFF.saturating_mul(2u32.saturating_pow(failures)).min(MAX_RESTART_BACKOFF)
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => String::from("Unknown panic")
+        }
+    }
+}
+
+/// Every slave of a channel is routed to the Sender of that channel
+fn register_device_routes(device_routes: &DeviceRoutes, route_owners: &mut HashMap<String, String>, name: &str, slaves: &[ModbusSlave], channel_tx: Sender<ChannelAction>) {
+    let mut routes = device_routes.lock().unwrap();
+    for slave in slaves {
+        routes.insert(slave.device_name.clone(), channel_tx.clone());
+        if route_owners.insert(slave.device_name.clone(), name.to_string()).is_some_and(|owner| owner != name) {
+            log::warn!("Device {} is defined in multiple channels, RPC requests are routed to the last one", slave.device_name);
+        }
+    }
+}
+
+/// Routes of devices that were taken over by another channel are kept
+fn remove_device_routes(device_routes: &DeviceRoutes, route_owners: &mut HashMap<String, String>, name: &str, slaves: &[ModbusSlave]) {
+    let mut routes = device_routes.lock().unwrap();
+    for slave in slaves {
+        if route_owners.get(&slave.device_name).is_some_and(|owner| owner == name) {
+            route_owners.remove(&slave.device_name);
+            routes.remove(&slave.device_name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use super::{restart_backoff, panic_message, await_stopped, register_device_routes, remove_device_routes,
+        ChannelSetup, ChannelState, ControlledChannel, RunningChannel};
+    use crate::channels::{ChannelStatus, DeviceRoutes};
+    use crate::channels::modbus::ModbusSlave;
+
+    #[test]
+    fn restart_backoff_doubles_up_to_max() {
+        assert_eq!(restart_backoff(0), Duration::from_secs(1));
+        assert_eq!(restart_backoff(3), Duration::from_secs(8));
+        assert_eq!(restart_backoff(9), Duration::from_secs(300));
+        assert_eq!(restart_backoff(100), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn panic_message_of_thread() {
+        let payload = std::thread::spawn(|| panic!("Could not open serial port: {}", "/dev/ttyUSB0")).join().unwrap_err();
+        assert_eq!(panic_message(payload), "Could not open serial port: /dev/ttyUSB0");
+        let payload = std::thread::spawn(|| std::panic::panic_any(7)).join().unwrap_err();
+        assert_eq!(panic_message(payload), "Unknown panic");
+    }
+
+    #[test]
+    fn routes_are_removed_by_their_owner() {
+        let slaves: Vec<ModbusSlave> = serde_yaml::from_str("[{ device_name: Meter1, modbus_id: 1, register_map: map.yml }]").unwrap();
+        let routes: DeviceRoutes = Arc::new(Mutex::new(HashMap::new()));
+        let mut owners = HashMap::new();
+        let (first_tx, _first_rx) = mpsc::channel();
+        let (second_tx, _second_rx) = mpsc::channel();
+        register_device_routes(&routes, &mut owners, "first.yml", &slaves, first_tx);
+        register_device_routes(&routes, &mut owners, "second.yml", &slaves, second_tx);
+        remove_device_routes(&routes, &mut owners, "first.yml", &slaves);
+        assert!(routes.lock().unwrap().contains_key("Meter1"));
+        remove_device_routes(&routes, &mut owners, "second.yml", &slaves);
+        assert!(routes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stuck_channel_is_not_waited_for() {
+        let config = serde_yaml::from_str("{ name: Stuck, host: localhost, port: 502, slaves: [] }").unwrap();
+        let mut channel = ControlledChannel {
+            setup: ChannelSetup::ModbusTcp(config, HashMap::new()),
+            state: ChannelState { name: String::from("stuck.yml"), status: ChannelStatus::Running, last_error: None, started_at: None, stopped_at: None, restarts: 0 },
+            running: None,
+            failures: 0,
+            restart_at: None
+        };
+        // Channel thread never exits
+        let (_exited_tx, exited_rx) = mpsc::channel();
+        let (channel_tx, _channel_rx) = mpsc::channel();
+        let running = RunningChannel { channel_tx, exited_rx, started: Instant::now() };
+        let started = Instant::now();
+        await_stopped(&mut channel, running, Instant::now() + Duration::from_millis(50));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(channel.state.status, ChannelStatus::Stopped);
+        assert!(channel.state.last_error.is_some());
+    }
+}
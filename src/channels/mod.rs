@@ -1,29 +1,66 @@
-use std::{thread::JoinHandle, collections::HashMap};
+use std::{thread::JoinHandle, collections::{HashMap, HashSet}};
+use std::sync::{Arc, Mutex, mpsc::Sender};
 
 use serde_yaml::Error;
 use chrono::{Utc};
 
-use crate::definitions::OneTelemetry;
+use crate::definitions::{OneTelemetry, DataPointValue, RpcRequest, AggregatorAction};
 
 pub mod modbus;
+pub mod controller;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ChannelStatus {
     Running,
     Stopped,
     Error,
 }
 
+/// Actions that other parts of the gateway send to a running channel
+#[derive(Debug)]
+pub enum ChannelAction {
+    Rpc(RpcRequest),
+    // Channel finishes current poll and its thread exits
+    Stop,
+}
+
+/// Device name -> Sender of the channel that owns the device.
+/// Transports use it to route RPC requests to channels
+pub type DeviceRoutes = Arc<Mutex<HashMap<String, Sender<ChannelAction>>>>;
+
+/// Device name -> latest value of every key collected from the device.
+/// Kept by the aggregator for server channels, devices are removed once they disconnect
+pub type DeviceValues = Arc<Mutex<HashMap<String, HashMap<String, DataPointValue>>>>;
+
+/// Devices of a channel that answered on their last poll.
+/// Devices that were never polled count as unreachable
+#[derive(Debug, Default)]
+pub struct DeviceReachability {
+    reachable: HashSet<String>
+}
+
+impl DeviceReachability {
+    /// Records the result of polling a device, returns connect/disconnect
+    /// action for the aggregator only when reachability of the device changed
+    pub fn update(&mut self, device_name: &str, device_type: &Option<String>, reachable: bool) -> Option<AggregatorAction> {
+        if reachable && self.reachable.insert(device_name.to_string()) {
+            log::info!("Device {} is reachable", device_name);
+            Some(AggregatorAction::DeviceConnected(device_name.to_string(), device_type.clone()))
+        } else if !reachable && self.reachable.remove(device_name) {
+            log::warn!("Device {} stopped answering", device_name);
+            Some(AggregatorAction::DeviceDisconnected(device_name.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
 pub trait ChannelConfig {
     fn serialize(config_string: String) -> Result<Self, Error> where Self: Sized;
 }
 
-// Todo:
-// Implement some way to terminate/restart channels from
-// something like a "ChannelController". This controller will
-// hold status and restart specified channel if its configuration
-// was changed at runtime
+// Channels are started, stopped and restarted by controller::ChannelController
 pub trait Channel {
     // Each channel has to serialize it into its special 
     fn run(self) -> JoinHandle<()>;
@@ -33,17 +70,25 @@ pub trait Channel {
 #[derive(Debug, Clone)]
 pub struct DataPoint {
     pub key: String,
-    pub value: String,
+    pub value: DataPointValue,
+    pub unit: Option<String>,
     pub ts: Option<i64>
 }
 
+impl DataPoint {
+    /// Unit is sent to transports as a device attribute eg: `L1_Voltage_unit: V`
+    pub fn unit_attribute(&self) -> Option<(String, DataPointValue)> {
+        self.unit.as_ref().map(|unit| (format!("{}_unit", self.key), DataPointValue::String(unit.clone())))
+    }
+}
+
 impl From<Vec<DataPoint>> for OneTelemetry {
     /// Returns an OneTelemetry struct with ts that is
     /// taken when calling .into()
     fn from(input: Vec<DataPoint>) -> Self {
         let ts: i64 = Utc::now().timestamp_millis();
 
-        let mut values: HashMap<String,String> = HashMap::new();
+        let mut values: HashMap<String,DataPointValue> = HashMap::new();
         for point in input {
             values.insert(point.key, point.value);
         }
@@ -52,4 +97,28 @@ impl From<Vec<DataPoint>> for OneTelemetry {
             values
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::definitions::AggregatorAction;
+    use super::DeviceReachability;
+
+    #[test]
+    fn reachability_changes_only() {
+        let mut reachability = DeviceReachability::default();
+        let device_type = Some(String::from("meter"));
+        // Never answered, nothing to disconnect
+        assert!(reachability.update("Meter1", &device_type, false).is_none());
+        assert!(matches!(
+            reachability.update("Meter1", &device_type, true),
+            Some(AggregatorAction::DeviceConnected(name, Some(t))) if name == "Meter1" && t == "meter"
+        ));
+        assert!(reachability.update("Meter1", &device_type, true).is_none());
+        assert!(matches!(
+            reachability.update("Meter1", &device_type, false),
+            Some(AggregatorAction::DeviceDisconnected(name)) if name == "Meter1"
+        ));
+        assert!(reachability.update("Meter1", &device_type, false).is_none());
+    }
+}
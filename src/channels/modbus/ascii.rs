@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
+
+use super::rtu::RtuStream;
+
+// Unit id, function, up to 252 bytes of data and LRC, every byte as 2 hex characters
+const MAX_FRAME_CHARS: usize = 2 * 256;
+
+/// Translates RTU frames written by the RTU channel to Modbus ASCII frames
+/// (':' start, hex encoded payload with LRC, CRLF end) and received ASCII frames back to RTU frames,
+/// so that request generation and response parsing are shared with the RTU channel
+pub struct AsciiStream<S: RtuStream> {
+    inner: S,
+    // Received frame converted to RTU, it is read in parts
+    buffer: VecDeque<u8>
+}
+
+impl<S: RtuStream> AsciiStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            buffer: VecDeque::new()
+        }
+    }
+
+    /// Reads characters of one frame between ':' and CRLF, characters before ':' are skipped
+    fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        let mut byte = [0u8; 1];
+        loop {
+            self.inner.read_exact(&mut byte)?;
+            if byte[0] == b':' {
+                break;
+            }
+        }
+        let mut frame = Vec::new();
+        loop {
+            self.inner.read_exact(&mut byte)?;
+            match byte[0] {
+                b'\n' if frame.last() == Some(&b'\r') => {
+                    frame.pop();
+                    return Ok(frame);
+                },
+                // Start of a new frame, previous one was not finished
+                b':' => frame.clear(),
+                c => frame.push(c)
+            }
+            if frame.len() > MAX_FRAME_CHARS + 1 {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Modbus ASCII frame is too long"));
+            }
+        }
+    }
+}
+
+impl<S: RtuStream> Read for AsciiStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() {
+            let frame = self.read_frame()?;
+            log::trace!("Received ASCII frame: {:?}", String::from_utf8_lossy(&frame));
+            self.buffer.extend(decode_frame(&frame)?);
+        }
+        let count = buf.len().min(self.buffer.len());
+        for (byte, received) in buf.iter_mut().zip(self.buffer.drain(..count)) {
+            *byte = received;
+        }
+        Ok(count)
+    }
+}
+
+impl<S: RtuStream> Write for AsciiStream<S> {
+    /// Whole RTU frame has to be written at once
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frame = encode_frame(buf)?;
+        log::trace!("Sending ASCII frame: {:?}", String::from_utf8_lossy(&frame));
+        self.inner.write_all(&frame)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: RtuStream> RtuStream for AsciiStream<S> {
+    fn clear(&mut self, buffer_to_clear: serialport::ClearBuffer) -> io::Result<()> {
+        if !matches!(buffer_to_clear, serialport::ClearBuffer::Output) {
+            self.buffer.clear();
+        }
+        self.inner.clear(buffer_to_clear)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+}
+
+/// Converts RTU frame (unit id, PDU, CRC) to ASCII frame
+pub fn encode_frame(rtu_frame: &[u8]) -> io::Result<Vec<u8>> {
+    if rtu_frame.len() < 4 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "RTU frame is too short"));
+    }
+    let (payload, crc) = rtu_frame.split_at(rtu_frame.len() - 2);
+    if crc16(payload).to_le_bytes() != crc {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "RTU frame has invalid CRC"));
+    }
+    let mut frame = Vec::with_capacity(2 * rtu_frame.len() + 3);
+    frame.push(b':');
+    frame.extend(hex::encode_upper(payload).into_bytes());
+    frame.extend(format!("{:02X}", lrc(payload)).into_bytes());
+    frame.extend_from_slice(b"\r\n");
+    Ok(frame)
+}
+
+/// Converts characters of ASCII frame between ':' and CRLF to RTU frame, LRC is checked and replaced with CRC
+pub fn decode_frame(characters: &[u8]) -> io::Result<Vec<u8>> {
+    let mut payload = hex::decode(characters)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Modbus ASCII frame is not hex encoded: {:?}", e)))?;
+    if payload.len() < 3 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Modbus ASCII frame is too short"));
+    }
+    let received_lrc = payload.pop().unwrap();
+    if lrc(&payload) != received_lrc {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Modbus ASCII frame has invalid LRC: {:02X}", received_lrc)));
+    }
+    let crc = crc16(&payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    Ok(payload)
+}
+
+/// Two's complement of the sum of all bytes
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg()
+}
+
+/// CRC-16/MODBUS of RTU frame, it is sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::time::Duration;
+    use super::{AsciiStream, encode_frame, decode_frame, lrc, crc16};
+    use crate::channels::modbus::engine::{read_group, ModbusError, ModbusTransport};
+    use crate::channels::modbus::rtu::{RtuStream, RtuTiming, RtuTransport};
+    use crate::channels::modbus::{ModbusGroupData, ModbusRegisterGroup};
+
+    /// Serial line with responses already waiting in it, reads time out when they were all read
+    struct FakeLine(VecDeque<u8>);
+
+    impl Read for FakeLine {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = buf.len().min(self.0.len());
+            if count == 0 {
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            for (byte, received) in buf.iter_mut().zip(self.0.drain(..count)) {
+                *byte = received;
+            }
+            Ok(count)
+        }
+    }
+
+    impl Write for FakeLine {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl RtuStream for FakeLine {
+        fn clear(&mut self, _buffer_to_clear: serialport::ClearBuffer) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(lrc(&[1, 3, 0, 0, 0, 2]), 0xFA);
+        assert_eq!(crc16(&[1, 3, 0, 0, 0, 2]).to_le_bytes(), [0xC4, 0x0B]);
+    }
+
+    #[test]
+    fn frames_are_converted_both_ways() {
+        let rtu = [1, 3, 0, 0, 0, 2, 0xC4, 0x0B];
+        assert_eq!(encode_frame(&rtu).unwrap(), b":010300000002FA\r\n".to_vec());
+        assert_eq!(decode_frame(b"010300000002FA").unwrap(), rtu.to_vec());
+        assert!(decode_frame(b"010300000002FB").is_err());
+        assert!(encode_frame(&[1, 3, 0, 0, 0, 2, 0xC4, 0x0C]).is_err());
+    }
+
+    /// Response with invalid LRC followed by the valid one
+    fn transport() -> RtuTransport<AsciiStream<FakeLine>> {
+        let line = FakeLine(b":01030400010002F6\r\n:01030400010002F5\r\n".iter().copied().collect());
+        let timing = RtuTiming { response_timeout: Duration::from_secs(1), silent_interval: Duration::from_secs(1), request_delay: Duration::ZERO };
+        RtuTransport::new(AsciiStream::new(line), timing)
+    }
+
+    #[test]
+    fn garbled_frame_is_requested_again() {
+        assert!(matches!(transport().transact(1, &[3, 0, 0, 0, 2]), Err(ModbusError::Garbled(_))));
+        let group: ModbusRegisterGroup = serde_yaml::from_str("{ starting_address: 0, elements_count: 2, data_points: [] }").unwrap();
+        assert_eq!(read_group(&mut transport(), 1, &group).unwrap(), ModbusGroupData::Registers(vec![1, 2]));
+    }
+}
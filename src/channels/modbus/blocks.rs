@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+
+use super::scheduler::ModbusPollInterval;
+use super::{ModbusDataPointReader, ModbusDataType, ModbusEndianness, ModbusReadFunction, ModbusRegisterGroup};
+
+// Most registers and bits a single read request may ask for
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_READ_BITS: u16 = 2000;
+// Unused registers read to join two blocks when the register map does not set max_gap
+pub const DEFAULT_MAX_GAP: u16 = 10;
+
+/// Data point addressed by its absolute register (or coil/input) address,
+/// points are joined into read blocks when the register map is loaded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModbusRegisterPoint {
+    #[serde(default)]
+    pub function: ModbusReadFunction,
+    pub address: u16,
+    pub register_count: Option<usize>, // Defaults to size of data_type, required for string
+    pub data_type: ModbusDataType,
+    pub key_name: String,
+    pub endianness: Option<ModbusEndianness>,
+    pub bit_offset: Option<u8>,
+    pub bit_length: Option<u8>,
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+    pub scale_factor_address: Option<u16>, // Address of int16 register holding the exponent (SunSpec)
+    pub unit: Option<String>,
+    pub poll_interval: Option<ModbusPollInterval>, // Overrides poll_interval of the slave
+}
+
+impl ModbusRegisterPoint {
+    fn register_count(&self) -> Result<u16, String> {
+        if self.function.is_bit_function() {
+            return match self.data_type {
+                ModbusDataType::Bool => Ok(1),
+                _ => Err(format!("Data type {:?} of {} can not be read from coils or discrete inputs", self.data_type, self.key_name))
+            };
+        }
+        let count = match self.register_count.or_else(|| self.data_type.register_count()) {
+            Some(count) => count,
+            None => return Err(format!("String {} needs register_count", self.key_name))
+        };
+        match u16::try_from(count) {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err(format!("Invalid register_count {} of {}", count, self.key_name))
+        }
+    }
+
+    /// First and last address that have to be read for this point
+    fn span(&self) -> Result<(u16, u16), String> {
+        let last = self.address.checked_add(self.register_count()? - 1)
+            .ok_or_else(|| format!("{} does not fit in the address space", self.key_name))?;
+        Ok(match self.scale_factor_address {
+            Some(sf) => (self.address.min(sf), last.max(sf)),
+            None => (self.address, last)
+        })
+    }
+
+    /// Data point reader relative to the block starting at `start`
+    fn reader(&self, start: u16) -> Result<ModbusDataPointReader, String> {
+        let index = (self.address - start) as usize;
+        Ok(ModbusDataPointReader {
+            // Bits are indexed by coil, registers by byte
+            data_offset: if self.function.is_bit_function() { index } else { 2 * index },
+            register_count: self.register_count()? as usize,
+            data_type: self.data_type.clone(),
+            key_name: self.key_name.clone(),
+            endianness: self.endianness,
+            bit_offset: self.bit_offset,
+            bit_length: self.bit_length,
+            scale: self.scale,
+            offset: self.offset,
+            scale_factor_register: self.scale_factor_address.map(|sf| (sf - start) as usize),
+            unit: self.unit.clone(),
+        })
+    }
+}
+
+/// Addresses that must never be read, eg: registers the device answers with an exception.
+/// Both ends are included, without function the range applies to all read functions
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModbusAddressRange {
+    pub function: Option<ModbusReadFunction>,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl ModbusAddressRange {
+    fn overlaps(&self, function: ModbusReadFunction, start: u16, end: u16) -> bool {
+        (self.function.is_none() || self.function == Some(function)) && self.start <= end && start <= self.end
+    }
+}
+
+/// Joins points into as few read blocks as possible. Points read with different function
+/// or poll interval never share a block, a block is at most 125 registers (2000 bits) long,
+/// skips at most `max_gap` unused addresses and never touches a forbidden range
+pub fn build_read_blocks(points: &[ModbusRegisterPoint], max_gap: u16, forbidden: &[ModbusAddressRange]) -> Result<Vec<ModbusRegisterGroup>, String> {
+    let mut spans = Vec::with_capacity(points.len());
+    for point in points {
+        let (start, end) = point.span()?;
+        if let Some(range) = forbidden.iter().find(|range| range.overlaps(point.function, start, end)) {
+            return Err(format!("{} at {} overlaps forbidden range {}-{}", point.key_name, point.address, range.start, range.end));
+        }
+        if end - start + 1 > read_limit(point.function) {
+            return Err(format!("{} at {} can not be read with a single request", point.key_name, point.address));
+        }
+        spans.push((start, end, point));
+    }
+    spans.sort_by_key(|(start, end, point)| (point.function.code(), *start, *end));
+
+    // Block being built: start, end and its points
+    let mut blocks: Vec<(u16, u16, Vec<&ModbusRegisterPoint>)> = Vec::new();
+    for (start, end, point) in spans {
+        let joined = blocks.iter_mut().rev().find(|(_, _, block_points)| {
+            block_points[0].function == point.function && block_points[0].poll_interval == point.poll_interval
+        }).filter(|(block_start, block_end, _)| {
+            let joined_end = end.max(*block_end);
+            start as u32 <= *block_end as u32 + 1 + max_gap as u32
+                && joined_end - *block_start < read_limit(point.function)
+                && !forbidden.iter().any(|range| range.overlaps(point.function, *block_start, joined_end))
+        });
+        match joined {
+            Some((_, block_end, block_points)) => {
+                *block_end = end.max(*block_end);
+                block_points.push(point);
+            },
+            None => blocks.push((start, end, vec![point]))
+        }
+    }
+
+    blocks.into_iter().map(|(start, end, block_points)| {
+        Ok(ModbusRegisterGroup {
+            function: block_points[0].function,
+            starting_address: start,
+            elements_count: end - start + 1,
+            poll_interval: block_points[0].poll_interval.clone(),
+            data_points: block_points.iter().map(|point| point.reader(start)).collect::<Result<_, String>>()?,
+            data: None
+        })
+    }).collect()
+}
+
+/// Most registers (or coils/inputs) that can be read by a single request
+pub fn read_limit(function: ModbusReadFunction) -> u16 {
+    if function.is_bit_function() { MAX_READ_BITS } else { MAX_READ_REGISTERS }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_read_blocks, ModbusAddressRange, ModbusRegisterPoint};
+    use crate::channels::modbus::ModbusReadFunction;
+
+    fn points(yaml: &str) -> Vec<ModbusRegisterPoint> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn blocks(points: &[ModbusRegisterPoint], max_gap: u16, forbidden: &[ModbusAddressRange]) -> Vec<(u8, u16, u16)> {
+        build_read_blocks(points, max_gap, forbidden).unwrap().iter()
+            .map(|group| (group.function.code(), group.starting_address, group.elements_count))
+            .collect()
+    }
+
+    #[test]
+    fn points_are_joined_into_blocks() {
+        let points = points(r#"
+- { address: 10, data_type: float, key_name: Voltage }
+- { address: 0, data_type: uint16, key_name: Status }
+- { address: 14, data_type: int16, key_name: Power, scale_factor_address: 20 }
+- { address: 40, data_type: uint16, key_name: Far }
+- { function: coils, address: 3, data_type: bool, key_name: Relay }
+"#);
+        assert_eq!(blocks(&points, 10, &[]), vec![(1, 3, 1), (3, 0, 21), (3, 40, 1)]);
+        assert_eq!(blocks(&points, 2, &[]), vec![(1, 3, 1), (3, 0, 1), (3, 10, 11), (3, 40, 1)]);
+
+        let groups = build_read_blocks(&points, 10, &[]).unwrap();
+        let power = groups[1].data_points.iter().find(|p| p.key_name == "Power").unwrap();
+        assert_eq!(power.data_offset, 28);
+        assert_eq!(power.scale_factor_register, Some(20));
+        let voltage = groups[1].data_points.iter().find(|p| p.key_name == "Voltage").unwrap();
+        assert_eq!((voltage.data_offset, voltage.register_count), (20, 2));
+    }
+
+    #[test]
+    fn blocks_respect_limits() {
+        let points = points(r#"
+- { address: 0, data_type: uint16, key_name: First }
+- { address: 100, data_type: double, key_name: Second }
+- { address: 124, data_type: uint16, key_name: Third }
+- { address: 125, data_type: uint16, key_name: Fourth }
+"#);
+        assert_eq!(blocks(&points, 100, &[]), vec![(3, 0, 125), (3, 125, 1)]);
+
+        let forbidden = vec![ModbusAddressRange { function: None, start: 50, end: 60 }];
+        assert_eq!(blocks(&points, 100, &forbidden), vec![(3, 0, 1), (3, 100, 26)]);
+        // Forbidden range of another function does not split blocks
+        let coils = vec![ModbusAddressRange { function: Some(ModbusReadFunction::Coils), start: 50, end: 60 }];
+        assert_eq!(blocks(&points, 100, &coils), vec![(3, 0, 125), (3, 125, 1)]);
+    }
+
+    #[test]
+    fn invalid_points_are_rejected() {
+        let forbidden = vec![ModbusAddressRange { function: None, start: 1, end: 1 }];
+        assert!(build_read_blocks(&points("[{ address: 0, data_type: uint32, key_name: A }]"), 10, &forbidden).is_err());
+        assert!(build_read_blocks(&points("[{ address: 0, data_type: string, key_name: A }]"), 10, &[]).is_err());
+        assert!(build_read_blocks(&points("[{ function: coils, address: 0, data_type: uint16, key_name: A }]"), 10, &[]).is_err());
+        assert!(build_read_blocks(&points("[{ address: 0, data_type: string, register_count: 126, key_name: A }]"), 10, &[]).is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::mpsc;
+
+use crate::channels::{ChannelAction, DataPoint, DeviceReachability};
+use crate::definitions::{AggregatorAction, AttributeMessage, OneTelemetry, RpcResponse, TimeseriesMessage};
+
+use super::scheduler::{DuePoll, PollScheduler};
+use super::rpc::{prepare_write, ModbusWrite, ModbusWriteFunction};
+use super::{ModbusGroupData, ModbusReadFunction, ModbusRegisterGroup, ModbusRegisterMap, ModbusSlave, ModbusSlaveId};
+
+// Garbled responses are requested again, timeouts are not as the slave is probably not there
+const GARBLED_RETRIES: u32 = 1;
+// Most coils and registers one write request can carry, byte count of the request is a single byte
+const MAX_WRITE_COILS: usize = 1968;
+const MAX_WRITE_REGISTERS: usize = 123;
+
+/// Carries request PDU (function code and data) to a slave and its response PDU back,
+/// framing of the PDU is up to the transport (MBAP header, RTU CRC, ASCII LRC)
+pub trait ModbusTransport {
+    fn transact(&mut self, modbus_id: ModbusSlaveId, request: &[u8]) -> Result<Vec<u8>, ModbusError>;
+    // Called after every batch of due slaves was polled and after every RPC write
+    fn end_cycle(&mut self) {}
+}
+
+/// Why a request got no valid response
+#[derive(Debug)]
+pub enum ModbusError {
+    Io(io::Error),
+    // Transport could not reach the server or line, no request was sent
+    NotConnected(String),
+    // No response within response timeout
+    Timeout,
+    // Response is incomplete, has wrong checksum or does not belong to the request
+    Garbled(String),
+    Exception(u8)
+}
+
+impl fmt::Display for ModbusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModbusError::Io(e) => write!(f, "I/O error: {:?}", e),
+            ModbusError::NotConnected(e) => write!(f, "Not connected: {}", e),
+            ModbusError::Timeout => write!(f, "No response within timeout"),
+            ModbusError::Garbled(e) => write!(f, "Garbled response: {}", e),
+            ModbusError::Exception(code) => write!(f, "Exception {}: {}", code, exception_name(*code))
+        }
+    }
+}
+
+impl From<io::Error> for ModbusError {
+    fn from(e: io::Error) -> Self {
+        ModbusError::Io(e)
+    }
+}
+
+pub fn exception_name(code: u8) -> &'static str {
+    match code {
+        0x01 => "Illegal function",
+        0x02 => "Illegal data address",
+        0x03 => "Illegal data value",
+        0x04 => "Slave device failure",
+        0x05 => "Acknowledge",
+        0x06 => "Slave device busy",
+        0x07 => "Negative acknowledge",
+        0x08 => "Memory parity error",
+        0x0A => "Gateway path unavailable",
+        0x0B => "Gateway target device failed to respond",
+        _ => "Unknown exception"
+    }
+}
+
+/// Polls slaves over the transport until the channel is stopped,
+/// RPC requests are handled in between polls
+pub fn poll_slaves<T: ModbusTransport + ?Sized>(
+        transport: &mut T,
+        register_maps: &HashMap<ModbusSlave, ModbusRegisterMap>,
+        aggregator: &mpsc::Sender<AggregatorAction>,
+        channel_rx: &mpsc::Receiver<ChannelAction>) {
+    let mut scheduler = PollScheduler::new(register_maps);
+    let mut reachability = DeviceReachability::default();
+    loop {
+        // Wait for next poll, RPC requests are handled in between
+        match channel_rx.recv_timeout(scheduler.time_until_next()) {
+            Ok(ChannelAction::Rpc(request)) => {
+                log::info!("Received RPC request {} {} for device {}", request.id, request.method, request.device);
+                let result = prepare_write(register_maps, &request)
+                    .and_then(|write| execute_write(transport, &write));
+                transport.end_cycle();
+                if let Err(e) = &result {
+                    log::error!("RPC request {} failed: {}", request.id, e);
+                }
+                let response = RpcResponse { device: request.device, id: request.id, result };
+                if let Err(e) = aggregator.send(AggregatorAction::SendRpcResponse(response)) {
+                    log::error!("Error sending RPC response to aggregation thread! Did it panic? : {:#?}", e);
+                }
+                continue;
+            },
+            Ok(ChannelAction::Stop) => {
+                log::info!("Stopping channel...");
+                break;
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                log::warn!("Channel was dropped by its controller, stopping...");
+                break;
+            }
+        }
+
+        for due in scheduler.take_due() {
+            let slave = &due.slave;
+            let reg_map = match register_maps.get(slave) {
+                Some(reg_map) => reg_map,
+                None => continue
+            };
+            let (attributes_message, timeseries_message, reachable) = poll_slave(transport, &due, reg_map);
+
+            if let Some(action) = reachability.update(&slave.device_name, &slave.device_type, reachable) {
+                if let Err(e) = aggregator.send(action) {
+                    log::error!("Error sending device reachability to aggregation thread! Did it panic? : {:#?}", e);
+                }
+            }
+            if attributes_message.1.is_empty() && timeseries_message.1.is_empty() {
+                continue;
+            }
+            if let Err(e) = aggregator.send(AggregatorAction::SendBoth(attributes_message, timeseries_message)) {
+                log::error!("Error sending data to aggregation thread! Did it panic? : {:#?}", e);
+            }
+        }
+        transport.end_cycle();
+    }
+}
+
+/// Reads due register groups of a slave into messages,
+/// slave is reachable when at least one of its groups was read
+fn poll_slave<T: ModbusTransport + ?Sized>(transport: &mut T, due: &DuePoll, reg_map: &ModbusRegisterMap) -> (AttributeMessage, TimeseriesMessage, bool) {
+    let slave = &due.slave;
+    let mut attributes_message: AttributeMessage = (slave.device_name.clone(), HashMap::new());
+    let mut timeseries_message: TimeseriesMessage = (slave.device_name.clone(), vec![]);
+    let mut answered_groups = 0;
+
+    let groups = due.attribute_groups(reg_map).map(|group| (group, true))
+        .chain(due.timeseries_groups(reg_map).map(|group| (group, false)));
+    for (reg_group, is_attribute) in groups {
+        log::trace!("Reading starting address: {:}, and register count: {:} of slave with id: {:?}", reg_group.starting_address, reg_group.elements_count, slave.modbus_id);
+        let group_data = match read_group(transport, slave.modbus_id, reg_group) {
+            Ok(data) => data,
+            Err(ModbusError::NotConnected(e)) => {
+                log::error!("Could not poll slave with id {}: {}", slave.modbus_id, e);
+                break;
+            },
+            Err(e) => {
+                log::error!("Error reading register group at {} of slave with id {}: {}", reg_group.starting_address, slave.modbus_id, e);
+                continue;
+            }
+        };
+        answered_groups += 1;
+        log::trace!("Read Raw data: {:?}", group_data);
+
+        let mut data_point_vec: Vec<DataPoint> = vec![];
+        for data_point in &reg_group.data_points {
+            if let Some(point) = data_point.parse_data(&group_data) {
+                if let Some((key, unit)) = point.unit_attribute() {
+                    attributes_message.1.insert(key, unit);
+                }
+                data_point_vec.push(point);
+            }
+        }
+        log::info!("Read and parsed register group with data {} points", data_point_vec.len());
+        log::debug!("Datapoints in reg group: {:?}", data_point_vec);
+        if is_attribute {
+            for point in data_point_vec {
+                attributes_message.1.insert(point.key, point.value);
+            }
+        } else {
+            timeseries_message.1.push(OneTelemetry::from(data_point_vec));
+        }
+    }
+    (attributes_message, timeseries_message, answered_groups > 0)
+}
+
+/// Reads register group with its read function, garbled responses are retried
+pub fn read_group<T: ModbusTransport + ?Sized>(transport: &mut T, modbus_id: ModbusSlaveId, reg_group: &ModbusRegisterGroup) -> Result<ModbusGroupData, ModbusError> {
+    let request = read_request(reg_group.function, reg_group.starting_address, reg_group.elements_count);
+    let mut retries = 0;
+    loop {
+        let result = transport.transact(modbus_id, &request)
+            .and_then(|response| parse_read_response(reg_group.function, reg_group.elements_count, &response));
+        match result {
+            Err(ModbusError::Garbled(e)) if retries < GARBLED_RETRIES => {
+                log::warn!("Garbled response from slave with id {}, requesting again: {}", modbus_id, e);
+                retries += 1;
+            },
+            result => return result
+        }
+    }
+}
+
+/// Executes write prepared from RPC request
+pub fn execute_write<T: ModbusTransport + ?Sized>(transport: &mut T, write: &ModbusWrite) -> Result<(), String> {
+    let request = write_request(write)?;
+    let response = transport.transact(write.slave.modbus_id, &request).map_err(|e| e.to_string())?;
+    check_write_response(&request, &response).map_err(|e| e.to_string())?;
+    log::info!("Written with function {} at address {} of slave {}", write.function.code(), write.address, write.slave.modbus_id);
+    Ok(())
+}
+
+pub fn read_request(function: ModbusReadFunction, address: u16, count: u16) -> Vec<u8> {
+    let mut request = vec![function.code()];
+    request.extend_from_slice(&address.to_be_bytes());
+    request.extend_from_slice(&count.to_be_bytes());
+    request
+}
+
+/// Extracts bits or registers from response PDU to a read request
+pub fn parse_read_response(function: ModbusReadFunction, count: u16, response: &[u8]) -> Result<ModbusGroupData, ModbusError> {
+    check_function(function.code(), response)?;
+    let byte_count = *response.get(1).ok_or_else(|| ModbusError::Garbled(String::from("Response without byte count")))? as usize;
+    let data = &response[2..];
+    if data.len() != byte_count {
+        return Err(ModbusError::Garbled(format!("Response has {} data bytes, byte count is {}", data.len(), byte_count)));
+    }
+    if function.is_bit_function() {
+        if byte_count < (count as usize).div_ceil(8) {
+            return Err(ModbusError::Garbled(format!("Response has {} bytes for {} bits", byte_count, count)));
+        }
+        let bits = data.iter()
+            .flat_map(|byte| (0..8).map(move |bit| byte & (1 << bit) != 0))
+            .take(count as usize)
+            .collect();
+        Ok(ModbusGroupData::Bits(bits))
+    } else {
+        if byte_count != 2 * count as usize {
+            return Err(ModbusError::Garbled(format!("Response has {} bytes for {} registers", byte_count, count)));
+        }
+        let registers = data.chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        Ok(ModbusGroupData::Registers(registers))
+    }
+}
+
+pub fn write_request(write: &ModbusWrite) -> Result<Vec<u8>, String> {
+    let mut request = vec![write.function.code()];
+    request.extend_from_slice(&write.address.to_be_bytes());
+    match (write.function, &write.data) {
+        (ModbusWriteFunction::SingleCoil, ModbusGroupData::Bits(bits)) if bits.len() == 1 => {
+            request.extend_from_slice(if bits[0] { &[0xFF, 0x00] } else { &[0x00, 0x00] });
+        },
+        (ModbusWriteFunction::MultipleCoils, ModbusGroupData::Bits(bits)) if bits.len() > MAX_WRITE_COILS => {
+            return Err(format!("Function 15 writes at most {} coils, got {}", MAX_WRITE_COILS, bits.len()));
+        },
+        (ModbusWriteFunction::MultipleCoils, ModbusGroupData::Bits(bits)) if !bits.is_empty() => {
+            request.extend_from_slice(&(bits.len() as u16).to_be_bytes());
+            let packed: Vec<u8> = bits.chunks(8)
+                .map(|byte| byte.iter().enumerate().fold(0u8, |packed, (bit, value)| packed | ((*value as u8) << bit)))
+                .collect();
+            request.push(packed.len() as u8);
+            request.extend(packed);
+        },
+        (ModbusWriteFunction::SingleRegister, ModbusGroupData::Registers(registers)) if registers.len() == 1 => {
+            request.extend_from_slice(&registers[0].to_be_bytes());
+        },
+        (ModbusWriteFunction::MultipleRegisters, ModbusGroupData::Registers(registers)) if registers.len() > MAX_WRITE_REGISTERS => {
+            return Err(format!("Function 16 writes at most {} registers, got {}", MAX_WRITE_REGISTERS, registers.len()));
+        },
+        (ModbusWriteFunction::MultipleRegisters, ModbusGroupData::Registers(registers)) if !registers.is_empty() => {
+            request.extend_from_slice(&(registers.len() as u16).to_be_bytes());
+            request.push((2 * registers.len()) as u8);
+            for register in registers {
+                request.extend_from_slice(&register.to_be_bytes());
+            }
+        },
+        _ => return Err(format!("Function {} can not write {:?}", write.function.code(), write.data))
+    }
+    Ok(request)
+}
+
+/// Write responses echo function code, address and value or count of the request
+fn check_write_response(request: &[u8], response: &[u8]) -> Result<(), ModbusError> {
+    check_function(request[0], response)?;
+    if response.len() != 5 || response[1..5] != request[1..5] {
+        return Err(ModbusError::Garbled(format!("Write response {:?} does not match request {:?}", response, request)));
+    }
+    Ok(())
+}
+
+/// Exception responses have highest bit of the function code set
+pub fn check_function(function: u8, response: &[u8]) -> Result<(), ModbusError> {
+    match response.first() {
+        Some(code) if *code == function | 0x80 => {
+            Err(ModbusError::Exception(response.get(1).copied().unwrap_or(0)))
+        },
+        Some(code) if *code == function => Ok(()),
+        Some(code) => Err(ModbusError::Garbled(format!("Response with function {} to request with function {}", code, function))),
+        None => Err(ModbusError::Garbled(String::from("Empty response")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::{poll_slaves, ModbusError, ModbusTransport};
+    use crate::channels::ChannelAction;
+    use crate::channels::modbus::{ModbusRegisterMap, ModbusSlave, ModbusSlaveId};
+    use crate::definitions::{AggregatorAction, DataPointValue, RpcRequest};
+
+    /// Answers requests with prepared responses and remembers the requests
+    struct FakeTransport {
+        responses: VecDeque<Result<Vec<u8>, ModbusError>>,
+        requests: Vec<(ModbusSlaveId, Vec<u8>)>
+    }
+
+    impl ModbusTransport for FakeTransport {
+        fn transact(&mut self, modbus_id: ModbusSlaveId, request: &[u8]) -> Result<Vec<u8>, ModbusError> {
+            self.requests.push((modbus_id, request.to_vec()));
+            self.responses.pop_front().unwrap_or(Err(ModbusError::Timeout))
+        }
+    }
+
+    fn register_maps() -> HashMap<ModbusSlave, ModbusRegisterMap> {
+        let slave: ModbusSlave = serde_yaml::from_str(r#"
+device_name: Meter1
+device_type: ElectricityMeter
+modbus_id: 7
+register_map: map.yml
+"#).unwrap();
+        let reg_map = ModbusRegisterMap::from_yaml(r#"
+attributes:
+  - starting_address: 10
+    elements_count: 1
+    data_points:
+      - data_offset: 0
+        register_count: 1
+        data_type: uint16
+        key_name: Serial
+timeseries:
+  - function: coils
+    starting_address: 0
+    elements_count: 3
+    data_points:
+      - data_offset: 2
+        register_count: 1
+        data_type: bool
+        key_name: Relay
+rpc:
+  - method: setRelay
+    function: single_coil
+    address: 2
+    data_type: bool
+"#).unwrap();
+        HashMap::from([(slave, reg_map)])
+    }
+
+    fn run(responses: Vec<Result<Vec<u8>, ModbusError>>, actions: Vec<ChannelAction>) -> (FakeTransport, Vec<AggregatorAction>) {
+        let mut transport = FakeTransport { responses: responses.into(), requests: vec![] };
+        let (aggregator_tx, aggregator_rx) = mpsc::channel();
+        let (channel_tx, channel_rx) = mpsc::channel();
+        for action in actions {
+            channel_tx.send(action).unwrap();
+        }
+        let maps = register_maps();
+        let poller = thread::spawn(move || {
+            poll_slaves(&mut transport, &maps, &aggregator_tx, &channel_rx);
+            transport
+        });
+        // First poll is due right away
+        let first = aggregator_rx.recv().unwrap();
+        channel_tx.send(ChannelAction::Stop).unwrap();
+        let transport = poller.join().unwrap();
+        let mut sent = vec![first];
+        sent.extend(aggregator_rx.try_iter());
+        (transport, sent)
+    }
+
+    #[test]
+    fn slave_is_polled_into_messages() {
+        let responses = vec![
+            Ok(vec![3, 2, 0x12, 0x34]),
+            // Garbled response is requested again
+            Err(ModbusError::Garbled(String::from("Invalid CRC"))),
+            Ok(vec![1, 1, 0b101]),
+        ];
+        let (transport, sent) = run(responses, vec![]);
+        assert_eq!(transport.requests, vec![
+            (7, vec![3, 0, 10, 0, 1]),
+            (7, vec![1, 0, 0, 0, 3]),
+            (7, vec![1, 0, 0, 0, 3]),
+        ]);
+        assert!(matches!(&sent[0], AggregatorAction::DeviceConnected(device, _) if device == "Meter1"));
+        match &sent[1] {
+            AggregatorAction::SendBoth(attributes, timeseries) => {
+                assert_eq!(attributes.1.get("Serial"), Some(&DataPointValue::UInt(0x1234)));
+                assert_eq!(timeseries.1[0].values.get("Relay"), Some(&DataPointValue::Bool(true)));
+            },
+            _ => panic!("Expected data of the slave")
+        }
+    }
+
+    #[test]
+    fn exception_is_returned_to_rpc() {
+        let request = RpcRequest { device: String::from("Meter1"), id: 1, method: String::from("setRelay"), params: serde_json::json!(true) };
+        let (transport, sent) = run(vec![Err(ModbusError::Exception(2))], vec![ChannelAction::Rpc(request)]);
+        assert_eq!(transport.requests[0], (7, vec![5, 0, 2, 0xFF, 0]));
+        match &sent[0] {
+            AggregatorAction::SendRpcResponse(response) => {
+                assert_eq!(response.result, Err(String::from("Exception 2: Illegal data address")));
+            },
+            _ => panic!("Expected RPC response")
+        }
+    }
+
+    #[test]
+    fn response_pdus() {
+        use super::{parse_read_response, check_write_response};
+        use crate::channels::modbus::{ModbusGroupData, ModbusReadFunction};
+        assert_eq!(parse_read_response(ModbusReadFunction::InputRegisters, 2, &[4, 4, 0, 1, 0, 2]).unwrap(), ModbusGroupData::Registers(vec![1, 2]));
+        assert!(matches!(parse_read_response(ModbusReadFunction::InputRegisters, 2, &[0x84, 3]), Err(ModbusError::Exception(3))));
+        assert!(matches!(parse_read_response(ModbusReadFunction::InputRegisters, 2, &[4, 4, 0, 1]), Err(ModbusError::Garbled(_))));
+        assert!(check_write_response(&[6, 0, 1, 0, 5], &[6, 0, 1, 0, 5]).is_ok());
+        assert!(check_write_response(&[6, 0, 1, 0, 5], &[6, 0, 1, 0, 6]).is_err());
+    }
+
+    #[test]
+    fn oversized_writes_are_rejected() {
+        use super::write_request;
+        use crate::channels::modbus::ModbusGroupData;
+        use crate::channels::modbus::rpc::{ModbusWrite, ModbusWriteFunction};
+        let slave: ModbusSlave = serde_yaml::from_str("{ device_name: Meter1, modbus_id: 1, register_map: map.yml }").unwrap();
+        let write = |function, data| write_request(&ModbusWrite { slave: slave.clone(), function, address: 0, data });
+        assert_eq!(write(ModbusWriteFunction::MultipleRegisters, ModbusGroupData::Registers(vec![0; 123])).unwrap()[5], 246);
+        assert!(write(ModbusWriteFunction::MultipleRegisters, ModbusGroupData::Registers(vec![0; 124])).is_err());
+        assert_eq!(write(ModbusWriteFunction::MultipleCoils, ModbusGroupData::Bits(vec![true; 1968])).unwrap()[5], 246);
+        assert!(write(ModbusWriteFunction::MultipleCoils, ModbusGroupData::Bits(vec![true; 1969])).is_err());
+    }
+}
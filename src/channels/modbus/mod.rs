@@ -1,5 +1,4 @@
-use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::HashSet;
 
 use serde::Deserialize;
 use serde::Serialize;
@@ -12,13 +11,25 @@ use serde_yaml::from_str;
 
 // use safe_transmute;
 use bytemuck;
-use bytebuffer::ByteBuffer;
 
 pub mod tcp;
 pub mod rtu;
+pub mod ascii;
+pub mod socket;
+pub mod scheduler;
+pub mod engine;
+pub mod rpc;
+pub mod blocks;
+pub mod scan;
+pub mod server;
+pub mod simulator;
 // pub mod rtu;
 
 use super::{ DataPoint,ChannelConfig};
+use scheduler::ModbusPollInterval;
+use rpc::ModbusRpcMethod;
+use blocks::{ModbusAddressRange, ModbusRegisterPoint};
+use crate::definitions::DataPointValue;
 
 pub enum ModbusClientConfig {
     Rtu,
@@ -33,19 +44,95 @@ pub enum ModbusRegisterMapType {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModbusRegisterMap {
+    pub endianness: Option<ModbusEndianness>, // Default for every data point, ABCD if not set
+    #[serde(default)]
     pub attributes: Vec<ModbusRegisterGroup>,
+    #[serde(default)]
     pub timeseries: Vec<ModbusRegisterGroup>,
+    #[serde(default)]
+    pub attribute_points: Vec<ModbusRegisterPoint>, // Joined into read blocks and appended to attributes
+    #[serde(default)]
+    pub timeseries_points: Vec<ModbusRegisterPoint>, // Joined into read blocks and appended to timeseries
+    pub max_gap: Option<u16>, // Unused registers that may be read to join points into one block, 10 if not set
+    #[serde(default)]
+    pub forbidden_ranges: Vec<ModbusAddressRange>, // Addresses that are never read by joined blocks
+    #[serde(default)]
+    pub rpc: Vec<ModbusRpcMethod>, // RPC methods that write to the device
 }
 
-// This struct could be use in server implementaion later
+impl ModbusRegisterMap {
+    /// Deserializes register map, joins data points listed by address into read blocks
+    /// and applies register map defaults to data points that do not override them
+    pub fn from_yaml(register_map_raw: &str) -> Result<Self, Error> {
+        let mut register_map = from_str::<ModbusRegisterMap>(register_map_raw)?;
+        let max_gap = register_map.max_gap.unwrap_or(blocks::DEFAULT_MAX_GAP);
+        let attributes = blocks::build_read_blocks(&register_map.attribute_points, max_gap, &register_map.forbidden_ranges)
+            .map_err(<Error as serde::de::Error>::custom)?;
+        let timeseries = blocks::build_read_blocks(&register_map.timeseries_points, max_gap, &register_map.forbidden_ranges)
+            .map_err(<Error as serde::de::Error>::custom)?;
+        register_map.attributes.extend(attributes);
+        register_map.timeseries.extend(timeseries);
+        let endianness = register_map.endianness.unwrap_or_default();
+        for group in register_map.attributes.iter_mut().chain(register_map.timeseries.iter_mut()) {
+            for data_point in group.data_points.iter_mut() {
+                data_point.endianness.get_or_insert(endianness);
+            }
+        }
+        for method in register_map.rpc.iter_mut() {
+            method.endianness.get_or_insert(endianness);
+        }
+        Ok(register_map)
+    }
+}
+
+// Groups are also the register table of server units, see server::ModbusDataTable
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModbusRegisterGroup {
+    #[serde(default)]
+    pub function: ModbusReadFunction, // Which modbus function is used to read this group
     pub starting_address: u16, // Starting read address
-    pub elements_count: u16,   // How many registers to read from starting_address
+    pub elements_count: u16,   // How many registers (or coils/inputs) to read from starting_address
+    pub poll_interval: Option<ModbusPollInterval>, // Overrides poll_interval of the slave
     pub data_points: Vec<ModbusDataPointReader>,
     pub data: Option<Vec<u16>> // Data that was read from modbus
 }
 
+/// Modbus read functions that a register group can be read with.
+/// Defaults to function 3 (read holding registers)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusReadFunction {
+    Coils,            // FC1
+    DiscreteInputs,   // FC2
+    #[default]
+    HoldingRegisters, // FC3
+    InputRegisters,   // FC4
+}
+
+impl ModbusReadFunction {
+    /// Modbus function code of this read function
+    pub fn code(&self) -> u8 {
+        match self {
+            ModbusReadFunction::Coils => 1,
+            ModbusReadFunction::DiscreteInputs => 2,
+            ModbusReadFunction::HoldingRegisters => 3,
+            ModbusReadFunction::InputRegisters => 4,
+        }
+    }
+
+    /// Coils and discrete inputs return single bits instead of 16bit registers
+    pub fn is_bit_function(&self) -> bool {
+        matches!(self, ModbusReadFunction::Coils | ModbusReadFunction::DiscreteInputs)
+    }
+}
+
+/// Raw data of a register group as it was read from the device
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModbusGroupData {
+    Bits(Vec<bool>),      // Function 1 and 2
+    Registers(Vec<u16>),  // Function 3 and 4
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum ModbusDataType {
@@ -55,115 +142,396 @@ pub enum ModbusDataType {
     Int16,  // i16
     UInt32, // u32
     UInt16, // u16
+    Int64,  // i64
+    UInt64, // u64
+    Bool,   // bool, single coil/discrete input or a non zero register
+    String, // ASCII string of register_count registers, 2 characters per register
+    Bcd16,  // packed BCD, 4 digits in 1 register
+    Bcd32,  // packed BCD, 8 digits in 2 registers
+    Bitfield, // bit_length bits starting at bit_offset of a single register
 }
+
+impl ModbusDataType {
+    /// Registers holding a value of this type, None for string whose length is set by register_count
+    pub fn register_count(&self) -> Option<usize> {
+        match self {
+            ModbusDataType::Int16 | ModbusDataType::UInt16 | ModbusDataType::Bool | ModbusDataType::Bcd16 | ModbusDataType::Bitfield => Some(1),
+            ModbusDataType::Int32 | ModbusDataType::UInt32 | ModbusDataType::Float | ModbusDataType::Bcd32 => Some(2),
+            ModbusDataType::Int64 | ModbusDataType::UInt64 | ModbusDataType::Double => Some(4),
+            ModbusDataType::String => None
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModbusDataPointReader {
     pub data_offset: usize,
     pub register_count: usize,
     pub data_type: ModbusDataType,
     pub key_name: String,
+    pub endianness: Option<ModbusEndianness>, // Defaults to endianness of the register map
+    pub bit_offset: Option<u8>, // Only for bitfield, 0 is the least significant bit
+    pub bit_length: Option<u8>, // Only for bitfield, defaults to 1 which is parsed as bool
+    pub scale: Option<f64>,  // Engineering value = value * scale * 10^scale_factor + offset
+    pub offset: Option<f64>,
+    pub scale_factor_register: Option<usize>, // Index of int16 register in this group holding the exponent (SunSpec)
+    pub unit: Option<String>,
 }
 
 impl ModbusDataPointReader {
-    pub fn  parse(&self, data: Vec<u16>) -> Option<DataPoint>{
-        // let (first, second, third) = dbg!(data.align_to::<u8>());
-
-        log::trace!("Incommint Vec<u16>: {:?}", data);
-        log::trace!("Data offset: {}", self.data_offset);
-        let mut buff = ByteBuffer::new();
-        for word in data {
-            buff.write_u16(word);
+    /// Parses data point from data of any register group type
+    pub fn parse_data(&self, data: &ModbusGroupData) -> Option<DataPoint> {
+        match data {
+            ModbusGroupData::Bits(bits) => self.parse_bits(bits.clone()),
+            ModbusGroupData::Registers(registers) => self.parse(registers.clone())
         }
-        // let transmuted = safce_transmute::transmute_to_bytes(data.as_slice());
-        // let transmuted = bytemuck::cast_slice(data.as_slice());
-        
+    }
+
+    /// Parses a data point from bits read by function 1 or 2.
+    /// For these groups `data_offset` is the index of the coil/input
+    /// relative to `starting_address` of the group
+    pub fn parse_bits(&self, data: Vec<bool>) -> Option<DataPoint> {
+        log::trace!("Incomming Vec<bool>: {:?}", data);
+        log::trace!("Bit offset: {}", self.data_offset);
 
         match self.data_type {
-            ModbusDataType::Float => {
-                // Float =  32bits /  2 registers
-                buff.set_rpos(self.data_offset);
-                if !((self.data_offset + 4) <= buff.len())  {
-                    log::warn!("continuing... , would crash otherwise");
-                    return None;
-                }
-                let value = buff.read_f32();
+            ModbusDataType::Bool => {
+                let value = match data.get(self.data_offset) {
+                    Some(v) => *v,
+                    None => {
+                        log::warn!("continuing... , would crash otherwise");
+                        return None;
+                    }
+                };
 
                 log::debug!("Value prased: {}", value);
                 Some(DataPoint {
                     key: self.key_name.clone(),
-                    value: value.to_string(),
+                    value: DataPointValue::Bool(value),
+                    unit: self.unit.clone(),
                     ts: None
                 })
-                    
+            },
+            _ => {
+                log::warn!("Data type {:?} of {} can not be read from coils or discrete inputs", self.data_type, self.key_name);
+                None
+            }
+        }
+    }
+
+    pub fn  parse(&self, data: Vec<u16>) -> Option<DataPoint>{
+        log::trace!("Incommint Vec<u16>: {:?}", data);
+        log::trace!("Data offset: {}", self.data_offset);
 
+        let value = match self.data_type {
+            ModbusDataType::Float => {
+                // Float =  32bits /  2 registers
+                let bytes = self.value_bytes(&data, 4)?;
+                DataPointValue::from(f32::from_be_bytes(bytes.try_into().ok()?))
             },
             ModbusDataType::Double => {
-                buff.set_rpos(self.data_offset);
-                if !((self.data_offset + 8) <= buff.len())  {
-                    log::warn!("continuing... , would crash otherwise");
+                let bytes = self.value_bytes(&data, 8)?;
+                DataPointValue::Float(f64::from_be_bytes(bytes.try_into().ok()?))
+            },
+            ModbusDataType::Int32 => {
+                let bytes = self.value_bytes(&data, 4)?;
+                DataPointValue::Int(i32::from_be_bytes(bytes.try_into().ok()?).into())
+            },
+            ModbusDataType::UInt32 => {
+                let bytes = self.value_bytes(&data, 4)?;
+                DataPointValue::UInt(u32::from_be_bytes(bytes.try_into().ok()?).into())
+            },
+            ModbusDataType::UInt16 => {
+                let bytes = self.value_bytes(&data, 2)?;
+                DataPointValue::UInt(u16::from_be_bytes(bytes.try_into().ok()?).into())
+            },
+            ModbusDataType::Int16 => {
+                let bytes = self.value_bytes(&data, 2)?;
+                DataPointValue::Int(i16::from_be_bytes(bytes.try_into().ok()?).into())
+            },
+            ModbusDataType::Int64 => {
+                let bytes = self.value_bytes(&data, 8)?;
+                DataPointValue::Int(i64::from_be_bytes(bytes.try_into().ok()?))
+            },
+            ModbusDataType::UInt64 => {
+                let bytes = self.value_bytes(&data, 8)?;
+                DataPointValue::UInt(u64::from_be_bytes(bytes.try_into().ok()?))
+            },
+            ModbusDataType::Bool => {
+                let bytes = self.value_bytes(&data, 2)?;
+                DataPointValue::Bool(u16::from_be_bytes(bytes.try_into().ok()?) != 0)
+            },
+            ModbusDataType::String => {
+                if self.register_count == 0 {
+                    log::warn!("String {} has to be at least 1 register long", self.key_name);
                     return None;
                 }
-                let value = buff.read_f64();
-
-                log::debug!("Value prased: {}", value);
-                Some(DataPoint {
-                    key: self.key_name.clone(),
-                    value: value.to_string(),
-                    ts: None
-                })
+                // Strings are read in order of registers, only bytes in registers can be swapped
+                let bytes = self.raw_bytes(&data, self.register_count * 2)?;
+                let bytes = match self.endianness.unwrap_or_default() {
+                    ModbusEndianness::Abcd | ModbusEndianness::Cdab => bytes,
+                    ModbusEndianness::Badc | ModbusEndianness::Dcba => ModbusEndianness::Badc.to_big_endian(&bytes)
+                };
+                DataPointValue::String(decode_string(&bytes))
             },
-            ModbusDataType::Int32 => {
-                buff.set_rpos(self.data_offset);
-                if !((self.data_offset + 4) <= buff.len())  {
-                    log::warn!("continuing... , would crash otherwise");
+            ModbusDataType::Bcd16 => {
+                let bytes = self.value_bytes(&data, 2)?;
+                match decode_bcd(&bytes) {
+                    Some(v) => DataPointValue::UInt(v),
+                    None => {
+                        log::warn!("Invalid BCD value {:02X?} of {}", bytes, self.key_name);
+                        return None;
+                    }
+                }
+            },
+            ModbusDataType::Bcd32 => {
+                let bytes = self.value_bytes(&data, 4)?;
+                match decode_bcd(&bytes) {
+                    Some(v) => DataPointValue::UInt(v),
+                    None => {
+                        log::warn!("Invalid BCD value {:02X?} of {}", bytes, self.key_name);
+                        return None;
+                    }
+                }
+            },
+            ModbusDataType::Bitfield => {
+                let bit_offset = match self.bit_offset {
+                    Some(o) => o as u32,
+                    None => {
+                        log::warn!("Bitfield {} is missing bit_offset", self.key_name);
+                        return None;
+                    }
+                };
+                let bit_length = self.bit_length.unwrap_or(1) as u32;
+                if bit_length == 0 || bit_offset + bit_length > 16 {
+                    log::warn!("Bitfield {} with bit_offset {} and bit_length {} does not fit in a register", self.key_name, bit_offset, bit_length);
                     return None;
                 }
-                let value = buff.read_i32();
-
-                log::debug!("Value prased: {}", value);
-                Some(DataPoint {
-                    key: self.key_name.clone(),
-                    value: value.to_string(),
-                    ts: None
-                })
+                let bytes = self.value_bytes(&data, 2)?;
+                let word = u16::from_be_bytes(bytes.try_into().ok()?) as u32;
+                let value = (word >> bit_offset) & ((1u32 << bit_length) - 1);
+                if bit_length == 1 {
+                    DataPointValue::Bool(value == 1)
+                } else {
+                    DataPointValue::UInt(value.into())
+                }
+            }
+        };
 
-            },
-            ModbusDataType::UInt32 => {
-                buff.set_rpos(self.data_offset);
-                if !((self.data_offset + 4) <= buff.len())  {
-                    log::warn!("continuing... , would crash otherwise");
+        let value = if self.is_scaled() {
+            let raw = match value.as_f64() {
+                Some(v) => v,
+                None => {
+                    log::warn!("Data type {:?} of {} can not be scaled", self.data_type, self.key_name);
                     return None;
                 }
-                let value = buff.read_u32();
+            };
+            DataPointValue::Float(self.scale_value(&data, raw)?)
+        } else {
+            value
+        };
 
-                log::debug!("Value prased: {}", value);
-                Some(DataPoint {
-                    key: self.key_name.clone(),
-                    value: value.to_string(),
-                    ts: None
-                })
+        log::debug!("Value prased: {}", value);
+        Some(DataPoint {
+            key: self.key_name.clone(),
+            value,
+            unit: self.unit.clone(),
+            ts: None
+        })
+    }
 
+    /// Writes engineering value into data of the register group, reverse of `parse_data`.
+    /// Scale and offset are removed, scale factor is taken from data the way it is read
+    pub fn encode_data(&self, value: &serde_json::Value, data: &mut ModbusGroupData) -> Result<(), String> {
+        match data {
+            ModbusGroupData::Bits(bits) => {
+                if !matches!(self.data_type, ModbusDataType::Bool) {
+                    return Err(format!("Data type {:?} of {} can not be written to coils or discrete inputs", self.data_type, self.key_name));
+                }
+                let bit = bits.get_mut(self.data_offset).ok_or_else(|| format!("{} is outside of its group", self.key_name))?;
+                *bit = rpc::json_to_bool(value)?;
+                Ok(())
             },
-            ModbusDataType::UInt16 => {
-                buff.set_rpos(min(self.data_offset, buff.len()));
-                if !((self.data_offset + 2) <= buff.len())  {
-                    log::warn!("continuing... , would crash otherwise");
+            ModbusGroupData::Registers(registers) => self.encode(value, registers)
+        }
+    }
+
+    fn encode(&self, value: &serde_json::Value, data: &mut [u16]) -> Result<(), String> {
+        let endianness = self.endianness.unwrap_or_default();
+        // Raw value of scaled data points is rounded to whole units of the register,
+        // floats written to unscaled integer types have to be whole numbers
+        let raw = if self.is_scaled() {
+            let mut raw = rpc::json_to_f64(value)? - self.offset.unwrap_or(0.0);
+            raw /= self.scale.unwrap_or(1.0);
+            if let Some(register) = self.scale_factor_register {
+                let scale_factor = *data.get(register).ok_or_else(|| format!("Scale factor register {} of {} is outside of its group", register, self.key_name))? as i16;
+                raw /= 10f64.powi(scale_factor as i32);
+            }
+            Some(raw)
+        } else {
+            value.as_f64().filter(|_| value.is_f64())
+        };
+        let raw_value = match (raw, &self.data_type) {
+            (Some(raw), ModbusDataType::Float | ModbusDataType::Double) => Some(serde_json::json!(raw)),
+            (Some(raw), _) => Some(self.whole_raw(raw)?),
+            (None, _) => None
+        };
+        let value = raw_value.as_ref().unwrap_or(value);
+
+        let bytes = match self.data_type {
+            ModbusDataType::Bitfield => {
+                let bit_offset = self.bit_offset.ok_or_else(|| format!("Bitfield {} is missing bit_offset", self.key_name))? as u32;
+                let bit_length = self.bit_length.unwrap_or(1) as u32;
+                if bit_length == 0 || bit_offset + bit_length > 16 {
+                    return Err(format!("Bitfield {} does not fit in a register", self.key_name));
+                }
+                let bits = if bit_length == 1 { rpc::json_to_bool(value)? as u32 } else { rpc::json_to_int::<u32>(value)? };
+                let mask = ((1u32 << bit_length) - 1) << bit_offset;
+                if (bits << bit_offset) & !mask != 0 {
+                    return Err(format!("Value {} of {} does not fit in {} bits", value, self.key_name, bit_length));
+                }
+                let current = self.value_bytes(data, 2).ok_or_else(|| format!("{} is outside of its group", self.key_name))?;
+                let word = u16::from_be_bytes([current[0], current[1]]) as u32;
+                let word = ((word & !mask) | (bits << bit_offset)) as u16;
+                // Reordering bytes to big endian reverts itself
+                endianness.to_big_endian(&word.to_be_bytes())
+            },
+            _ => rpc::encode_bytes(&self.data_type, endianness, Some(self.register_count), value)?
+        };
+
+        let mut data_bytes: Vec<u8> = data.iter().flat_map(|word| word.to_be_bytes()).collect();
+        if self.data_offset + bytes.len() > data_bytes.len() {
+            return Err(format!("{} is outside of its group", self.key_name));
+        }
+        data_bytes[self.data_offset..self.data_offset + bytes.len()].copy_from_slice(&bytes);
+        for (register, word) in data.iter_mut().zip(data_bytes.chunks(2)) {
+            *register = u16::from_be_bytes([word[0], word[1]]);
+        }
+        Ok(())
+    }
+
+    /// Integer raw value, casts saturate so the value has to fit into i64 or u64.
+    /// Range of the data type itself is checked when the value is encoded
+    fn whole_raw(&self, raw: f64) -> Result<serde_json::Value, String> {
+        let rounded = raw.round();
+        if !self.is_scaled() && rounded != raw {
+            return Err(format!("Value {} of {} is not a whole number", raw, self.key_name));
+        }
+        if (0.0..u64::MAX as f64).contains(&rounded) {
+            Ok(serde_json::json!(rounded as u64))
+        } else if (i64::MIN as f64..0.0).contains(&rounded) {
+            Ok(serde_json::json!(rounded as i64))
+        } else {
+            Err(format!("Value {} of {} is out of range of {:?}", raw, self.key_name, self.data_type))
+        }
+    }
+
+    /// Bytes parsed from register data starting at `data_offset`
+    pub fn size(&self) -> usize {
+        2 * self.data_type.register_count().unwrap_or(self.register_count)
+    }
+
+    fn is_scaled(&self) -> bool {
+        self.scale.is_some() || self.offset.is_some() || self.scale_factor_register.is_some()
+    }
+
+    /// Converts raw value into engineering value with scale, offset and
+    /// scale factor read from another register of the same group
+    fn scale_value(&self, data: &[u16], raw: f64) -> Option<f64> {
+        let mut value = raw * self.scale.unwrap_or(1.0);
+        if let Some(register) = self.scale_factor_register {
+            let scale_factor = match data.get(register) {
+                Some(sf) => *sf as i16,
+                None => {
+                    log::warn!("Scale factor register {} of {} is outside of read registers", register, self.key_name);
                     return None;
                 }
-                let value = buff.read_u16();
+            };
+            value *= 10f64.powi(scale_factor as i32);
+        }
+        Some(value + self.offset.unwrap_or(0.0))
+    }
 
-                log::debug!("Value prased: {}", value);
-                Some(DataPoint {
-                    key: self.key_name.clone(),
-                    value: value.to_string(),
-                    ts: None
-                })
+    /// Returns `size` bytes starting at `data_offset` reordered to big endian
+    /// according to the endianness of this data point
+    fn value_bytes(&self, data: &[u16], size: usize) -> Option<Vec<u8>> {
+        let bytes = self.raw_bytes(data, size)?;
+        Some(self.endianness.unwrap_or_default().to_big_endian(&bytes))
+    }
+
+    /// Returns `size` bytes starting at `data_offset` in order they were read
+    fn raw_bytes(&self, data: &[u16], size: usize) -> Option<Vec<u8>> {
+        let bytes: Vec<u8> = data.iter().flat_map(|word| word.to_be_bytes()).collect();
+        if self.data_offset + size > bytes.len() {
+            log::warn!("continuing... , would crash otherwise");
+            return None;
+        }
+        Some(bytes[self.data_offset..self.data_offset + size].to_vec())
+    }
+}
+
+/// Decodes ASCII string, the string ends at first NUL character
+/// and padding with spaces is removed
+fn decode_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+/// Decodes packed BCD, every nibble is one decimal digit.
+/// Returns None if any of the nibbles is not a decimal digit
+fn decode_bcd(bytes: &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for byte in bytes {
+        for nibble in [byte >> 4, byte & 0x0F] {
+            if nibble > 9 {
+                return None;
             }
-            _ => {
-                todo!()
+            value = value * 10 + nibble as u64;
+        }
+    }
+    Some(value)
+}
+
+/// Order of bytes of a multi register value, A being the most significant byte.
+/// ABCD is big endian as defined by modbus, CDAB has swapped words,
+/// BADC has swapped bytes in every word and DCBA is little endian
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ModbusEndianness {
+    #[default]
+    #[serde(rename = "ABCD", alias = "abcd")]
+    Abcd,
+    #[serde(rename = "CDAB", alias = "cdab")]
+    Cdab,
+    #[serde(rename = "BADC", alias = "badc")]
+    Badc,
+    #[serde(rename = "DCBA", alias = "dcba")]
+    Dcba,
+}
+
+impl ModbusEndianness {
+    fn swaps_words(self) -> bool {
+        matches!(self, ModbusEndianness::Cdab | ModbusEndianness::Dcba)
+    }
+
+    fn swaps_bytes(self) -> bool {
+        matches!(self, ModbusEndianness::Badc | ModbusEndianness::Dcba)
+    }
 
+    /// Reorders bytes as they were read from registers into big endian order.
+    /// Works for values of any register count eg: 64bit CDAB is read as GHEFCDAB
+    pub fn to_big_endian(self, bytes: &[u8]) -> Vec<u8> {
+        let mut words: Vec<[u8; 2]> = bytes.chunks(2)
+            .map(|word| [word[0], *word.get(1).unwrap_or(&0)])
+            .collect();
+        if self.swaps_words() {
+            words.reverse();
+        }
+        if self.swaps_bytes() {
+            for word in words.iter_mut() {
+                word.swap(0, 1);
             }
         }
+        words.concat()
     }
 }
 
@@ -173,6 +541,10 @@ pub struct ModbusSlave {
     pub device_type: Option<String>,
     pub modbus_id: ModbusSlaveId,
     pub register_map: String,
+    pub poll_interval: Option<ModbusPollInterval>, // Milliseconds or cron expression, default 10s
+    // Modbus TCP only, overrides host/port of the channel eg: for devices behind other TCP-to-RTU gateways
+    pub host: Option<String>,
+    pub port: Option<u16>,
     // pub file_descriptor: Option<i32>
 
 }
@@ -180,13 +552,27 @@ pub struct ModbusSlave {
 pub type ModbusSlaveId = u8;
 
 
-#[derive(Serialize, Deserialize ,Debug)]
+#[derive(Serialize, Deserialize ,Debug, Clone)]
 pub struct ModbusClientTcpConfig {
     pub name: String,
     pub host: String,
     pub port: u16,
-    pub slaves: Vec<ModbusSlave>
+    pub slaves: Vec<ModbusSlave>,
+    pub byte_timeout: Option<u64>, // Milliseconds, default 50
+    pub response_timeout: Option<u64>, // Milliseconds, default 1050
+    #[serde(default)]
+    pub connection: ModbusTcpConnectionMode
+
+}
 
+/// Persistent connection is kept open between polls and reopened after it was lost,
+/// per cycle connection is opened for every poll cycle and closed afterwards
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusTcpConnectionMode {
+    #[default]
+    Persistent,
+    PerCycle
 }
 
 
@@ -214,12 +600,171 @@ pub struct ModbusClientRtuConfig {
     pub parity: char,
     pub data_bits: u8,
     pub stop_bits: u8,
+    #[serde(default)]
+    pub flow_control: ModbusFlowControl,
+    pub rs485: Option<ModbusRs485Config>,
+    #[serde(default)]
+    pub mode: ModbusSerialMode,
+    pub response_timeout: Option<u64>, // Milliseconds, default 1000
+    pub request_delay: Option<u64>, // Milliseconds before every request, default 0
     pub slaves: Vec<ModbusSlave>
 }
 
+impl ModbusClientRtuConfig {
+    pub fn serial_parity(&self) -> Result<serialport::Parity, String> {
+        match self.parity {
+            'E' => Ok(serialport::Parity::Even),
+            'N' => Ok(serialport::Parity::None),
+            'O' => Ok(serialport::Parity::Odd),
+            _ => Err(format!("Invalid parity: {:?}, expected 'E', 'N' or 'O'", self.parity))
+        }
+    }
+
+    pub fn serial_data_bits(&self) -> Result<serialport::DataBits, String> {
+        match self.data_bits {
+            8 => Ok(serialport::DataBits::Eight),
+            7 => Ok(serialport::DataBits::Seven),
+            6 => Ok(serialport::DataBits::Six),
+            5 => Ok(serialport::DataBits::Five),
+            _ => Err(format!("Invalid data_bits: {}, expected 5, 6, 7 or 8", self.data_bits))
+        }
+    }
+
+    pub fn serial_stop_bits(&self) -> Result<serialport::StopBits, String> {
+        match self.stop_bits {
+            1 => Ok(serialport::StopBits::One),
+            2 => Ok(serialport::StopBits::Two),
+            _ => Err(format!("Invalid stop_bits: {}, expected 1 or 2", self.stop_bits))
+        }
+    }
+
+    /// Start bit, data bits, parity bit and stop bits of one character
+    pub fn character_bits(&self) -> u32 {
+        let parity = if self.parity == 'N' { 0 } else { 1 };
+        1 + self.data_bits as u32 + parity + self.stop_bits as u32
+    }
+
+    pub fn serial_flow_control(&self) -> serialport::FlowControl {
+        match self.flow_control {
+            ModbusFlowControl::None => serialport::FlowControl::None,
+            ModbusFlowControl::Software => serialport::FlowControl::Software,
+            ModbusFlowControl::Hardware => serialport::FlowControl::Hardware
+        }
+    }
+
+    /// Serial settings are checked when the config is loaded, so that the channel is not started with wrong ones
+    fn validate(&self) -> Result<(), String> {
+        self.serial_parity()?;
+        self.serial_data_bits()?;
+        self.serial_stop_bits()?;
+        if let Some(rs485) = &self.rs485 {
+            if rs485.mode == ModbusRs485Mode::Software && self.flow_control == ModbusFlowControl::Hardware {
+                return Err("RTS can not be toggled by software RS-485 control with hardware flow control".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusFlowControl {
+    #[default]
+    None,
+    Software,
+    Hardware
+}
+
+/// RS-485 transceiver direction driven by RTS
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModbusRs485Config {
+    #[serde(default)]
+    pub mode: ModbusRs485Mode,
+    pub rts_on_send: Option<bool>, // Level of RTS while sending, default true
+    pub delay_before_send: Option<u32>, // Milliseconds, default 0
+    pub delay_after_send: Option<u32> // Milliseconds, default 0
+}
+
+/// Kernel mode uses RS-485 support of the UART driver (TIOCSRS485),
+/// software mode toggles RTS around every sent frame
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusRs485Mode {
+    #[default]
+    Kernel,
+    Software
+}
+
+/// Framing used on the serial line, RTU is binary with CRC,
+/// ASCII is hex encoded with LRC between ':' and CRLF
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusSerialMode {
+    #[default]
+    Rtu,
+    Ascii
+}
+
 impl ChannelConfig for ModbusClientRtuConfig {
     fn serialize(config_string: String) -> Result<Self, Error> where Self: Sized {
-        from_str::<ModbusClientRtuConfig>(&config_string)
+        let config = from_str::<ModbusClientRtuConfig>(&config_string)?;
+        config.validate().map_err(<Error as serde::de::Error>::custom)?;
+        Ok(config)
+    }
+}
+
+/// RTU frames tunneled over TCP or UDP by serial-to-Ethernet converters
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModbusClientRtuSocketConfig {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub response_timeout: Option<u64>, // Milliseconds, default 1000
+    pub request_delay: Option<u64>, // Milliseconds before every request, default 0
+    pub slaves: Vec<ModbusSlave>
+}
+
+impl ChannelConfig for ModbusClientRtuSocketConfig {
+    fn serialize(config_string: String) -> Result<Self, Error> where Self: Sized {
+        from_str::<ModbusClientRtuSocketConfig>(&config_string)
+    }
+}
+
+/// Serves latest values of devices polled by other channels to local Modbus TCP clients eg: SCADA or HMI
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModbusServerTcpConfig {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub update_interval: Option<u64>, // Milliseconds between updates of served values, default 1000
+    pub units: Vec<ModbusServerUnit>
+}
+
+/// Unit that serves values of the device, encoded into data points of the register map
+#[derive(Serialize, Deserialize, Debug, PartialEq, Hash, Eq, Clone)]
+pub struct ModbusServerUnit {
+    pub device_name: String,
+    pub unit_id: ModbusSlaveId,
+    pub register_map: String
+}
+
+impl ModbusServerTcpConfig {
+    fn validate(&self) -> Result<(), String> {
+        let mut unit_ids = HashSet::new();
+        for unit in &self.units {
+            if !unit_ids.insert(unit.unit_id) {
+                return Err(format!("Unit id {} is used more than once", unit.unit_id));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ChannelConfig for ModbusServerTcpConfig {
+    fn serialize(config_string: String) -> Result<Self, Error> where Self: Sized {
+        let config = from_str::<ModbusServerTcpConfig>(&config_string)?;
+        config.validate().map_err(<Error as serde::de::Error>::custom)?;
+        Ok(config)
     }
 }
 
@@ -236,10 +781,37 @@ impl ChannelConfig for ModbusClientRtuConfig {
 #[cfg(test)]
 mod tests {
     use crate::channels::{ChannelConfig, modbus::ModbusSlave};
+    use crate::definitions::DataPointValue;
 
-    use super::{ModbusClientTcpConfig, ModbusRegisterMap, ModbusDataPointReader};
+    use super::{ModbusClientRtuConfig, ModbusRs485Mode, ModbusClientTcpConfig, ModbusTcpConnectionMode, ModbusRegisterMap, ModbusDataPointReader, ModbusRegisterGroup, ModbusReadFunction, ModbusEndianness, ModbusDataType};
     use std::fs;
 
+    #[test]
+    fn construct_modbus_rtu_channel() {
+        let data = r#"
+            name: Modbus Channel 2
+            port: /dev/ttyS1
+            baudrate: 9600
+            parity: 'N'
+            data_bits: 8
+            stop_bits: 2
+            flow_control: none
+            rs485:
+              mode: software
+              delay_after_send: 1
+            slaves: []
+        "#;
+
+        let res = ModbusClientRtuConfig::serialize(data.to_string()).unwrap();
+        assert_eq!(res.serial_stop_bits(), Ok(serialport::StopBits::Two));
+        assert_eq!(res.rs485.unwrap().mode, ModbusRs485Mode::Software);
+
+        assert!(ModbusClientRtuConfig::serialize(data.replace("stop_bits: 2", "stop_bits: 3")).is_err());
+        assert!(ModbusClientRtuConfig::serialize(data.replace("parity: 'N'", "parity: 'X'")).is_err());
+        assert!(ModbusClientRtuConfig::serialize(data.replace("flow_control: none", "flow_control: rts")).is_err());
+        assert!(ModbusClientRtuConfig::serialize(data.replace("flow_control: none", "flow_control: hardware")).is_err());
+    }
+
     #[test]
     fn construct_modbus_tcp_channel() {
         let data = r#"
@@ -259,11 +831,16 @@ mod tests {
         let res = res.unwrap();
         assert_eq!(res.host, "192.168.1.1");
         assert_eq!(res.port, 502);
+        assert_eq!(res.connection, ModbusTcpConnectionMode::Persistent);
+        assert_eq!(res.response_timeout, None);
         assert_eq!(res.slaves, vec![ModbusSlave{
             device_name: "Elektromer1".to_string(),
             device_type: Some("DEVICE_TYPE".to_string()),
             modbus_id: 1,
-            register_map: "./register_map/feafef.yml".to_string()
+            register_map: "./register_map/feafef.yml".to_string(),
+            poll_interval: None,
+            host: None,
+            port: None
         }]);
 
         
@@ -315,7 +892,14 @@ mod tests {
             data_offset: 0usize,
             register_count: 4usize,
             data_type: super::ModbusDataType::Float,
-            key_name: String::from("TestingTimeseries")
+            key_name: String::from("TestingTimeseries"),
+            endianness: None,
+            bit_offset: None,
+            bit_length: None,
+            scale: None,
+            offset: None,
+            scale_factor_register: None,
+            unit: None
         };
 
         // unsafe {
@@ -323,7 +907,259 @@ mod tests {
 
             println!("Return: {:?}", ret);
         // }
+        assert_ne!(ret.unwrap().value, DataPointValue::Float(10.1));
+
+        let structure = ModbusDataPointReader {
+            endianness: Some(ModbusEndianness::Cdab),
+            ..structure
+        };
+        assert_eq!(structure.parse(data).unwrap().value, DataPointValue::Float(10.1));
+
+    }
+    #[test]
+    fn datapoint_endianness() {
+        // 0x11223344 as stored by devices with different byte orders
+        let orders = [
+            (ModbusEndianness::Abcd, vec![0x1122, 0x3344]),
+            (ModbusEndianness::Cdab, vec![0x3344, 0x1122]),
+            (ModbusEndianness::Badc, vec![0x2211, 0x4433]),
+            (ModbusEndianness::Dcba, vec![0x4433, 0x2211]),
+        ];
+        for (endianness, data) in orders {
+            let reader = ModbusDataPointReader {
+                data_offset: 0,
+                register_count: 2,
+                data_type: super::ModbusDataType::UInt32,
+                key_name: String::from("Value"),
+                endianness: Some(endianness),
+                bit_offset: None,
+                bit_length: None,
+                scale: None,
+                offset: None,
+                scale_factor_register: None,
+                unit: None
+            };
+            assert_eq!(reader.parse(data).unwrap().value, DataPointValue::UInt(0x11223344), "{:?}", endianness);
+        }
+    }
+    #[test]
+    fn register_map_default_endianness() {
+        let data = r#"
+            endianness: CDAB
+            attributes: []
+            timeseries:
+              - starting_address: 0
+                elements_count: 4
+                data_points:
+                  - data_offset: 0
+                    register_count: 2
+                    data_type: float
+                    key_name: Inherited
+                  - data_offset: 4
+                    register_count: 2
+                    data_type: float
+                    key_name: Overridden
+                    endianness: ABCD
+        "#;
+        let register_map = ModbusRegisterMap::from_yaml(data).unwrap();
+        let data_points = &register_map.timeseries[0].data_points;
+        assert_eq!(data_points[0].endianness, Some(ModbusEndianness::Cdab));
+        assert_eq!(data_points[1].endianness, Some(ModbusEndianness::Abcd));
+    }
+    #[test]
+    fn register_map_points_are_joined() {
+        let data = r#"
+            endianness: CDAB
+            max_gap: 4
+            forbidden_ranges:
+              - start: 20
+                end: 29
+            timeseries_points:
+              - { address: 10, data_type: float, key_name: Voltage }
+              - { address: 14, data_type: uint16, key_name: Current }
+              - { address: 30, data_type: uint16, key_name: Energy }
+        "#;
+        let register_map = ModbusRegisterMap::from_yaml(data).unwrap();
+        assert!(register_map.attributes.is_empty());
+        let groups: Vec<(u16, u16, usize)> = register_map.timeseries.iter()
+            .map(|group| (group.starting_address, group.elements_count, group.data_points.len()))
+            .collect();
+        assert_eq!(groups, vec![(10, 5, 2), (30, 1, 1)]);
+        assert_eq!(register_map.timeseries[0].data_points[0].endianness, Some(ModbusEndianness::Cdab));
+
+        assert!(ModbusRegisterMap::from_yaml("attribute_points: [{ address: 0, data_type: string, key_name: Serial }]").is_err());
+    }
+    #[test]
+    fn construct_coil_register_group() {
+        let data = r#"
+            function: coils
+            starting_address: 10
+            elements_count: 4
+            data_points:
+              - data_offset: 2
+                register_count: 1
+                data_type: bool
+                key_name: Alarm
+        "#;
+        let group = serde_yaml::from_str::<ModbusRegisterGroup>(data).unwrap();
+        assert_eq!(group.function, ModbusReadFunction::Coils);
+        assert!(group.function.is_bit_function());
 
+        let point = group.data_points[0].parse_bits(vec![false, false, true, false]).unwrap();
+        assert_eq!(point.value, DataPointValue::Bool(true));
+        assert!(group.data_points[0].parse_bits(vec![false, false]).is_none());
 
+        let default_group = serde_yaml::from_str::<ModbusRegisterGroup>("starting_address: 0\nelements_count: 1\ndata_points: []").unwrap();
+        assert_eq!(default_group.function, ModbusReadFunction::HoldingRegisters);
     }
-}
\ No newline at end of file
+    fn reader(data_type: ModbusDataType, data_offset: usize, register_count: usize) -> ModbusDataPointReader {
+        ModbusDataPointReader {
+            data_offset,
+            register_count,
+            data_type,
+            key_name: String::from("Value"),
+            endianness: None,
+            bit_offset: None,
+            bit_length: None,
+            scale: None,
+            offset: None,
+            scale_factor_register: None,
+            unit: None
+        }
+    }
+    #[test]
+    fn parse_int16() {
+        let data = vec![0x0001, 0xFFFE];
+        assert_eq!(reader(ModbusDataType::Int16, 0, 1).parse(data.clone()).unwrap().value, DataPointValue::Int(1));
+        assert_eq!(reader(ModbusDataType::Int16, 2, 1).parse(data.clone()).unwrap().value, DataPointValue::Int(-2));
+        assert!(reader(ModbusDataType::Int16, 4, 1).parse(data).is_none());
+    }
+    #[test]
+    fn parse_64bit_integers() {
+        let data = vec![0xFFFF, 0xFFFF, 0xFFFF, 0xFFFE];
+        assert_eq!(reader(ModbusDataType::Int64, 0, 4).parse(data.clone()).unwrap().value, DataPointValue::Int(-2));
+        assert_eq!(reader(ModbusDataType::UInt64, 0, 4).parse(data.clone()).unwrap().value, DataPointValue::UInt(u64::MAX - 1));
+        assert!(reader(ModbusDataType::UInt64, 2, 4).parse(data.clone()).is_none());
+
+        let data = vec![0x0004, 0x0003, 0x0002, 0x0001];
+        let mut swapped = reader(ModbusDataType::UInt64, 0, 4);
+        swapped.endianness = Some(ModbusEndianness::Cdab);
+        assert_eq!(swapped.parse(data).unwrap().value, DataPointValue::UInt(0x0001000200030004));
+    }
+    #[test]
+    fn parse_string() {
+        // "SN-12345" followed by NUL padding
+        let data = vec![0x534E, 0x2D31, 0x3233, 0x3435, 0x0000];
+        assert_eq!(reader(ModbusDataType::String, 0, 5).parse(data.clone()).unwrap().value, DataPointValue::String(String::from("SN-12345")));
+        assert_eq!(reader(ModbusDataType::String, 2, 2).parse(data.clone()).unwrap().value, DataPointValue::String(String::from("-123")));
+        assert!(reader(ModbusDataType::String, 4, 5).parse(data.clone()).is_none());
+        assert!(reader(ModbusDataType::String, 0, 0).parse(data).is_none());
+
+        let mut swapped = reader(ModbusDataType::String, 0, 2);
+        swapped.endianness = Some(ModbusEndianness::Badc);
+        assert_eq!(swapped.parse(vec![0x4241, 0x4420]).unwrap().value, DataPointValue::String(String::from("AB D")));
+    }
+    #[test]
+    fn parse_bcd() {
+        assert_eq!(reader(ModbusDataType::Bcd16, 0, 1).parse(vec![0x1234]).unwrap().value, DataPointValue::UInt(1234));
+        assert_eq!(reader(ModbusDataType::Bcd32, 0, 2).parse(vec![0x0012, 0x3456]).unwrap().value, DataPointValue::UInt(123456));
+        assert!(reader(ModbusDataType::Bcd16, 0, 1).parse(vec![0x12A4]).is_none());
+        assert!(reader(ModbusDataType::Bcd32, 0, 2).parse(vec![0x1234]).is_none());
+    }
+    #[test]
+    fn parse_bitfield() {
+        let data = vec![0b0000_0000_1011_0100];
+        let mut bit = reader(ModbusDataType::Bitfield, 0, 1);
+        bit.bit_offset = Some(2);
+        assert_eq!(bit.parse(data.clone()).unwrap().value, DataPointValue::Bool(true));
+        bit.bit_offset = Some(3);
+        assert_eq!(bit.parse(data.clone()).unwrap().value, DataPointValue::Bool(false));
+
+        let mut field = reader(ModbusDataType::Bitfield, 0, 1);
+        field.bit_offset = Some(4);
+        field.bit_length = Some(4);
+        assert_eq!(field.parse(data.clone()).unwrap().value, DataPointValue::UInt(11));
+
+        field.bit_offset = Some(14);
+        assert!(field.parse(data.clone()).is_none());
+        field.bit_offset = None;
+        assert!(field.parse(data.clone()).is_none());
+        field.bit_offset = Some(0);
+        field.data_offset = 2;
+        assert!(field.parse(data).is_none());
+    }
+    #[test]
+    fn parse_scaled_value() {
+        let data = vec![2301, 0xFFFE, 1500];
+
+        let mut voltage = reader(ModbusDataType::UInt16, 0, 1);
+        voltage.scale = Some(0.1);
+        voltage.unit = Some(String::from("V"));
+        let point = voltage.parse(data.clone()).unwrap();
+        assert!((point.value.as_f64().unwrap() - 230.1).abs() < 1e-9);
+        assert_eq!(point.unit_attribute(), Some((String::from("Value_unit"), DataPointValue::String(String::from("V")))));
+
+        let mut temperature = reader(ModbusDataType::UInt16, 4, 1);
+        temperature.scale = Some(0.1);
+        temperature.offset = Some(-40.0);
+        assert!((temperature.parse(data.clone()).unwrap().value.as_f64().unwrap() - 110.0).abs() < 1e-9);
+
+        // SunSpec style, exponent -2 in second register
+        let mut power = reader(ModbusDataType::UInt16, 4, 1);
+        power.scale_factor_register = Some(1);
+        assert!((power.parse(data.clone()).unwrap().value.as_f64().unwrap() - 15.0).abs() < 1e-9);
+        power.scale_factor_register = Some(3);
+        assert!(power.parse(data.clone()).is_none());
+
+        let mut text = reader(ModbusDataType::String, 0, 1);
+        text.scale = Some(2.0);
+        assert!(text.parse(data).is_none());
+    }
+    #[test]
+    fn encoded_values_are_parsed_back() {
+        use super::ModbusGroupData;
+        use serde_json::json;
+        let mut data = ModbusGroupData::Registers(vec![0xFFFF, 0xFFFE, 0, 0, 0, 0]);
+
+        let mut float = reader(ModbusDataType::Float, 4, 2);
+        float.endianness = Some(ModbusEndianness::Cdab);
+        float.encode_data(&json!(230.5), &mut data).unwrap();
+        assert_eq!(float.parse_data(&data).unwrap().value, DataPointValue::Float(230.5));
+
+        // Exponent -2 in second register
+        let mut power = reader(ModbusDataType::UInt16, 8, 1);
+        power.scale_factor_register = Some(1);
+        power.encode_data(&json!(15.0), &mut data).unwrap();
+        assert_eq!(data, ModbusGroupData::Registers(vec![0xFFFF, 0xFFFE, 0x8000, 0x4366, 1500, 0]));
+
+        // Other bits of the register are kept
+        let mut field = reader(ModbusDataType::Bitfield, 0, 1);
+        field.bit_offset = Some(4);
+        field.bit_length = Some(4);
+        field.encode_data(&json!(3), &mut data).unwrap();
+        assert_eq!(field.parse_data(&data).unwrap().value, DataPointValue::UInt(3));
+        assert!(field.encode_data(&json!(16), &mut data).is_err());
+
+        let text = reader(ModbusDataType::String, 10, 1);
+        assert!(text.encode_data(&json!("ABC"), &mut data).is_err());
+        text.encode_data(&json!("AB"), &mut data).unwrap();
+        assert_eq!(data, ModbusGroupData::Registers(vec![0xFF3F, 0xFFFE, 0x8000, 0x4366, 1500, 0x4142]));
+
+        // Unscaled integers are not rounded and casts do not saturate
+        let mut setpoint = reader(ModbusDataType::Int16, 10, 1);
+        assert!(setpoint.encode_data(&json!(12.7), &mut data).is_err());
+        assert!(reader(ModbusDataType::UInt16, 10, 1).encode_data(&json!(-1), &mut data).is_err());
+        setpoint.encode_data(&json!(-12.0), &mut data).unwrap();
+        assert_eq!(setpoint.parse_data(&data).unwrap().value, DataPointValue::Int(-12));
+        setpoint.scale = Some(0.1);
+        setpoint.encode_data(&json!(1.27), &mut data).unwrap();
+        assert!((setpoint.parse_data(&data).unwrap().value.as_f64().unwrap() - 1.3).abs() < 1e-9);
+        let mut energy = reader(ModbusDataType::Int64, 0, 4);
+        energy.scale = Some(1.0);
+        assert!(energy.encode_data(&json!(1e30), &mut data).is_err());
+
+        let mut bits = ModbusGroupData::Bits(vec![false, false]);
+        reader(ModbusDataType::Bool, 1, 1).encode_data(&json!(true), &mut bits).unwrap();
+        assert_eq!(bits, ModbusGroupData::Bits(vec![false, true]));
+    }
+}
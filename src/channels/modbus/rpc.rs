@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::definitions::RpcRequest;
+use super::{ModbusDataType, ModbusEndianness, ModbusGroupData, ModbusRegisterMap, ModbusSlave};
+
+/// Modbus write functions that RPC methods can use
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusWriteFunction {
+    SingleCoil,        // FC5
+    SingleRegister,    // FC6
+    MultipleCoils,     // FC15
+    MultipleRegisters, // FC16
+}
+
+impl ModbusWriteFunction {
+    pub fn code(&self) -> u8 {
+        match self {
+            ModbusWriteFunction::SingleCoil => 5,
+            ModbusWriteFunction::SingleRegister => 6,
+            ModbusWriteFunction::MultipleCoils => 15,
+            ModbusWriteFunction::MultipleRegisters => 16,
+        }
+    }
+
+    pub fn is_bit_function(&self) -> bool {
+        matches!(self, ModbusWriteFunction::SingleCoil | ModbusWriteFunction::MultipleCoils)
+    }
+}
+
+/// Maps a ThingsBoard RPC method to a write on the device.
+/// Value is taken from RPC params, either directly or from `{"value": ...}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModbusRpcMethod {
+    pub method: String,
+    pub function: ModbusWriteFunction,
+    pub address: u16,
+    pub data_type: ModbusDataType,
+    pub register_count: Option<usize>, // Only for string, registers to write
+    pub endianness: Option<ModbusEndianness>, // Defaults to endianness of the register map
+}
+
+/// Write that is ready to be sent to a slave
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModbusWrite {
+    pub slave: ModbusSlave,
+    pub function: ModbusWriteFunction,
+    pub address: u16,
+    pub data: ModbusGroupData,
+}
+
+/// Finds slave and RPC method the request is meant for and encodes its params
+pub fn prepare_write(register_maps: &HashMap<ModbusSlave, ModbusRegisterMap>, request: &RpcRequest) -> Result<ModbusWrite, String> {
+    let (slave, reg_map) = register_maps.iter()
+        .find(|(slave, _)| slave.device_name == request.device)
+        .ok_or_else(|| format!("Unknown device: {}", request.device))?;
+    let method = reg_map.rpc.iter()
+        .find(|m| m.method == request.method)
+        .ok_or_else(|| format!("Unknown RPC method {} of device {}", request.method, request.device))?;
+
+    let value = match &request.params {
+        Value::Object(params) if params.contains_key("value") => &params["value"],
+        params => params
+    };
+    Ok(ModbusWrite {
+        slave: slave.clone(),
+        function: method.function,
+        address: method.address,
+        data: method.encode(value)?,
+    })
+}
+
+impl ModbusRpcMethod {
+    /// Encodes value with data type and endianness of this method
+    pub fn encode(&self, value: &Value) -> Result<ModbusGroupData, String> {
+        if self.function.is_bit_function() {
+            let bits = match (self.function, value) {
+                (ModbusWriteFunction::MultipleCoils, Value::Array(values)) => {
+                    values.iter().map(json_to_bool).collect::<Result<Vec<bool>, String>>()?
+                },
+                _ => vec![json_to_bool(value)?]
+            };
+            if !matches!(self.data_type, ModbusDataType::Bool) {
+                return Err(format!("Coils can only be written with bool data type, not {:?}", self.data_type));
+            }
+            return Ok(ModbusGroupData::Bits(bits));
+        }
+
+        let bytes = encode_bytes(&self.data_type, self.endianness.unwrap_or_default(), self.register_count, value)?;
+        let registers: Vec<u16> = bytes.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect();
+        if self.function == ModbusWriteFunction::SingleRegister && registers.len() != 1 {
+            return Err(format!("Data type {:?} does not fit in a single register", self.data_type));
+        }
+        Ok(ModbusGroupData::Registers(registers))
+    }
+}
+
+/// Encodes value of the data type into bytes in order of registers, strings take `register_count`
+/// registers or as many as the string needs when it is not set
+pub fn encode_bytes(data_type: &ModbusDataType, endianness: ModbusEndianness, register_count: Option<usize>, value: &Value) -> Result<Vec<u8>, String> {
+    let bytes = match data_type {
+        ModbusDataType::Bool => vec![0, json_to_bool(value)? as u8],
+        ModbusDataType::UInt16 => endianness.to_big_endian(&json_to_int::<u16>(value)?.to_be_bytes()),
+        ModbusDataType::Int16 => endianness.to_big_endian(&json_to_int::<i16>(value)?.to_be_bytes()),
+        ModbusDataType::UInt32 => endianness.to_big_endian(&json_to_int::<u32>(value)?.to_be_bytes()),
+        ModbusDataType::Int32 => endianness.to_big_endian(&json_to_int::<i32>(value)?.to_be_bytes()),
+        ModbusDataType::UInt64 => endianness.to_big_endian(&json_to_int::<u64>(value)?.to_be_bytes()),
+        ModbusDataType::Int64 => endianness.to_big_endian(&json_to_int::<i64>(value)?.to_be_bytes()),
+        ModbusDataType::Float => endianness.to_big_endian(&(json_to_f64(value)? as f32).to_be_bytes()),
+        ModbusDataType::Double => endianness.to_big_endian(&json_to_f64(value)?.to_be_bytes()),
+        ModbusDataType::Bcd16 => endianness.to_big_endian(&encode_bcd(json_to_int::<u64>(value)?, 2)?),
+        ModbusDataType::Bcd32 => endianness.to_big_endian(&encode_bcd(json_to_int::<u64>(value)?, 4)?),
+        ModbusDataType::String => {
+            let text = value.as_str().ok_or_else(|| format!("Expected string, got: {}", value))?;
+            let size = register_count.unwrap_or(text.len().div_ceil(2)) * 2;
+            if !text.is_ascii() || text.len() > size {
+                return Err(format!("String {:?} is not ASCII or longer than {} characters", text, size));
+            }
+            let mut bytes = text.as_bytes().to_vec();
+            bytes.resize(size, 0);
+            match endianness {
+                ModbusEndianness::Abcd | ModbusEndianness::Cdab => bytes,
+                ModbusEndianness::Badc | ModbusEndianness::Dcba => ModbusEndianness::Badc.to_big_endian(&bytes)
+            }
+        },
+        ModbusDataType::Bitfield => {
+            return Err(String::from("Bitfields can not be written"));
+        }
+    };
+    Ok(bytes)
+}
+
+pub fn json_to_bool(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::Number(n) if n.as_u64() == Some(0) || n.as_u64() == Some(1) => Ok(n.as_u64() == Some(1)),
+        Value::String(s) if s == "true" || s == "false" => Ok(s == "true"),
+        _ => Err(format!("Expected bool, got: {}", value))
+    }
+}
+
+pub fn json_to_f64(value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Number(n) => n.as_f64().ok_or_else(|| format!("Expected number, got: {}", value)),
+        Value::String(s) => s.parse::<f64>().map_err(|_| format!("Expected number, got: {}", value)),
+        _ => Err(format!("Expected number, got: {}", value))
+    }
+}
+
+/// Integers are accepted as json numbers or strings and must fit into T
+pub fn json_to_int<T: TryFrom<i128>>(value: &Value) -> Result<T, String> {
+    let number: Option<i128> = match value {
+        Value::Number(n) => n.as_i64().map(i128::from).or_else(|| n.as_u64().map(i128::from)),
+        Value::String(s) => s.parse::<i128>().ok(),
+        _ => None
+    };
+    number
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| format!("Expected integer in range of {}, got: {}", std::any::type_name::<T>(), value))
+}
+
+/// Packs decimal digits of value into `size` bytes of BCD
+fn encode_bcd(mut value: u64, size: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0u8; size];
+    for byte in bytes.iter_mut().rev() {
+        *byte = (value % 10) as u8 | (((value / 10) % 10) as u8) << 4;
+        value /= 100;
+    }
+    if value != 0 {
+        return Err(format!("Value does not fit in {} BCD digits", size * 2));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use serde_json::json;
+
+    use super::{prepare_write, ModbusRpcMethod, ModbusWriteFunction};
+    use crate::channels::modbus::{ModbusDataPointReader, ModbusDataType, ModbusEndianness, ModbusGroupData, ModbusRegisterMap, ModbusSlave};
+    use crate::definitions::{DataPointValue, RpcRequest};
+
+    fn method(function: ModbusWriteFunction, data_type: ModbusDataType) -> ModbusRpcMethod {
+        ModbusRpcMethod {
+            method: String::from("setValue"),
+            function,
+            address: 100,
+            data_type,
+            register_count: None,
+            endianness: None,
+        }
+    }
+
+    #[test]
+    fn encode_values() {
+        let coil = method(ModbusWriteFunction::SingleCoil, ModbusDataType::Bool);
+        assert_eq!(coil.encode(&json!(true)), Ok(ModbusGroupData::Bits(vec![true])));
+        assert!(coil.encode(&json!(2)).is_err());
+
+        let coils = method(ModbusWriteFunction::MultipleCoils, ModbusDataType::Bool);
+        assert_eq!(coils.encode(&json!([true, 0])), Ok(ModbusGroupData::Bits(vec![true, false])));
+
+        let register = method(ModbusWriteFunction::SingleRegister, ModbusDataType::Int16);
+        assert_eq!(register.encode(&json!(-2)), Ok(ModbusGroupData::Registers(vec![0xFFFE])));
+        assert!(register.encode(&json!(40000)).is_err());
+
+        let too_big = method(ModbusWriteFunction::SingleRegister, ModbusDataType::Float);
+        assert!(too_big.encode(&json!(1.0)).is_err());
+
+        let mut float = method(ModbusWriteFunction::MultipleRegisters, ModbusDataType::Float);
+        float.endianness = Some(ModbusEndianness::Cdab);
+        assert_eq!(float.encode(&json!("10.1")), Ok(ModbusGroupData::Registers(vec![0x999A, 0x4121])));
+
+        let bcd = method(ModbusWriteFunction::MultipleRegisters, ModbusDataType::Bcd32);
+        assert_eq!(bcd.encode(&json!(123456)), Ok(ModbusGroupData::Registers(vec![0x0012, 0x3456])));
+
+        let mut string = method(ModbusWriteFunction::MultipleRegisters, ModbusDataType::String);
+        string.register_count = Some(2);
+        assert_eq!(string.encode(&json!("ABC")), Ok(ModbusGroupData::Registers(vec![0x4142, 0x4300])));
+        assert!(string.encode(&json!("ABCDE")).is_err());
+    }
+
+    #[test]
+    fn encoded_values_decode_back() {
+        let mut float = method(ModbusWriteFunction::MultipleRegisters, ModbusDataType::Double);
+        float.endianness = Some(ModbusEndianness::Dcba);
+        let registers = match float.encode(&json!(230.5)).unwrap() {
+            ModbusGroupData::Registers(r) => r,
+            ModbusGroupData::Bits(_) => panic!("Expected registers")
+        };
+        let reader = ModbusDataPointReader {
+            data_offset: 0,
+            register_count: 4,
+            data_type: ModbusDataType::Double,
+            key_name: String::from("Value"),
+            endianness: Some(ModbusEndianness::Dcba),
+            bit_offset: None,
+            bit_length: None,
+            scale: None,
+            offset: None,
+            scale_factor_register: None,
+            unit: None
+        };
+        assert_eq!(reader.parse(registers).unwrap().value, DataPointValue::Float(230.5));
+    }
+
+    #[test]
+    fn prepare_rpc_write() {
+        let register_map = ModbusRegisterMap::from_yaml(r#"
+            endianness: CDAB
+            attributes: []
+            timeseries: []
+            rpc:
+              - method: setLimit
+                function: multiple_registers
+                address: 10
+                data_type: uint32
+        "#).unwrap();
+        let slave = ModbusSlave {
+            device_name: String::from("Meter1"),
+            device_type: None,
+            modbus_id: 1,
+            register_map: String::from("map.yml"),
+            poll_interval: None,
+            host: None,
+            port: None,
+        };
+        let mut register_maps = HashMap::new();
+        register_maps.insert(slave.clone(), register_map);
+
+        let mut request = RpcRequest {
+            device: String::from("Meter1"),
+            id: 1,
+            method: String::from("setLimit"),
+            params: json!({"value": 0x00010002}),
+        };
+        let write = prepare_write(&register_maps, &request).unwrap();
+        assert_eq!(write.slave, slave);
+        assert_eq!(write.address, 10);
+        assert_eq!(write.data, ModbusGroupData::Registers(vec![0x0002, 0x0001]));
+
+        request.method = String::from("unknown");
+        assert!(prepare_write(&register_maps, &request).is_err());
+        request.device = String::from("Meter2");
+        assert!(prepare_write(&register_maps, &request).is_err());
+    }
+}
@@ -1,36 +1,43 @@
-use std::io::{ErrorKind, Write, Read};
+use std::io::{self, ErrorKind, Write, Read};
 use std::time::Duration;
 use std::{thread::JoinHandle, collections::HashMap};
 use std::thread;
 use std::sync::mpsc;
 
-use crate::channels::DataPoint;
-use crate::definitions::{TimeseriesMessage, AttributeMessage, OneTelemetry};
+use crate::channels::ChannelAction;
 use crate::{channels::{Channel, ChannelStatus}, definitions::AggregatorAction};
 
-use super::{ModbusClientRtuConfig, ModbusSlave, ModbusRegisterMap};
-use bytebuffer::ByteBuffer;
-use rmodbus::{client::ModbusRequest, guess_response_frame_len, ModbusProto};
+use super::engine::{poll_slaves, ModbusError, ModbusTransport};
+use super::ascii::{AsciiStream, crc16};
+use super::{ModbusClientRtuConfig, ModbusSerialMode, ModbusRs485Config, ModbusRs485Mode, ModbusSlave, ModbusSlaveId, ModbusRegisterMap};
 use serialport::{self, SerialPort};
-// use libmodbus::{ModbusClient, Modbus,  ModbusRTU, ErrorRecoveryMode, Timeout};
+
+// Response timeout in milliseconds when it is not configured
+pub const DEFAULT_RESPONSE_TIMEOUT: u64 = 1000;
+// Unit id, 253 bytes of PDU and CRC
+const MAX_RTU_FRAME: usize = 256;
+
 #[derive(Debug)]
 pub struct ModbusRtuChannel {
     config: ModbusClientRtuConfig,
     status: ChannelStatus,
     register_maps: HashMap<ModbusSlave, ModbusRegisterMap>,
-    aggregator_tx: mpsc::Sender<AggregatorAction>
+    aggregator_tx: mpsc::Sender<AggregatorAction>,
+    channel_rx: mpsc::Receiver<ChannelAction>
 }
 
 impl ModbusRtuChannel {
     pub fn new(
         config: ModbusClientRtuConfig,
         register_maps: HashMap<ModbusSlave, ModbusRegisterMap>,
-        aggregator_tx: mpsc::Sender<AggregatorAction>
+        aggregator_tx: mpsc::Sender<AggregatorAction>,
+        channel_rx: mpsc::Receiver<ChannelAction>
     ) -> Self {
         Self {
             config,
             register_maps,
             aggregator_tx,
+            channel_rx,
             status: ChannelStatus::Stopped
         }
     }
@@ -38,463 +45,21 @@ impl ModbusRtuChannel {
 
 
 impl Channel for ModbusRtuChannel {
-    fn run(self) -> JoinHandle<()>{
+    fn run(mut self) -> JoinHandle<()>{
+        self.status = ChannelStatus::Running;
         log::trace!("RTU CONFIG: {:?}", self);
 
         let builder = thread::Builder::new()
-            .name(self.config.name)
+            .name(self.config.name.clone())
             .spawn(move || {
 
             let aggregator = self.aggregator_tx.clone();
 
-                let parity = match self.config.parity {
-                    'E' => serialport::Parity::Even,
-                    'N' => serialport::Parity::None,
-                    'O' => serialport::Parity::Odd,
-                    _ => {
-                        log::warn!("Could not set parity: {:?}, defaulting to Parity::None", self.config.parity);
-                        serialport::Parity::None
-                    }
-                };
-                let databits = match self.config.data_bits {
-                    8 => serialport::DataBits::Eight,
-                    7 => serialport::DataBits::Seven,
-                    6 => serialport::DataBits::Six,
-                    5 => serialport::DataBits::Five,
-                    _ => {
-                        log::warn!("Could not set data_bits: {:?}, defaulting to DataBits::Eight", self.config.data_bits);
-                        serialport::DataBits::Eight
-                    }
-                };
-            // modbus.set_debug(true);
-            // modbus.rtu_set_serial_mode(SerialMode::RtuRS485).unwrap();
-            // modbus.rtu_set_rts(RequestToSendMode::RtuRtsUp).unwrap();
-                let mut reg_maps  = self.register_maps.clone();
+                let reg_maps = self.register_maps.clone();
             
                 log::debug!("Opening serial port: {:?}", self.config.port);
-                let builder = serialport::new(self.config.port.clone(), self.config.baudrate);
-                let builder = builder.timeout(Duration::from_secs(1));
-                let builder = builder.data_bits(databits);
-                let builder = builder.parity(parity);
-                let mut port = builder.open_native().expect(&format!("Could not open serial port: {:?}", self.config.port.clone()));
-                // log::debug!("Creating RTU Context");
-                
-                // let mut modbus = Modbus::new_rtu(
-                //         &self.config.port,
-                //         self.config.baudrate.try_into().unwrap(),
-                //         self.config.parity,
-                //         self.config.data_bits.into(),
-                //         self.config.stop_bits.into()).unwrap();
-                // modbus.set_byte_timeout(Timeout::new(0,50000)).unwrap();
-                // modbus.set_response_timeout(Timeout::new(1,50000)).unwrap();
-                // modbus.set_error_recovery(Some(&[ErrorRecoveryMode::Protocol, ErrorRecoveryMode::Link])).unwrap();
-
-
-                
-                // File descriptor slave
-                // key: modbus_id
-                // value: descriptor id
-                let mut fds: HashMap<u8, i32> = HashMap::new();
-                // if reg_maps.len() < 1000 {
-                //     let mut i = 1;
-                //     for (slave,reg_map) in &reg_maps {
-                //         fds.insert(slave.modbus_id.clone(), i  );
-                //         i += 1;
-                //     };
-                // }
-                log::debug!("FDS: {:?}", fds);
-                // while let Err(e) = modbus.connect() {
-                //     log::error!("Error connecting to serial line: {:?}", self.config.port);
-                //     thread::sleep(Duration::from_millis(1000));
-                // };
-                loop {
-
-                    for (slave, reg_map) in &reg_maps {
-
-
-
-                        // Set correct ModbusID to call on
-                        // ctx.set_slave(Slave(slave.modbus_id));
-                        // log::info!("Connecting to slave with id: {}",slave.modbus_id );
-                        // log::debug!("Filedescriptor Slaves Hashmap: {:?}", fds);
-                        // match fds.get(&slave.modbus_id) {
-                            // Some(f) => {
-                                // log::debug!("Found existing file descriptor for slave {} ", slave.modbus_id);
-                        // modbus.set_socket(slave.modbus_id.into()).unwrap();
-                        // modbus.set_slave(slave.modbus_id).unwrap();
-                        // match  modbus.get_socket() {
-                        //     Ok(fd) => {
-                        //         log::info!("Connected  to slave with id : {:?}, on file descriptor: {:?}",
-                        //         slave.modbus_id, fd);
-                        //     },
-                        //     Err(e) => log::error!("Error getting file descriptor for slave with id: {:?}", slave.modbus_id)
-                        // }
-
-                        let mut attributes_message: AttributeMessage = (slave.device_name.clone(), HashMap::new());
-                        let mut timeseries_message: TimeseriesMessage = (slave.device_name.clone(), vec![]);
-                        // Read Attributes 
-                        for reg_group in &reg_map.attributes {
-                            
-                            let mut mreq = ModbusRequest::new(slave.modbus_id, ModbusProto::Rtu);
-                            let mut read_buffer =  Vec::new();
-                            let mut request = Vec::new();
-
-                            let mut data_point_vec: Vec<DataPoint> = vec![];
-                            match port.clear(serialport::ClearBuffer::All) {
-                                Err(e) => log::error!("Error clearing buffers! {:?}", e),
-                                _ => ()
-                            }
-
-                            // Call group 
-                            log::trace!("Reading starting address: {:}, and register count: {:}", reg_group.starting_address, reg_group.elements_count);
-                            
-                            match mreq.generate_get_holdings(
-                                reg_group.starting_address, reg_group.elements_count, &mut request) {
-
-                                Err(e) => {
-                                    log::error!("Error creating read holding request: {:?}", e);
-                                    continue;
-                                },
-                                Ok(_) => log::debug!("Created read holding registers request...")
-                            }
-                            match port.write(&request) {
-                                Ok(_) => {
-                                    log::debug!("Send request to read holding registers...");
-                                    if port.flush().is_err() {log::warn!("Error flush write buffer...")};
-                                },
-                                Err(e) => {
-                                    log::error!("Could not send request for holding registers: {:?}", e);
-                                    continue;
-                                }
-                            }
-                            // Possible timeout window
-                            let mut buf = [0,0,0,0,0,0,0,0,0,0];
-                            match port.read_exact(&mut buf) {
-                                Ok(_) => log::debug!("read first response correctly"),
-                                Err(e) => {
-                                    match port.clear(serialport::ClearBuffer::Input) {
-                                        Err(e) => log::error!("Error clearing input buffer! {:?}", e),
-                                        _ => ()
-                                    }
-                                    match e.kind() {
-                                        ErrorKind::TimedOut => {
-                                            log::warn!("Operation to read first response timedout");
-                                            continue;
-                                        },
-                                        _ => {
-                                            log::error!("Error reading first response: {:?}",e );
-                                            continue;
-                                        }
-                                    }
-                                }
-                            }
-                            match port.clear(serialport::ClearBuffer::Input) {
-                                Err(e) => log::error!("Error clearing input buffer! {:?}", e),
-                                _ => ()
-                            }
-                            log::trace!("From serial read this buffer: {:?}", buf);
-                            let len = guess_response_frame_len(&buf, ModbusProto::Rtu);
-                            let mut response = Vec::new();
-                            response.extend_from_slice(&buf);
-                            match len {
-                                Err(e) => {
-                                    log::error!("Error guessing response frame len: {:?}", e);
-                                    match port.clear(serialport::ClearBuffer::Input) {
-                                        Err(e) => log::error!("Error clearing input buffer! {:?}", e),
-                                        _ => ()
-                                    }
-                                    continue;
-                                },
-                                Ok(i) => {
-                                    if i > 10{
-                                        let mut rest = vec![0u8; (i - 10) as usize];
-                                        match port.read_exact(&mut rest) {
-                                            Ok(_) => {
-                                                log::debug!("read first response correctly");
-                                                match port.clear(serialport::ClearBuffer::Input) {
-                                                    Err(e) => log::error!("Error clearing input buffer! {:?}", e),
-                                                    _ => ()
-                                                }
-
-                                            },
-                                            Err(e) => 
-                                            {
-                                                match port.clear(serialport::ClearBuffer::Input) {
-                                                    Err(e) => log::error!("Error clearing input buffer! {:?}", e),
-                                                    _ => ()
-                                                }
-                                                match e.kind() {
-                                                    ErrorKind::TimedOut => {
-                                                        log::warn!("Operation to read first response timedout");
-                                                        continue;
-                                                    }
-                                                    _ => {
-                                                        log::error!("Error reading first response: {:?}",e );
-                                                        continue;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        response.extend(rest);
-                                    }
-                                }
-                            }
-                            log::trace!("Checking response: {:?}", response);
-                            // let mut read_buffer = Vec::new();
-                            match mreq.parse_ok(&response) {
-                                Ok(_) => {
-                                    log::debug!("Modbus response is OK");
-                                    match mreq.parse_u16(&response, &mut read_buffer) {
-                                        Ok(_) => log::info!("successfully extracted data from response!"),
-                                        Err(e) => {
-                                            log::error!("Error extracting data from response: {:?}", e);
-                                            continue;
-                                        }
-                                    };
-                                },
-                                Err(e) => {
-                                    log::error!("Error in modbus response: {:?}", e);
-                                    continue;
-                                }
-                            };
-                            log::trace!("Read Raw data: {:?}", read_buffer);
-
-
-
-                            // let reg_response = modbus.read_registers(
-                            //         reg_group.starting_address,
-                            //         reg_group.elements_count,
-                            //         &mut read_buffer);
-
-                            // match modbus.flush() {
-                            //     Ok(_) => log::debug!("Flushed untransmited data..."),
-                            //     Err(e) => log::error!("Error flushing untransmited data: {:?}", e)
-                            // }
-
-                            // match reg_response {
-                            //     Ok(res) => log::debug!("Read {:?} holding registers...", res ),
-                            //     Err(e) => {
-                            //         log::error!("Error reading holding registers: {:?}", e);
-                            //         continue
-                            //     }
-                            // };
-                            let mut buff = ByteBuffer::new();
-                            for word in read_buffer.clone() {
-                                buff.write_u16(word);
-                            }
-
-
-                            for data_point in &reg_group.data_points {
-                                match data_point.parse(read_buffer.clone()) {
-                                    Some(point) => {
-
-                                        data_point_vec.push(point.clone());
-                                        attributes_message.1.insert(point.key, point.value);
-                                    },
-                                    None => continue
-                                }
-                                // parse into message
-                            }
-
-                            log::info!("Read and parsed register group with data {} points", data_point_vec.len());
-                            log::trace!("Datapoints in reg group: {:?}", data_point_vec);
-                            log::trace!("Attribute message: {:?}", attributes_message)
-
-                        }
-                        // Read Timeseries
-                        // thread::sleep(Duration::from_millis(100));
-                        for reg_group in &reg_map.timeseries {
-
-                            let mut mreq = ModbusRequest::new(slave.modbus_id, ModbusProto::Rtu);
-                            let mut read_buffer =  Vec::new();
-                            let mut request = Vec::new();
-
-                            let mut data_point_vec: Vec<DataPoint> = vec![];
-
-                            // Call group 
-                            log::info!("Reading starting address: {:}, and register count: {:} of slave with id: {:?}", reg_group.starting_address, reg_group.elements_count, slave.modbus_id);
-                            match port.clear(serialport::ClearBuffer::All) {
-                                Err(e) => log::error!("Error clearing output buffer! {:?}", e),
-                                _ => ()
-                            }
-                            
-                            match mreq.generate_get_holdings(
-                                reg_group.starting_address, reg_group.elements_count, &mut request) {
-
-                                Err(e) => log::error!("Error creating read holding request: {:?}", e),
-                                Ok(_) => log::debug!("Created read holding registers request...")
-                            }
-                            match port.write(&request) {
-                                Ok(_) => {
-                                    log::debug!("Send request to read holding registers...");
-                                    if port.flush().is_err() {log::warn!("Error flush write buffer...")};
-                                },
-                                Err(e) => {
-                                    log::error!("Could not send request for holding registers: {:?}", e);
-                                    continue;
-                                }
-                            }
-                            // Possible timeout window
-                            let mut buf = [0,0,0,0,0,0,0,0,0,0];
-                            match port.read_exact(&mut buf) {
-                                Ok(_) => log::debug!("read first response correctly"),
-                                Err(e) => match e.kind() {
-                                    ErrorKind::TimedOut => {
-                                        log::warn!("Operation to read first response timedout");
-                                        continue;
-                                    }
-                                    _ => {
-                                        log::error!("Error reading first response: {:?}",e );
-                                        continue;
-                                    }
-                                }
-                            }
-                            log::trace!("From serial read this buffer: {:?}", buf);
-                            let len = guess_response_frame_len(&buf, ModbusProto::Rtu);
-                            let mut response = Vec::new();
-                            response.extend_from_slice(&buf);
-                            match len {
-                                Err(e) => {
-                                    log::error!("Error guessing response frame len: {:?}", e);
-                                    continue;
-                                },
-                                Ok(i) => {
-                                    if i > 10{
-                                        let mut rest = vec![0u8; (i - 10) as usize];
-                                        match port.read_exact(&mut rest) {
-                                            Ok(_) => log::debug!("read first response correctly"),
-                                            Err(e) => match e.kind() {
-                                                ErrorKind::TimedOut => {
-                                                    log::warn!("Operation to read first response timedout");
-                                                    continue;
-                                                }
-                                                _ => {
-                                                    log::error!("Error reading first response: {:?}",e );
-                                                    continue;
-                                                }
-                                            }
-                                        }
-                                        response.extend(rest);
-                                    }
-                                }
-                            }
-                            log::trace!("Checking response: {:?}", response);
-                            // let mut read_buffer = Vec::new();
-                            match mreq.parse_ok(&response) {
-                                Ok(_) => {
-                                    log::debug!("Modbus response is OK");
-                                    match mreq.parse_u16(&response, &mut read_buffer) {
-                                        Ok(_) => log::info!("successfully extracted data from response!"),
-                                        Err(e) => {
-                                            log::error!("Error extracting data from response: {:?}", e);
-                                            continue;
-                                        }
-                                    };
-                                },
-                                Err(e) => {
-                                    log::error!("Error in modbus response: {:?}", e);
-                                    continue;
-                                }
-                          
-                            };
-                            log::trace!("Read Raw data: {:?}", read_buffer);
-
-
-
-                            // OLD
-                            
-                            // let mut mreq = ModbusRequest::new(slave.modbus_id, ModbusProto::Rtu);
-                            // let mut read_buffer = Vec::new();
-                            // let mut request = Vec::new();
-
-                            // let mut data_point_vec: Vec<DataPoint> = vec![];
-
-                            // // Call group 
-                            
-                            // match mreq.generate_get_holdings(
-                            //     reg_group.starting_address, reg_group.elements_count, &mut request) {
-
-                            //     Err(e) => log::error!("Error creating read holding request: {:?}", e),
-                            //     Ok(_) => log::debug!("Created read holding registers request...")
-                            // }
-                            // port.clear(serialport::ClearBuffer::All).unwrap();
-                            // port.write(&request).unwrap();
-                            // // Possible timeout window
-                            // let mut buf = [0,0,0,0,0,0,0,0,0,0];
-                            // port.read(&mut buf).unwrap();
-                            // log::trace!("From serial read this buffer: {:?}", buf);
-                            
-
-                            // let len = guess_response_frame_len(&buf, ModbusProto::Rtu);
-                            // let mut response = Vec::new();
-                            // response.extend_from_slice(&buf);
-                            // match len {
-                            //     Err(e) => log::error!("Error guessing response frame len: {:?}", e),
-                            //     Ok(i) => {
-                            //         if i > 10{
-                            //             let mut rest = vec![0u8; (i - 10) as usize];
-                            //             port.read_exact(&mut rest).unwrap();
-                            //             response.extend(rest);
-                            //         }
-                            //     }
-                            // }
-                            // // let mut read_buffer = Vec::new();
-                            // match mreq.parse_ok(&response) {
-                            //     Ok(_) => {
-                            //         log::debug!("Modbus response is OK");
-                            //         match mreq.parse_u16(&response, &mut read_buffer) {
-                            //             Ok(_) => log::info!("successfully extracted data from response!"),
-                            //             Err(e) => log::error!("Error extracting data from response: {:?}", e)
-                            //         };
-                            //     },
-                            //     Err(e) => log::error!("Error in modbus response: {:?}", e)
-                            // };
-                            // log::trace!("Read Raw data: {:?}", read_buffer);
-
-                            // log::trace!("Reading starting address: {:}, and register count: {:}", reg_group.starting_address, reg_group.elements_count);
-                            // let reg_response = modbus.read_registers(
-                            //         reg_group.starting_address,
-                            //         reg_group.elements_count,
-                            //         &mut read_buffer);
-                            
-                            // match modbus.flush() {
-                            //     Ok(_) => log::debug!("Flushed untransmited data..."),
-                            //     Err(e) => log::error!("Error flushing untransmited data: {:?}", e)
-                            // }
-                            // match reg_response {
-                            //     Ok(res) => log::debug!("Read {:?} holding registers...", res ),
-                            //     Err(e) => {
-                            //         log::error!("Error reading holding registers: {:?}", e);
-                            //         continue
-                            //     }
-                            // };
-
-                            for data_point in &reg_group.data_points {
-                                match  data_point.parse(read_buffer.clone()) {
-                                    Some(point) => {
-                                        data_point_vec.push(point);
-
-                                    },
-                                    None => continue
-                                }
-                            }
-                            
-
-                            log::info!("Read and parsed register group with data {} points", data_point_vec.len());
-                            log::trace!("Datapoints in reg group: {:?}", data_point_vec);
-                            timeseries_message.1.push(OneTelemetry::from(data_point_vec));
-                        }
-
-                        match aggregator.send(AggregatorAction::SendBoth(attributes_message, timeseries_message)) {
-                            Err(e) => log::error!("Error sending data to aggregation thread! Did it panic? : {:#?}", e),
-                            _ => {}
-                        }
-                    }
-
-                    
-                    // modbus.close();
-                    thread::sleep(Duration::from_millis(10000));
-                }
+                let mut transport = open_transport(&self.config).unwrap_or_else(|e| panic!("{}", e));
+                poll_slaves(transport.as_mut(), &reg_maps, &aggregator, &self.channel_rx);
                 
         }).unwrap();
         builder
@@ -502,6 +67,449 @@ impl Channel for ModbusRtuChannel {
     }
 
     fn status(&self) ->  crate::channels::ChannelStatus {
-        todo!()
+        self.status.clone()
+    }
+}
+
+/// Byte stream carrying RTU frames, serial line or socket
+pub trait RtuStream: Read + Write {
+    fn clear(&mut self, buffer_to_clear: serialport::ClearBuffer) -> io::Result<()>;
+    // Reads time out with ErrorKind::TimedOut
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl<T: SerialPort> RtuStream for T {
+    fn clear(&mut self, buffer_to_clear: serialport::ClearBuffer) -> io::Result<()> {
+        SerialPort::clear(self, buffer_to_clear).map_err(io::Error::from)
     }
-}
\ No newline at end of file
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        SerialPort::set_timeout(self, timeout).map_err(io::Error::from)
+    }
+}
+
+/// Serial port which drives RS-485 transceiver direction with RTS around every sent frame
+struct RtsToggle<P: SerialPort> {
+    port: P,
+    rts_on_send: bool,
+    delay_before_send: Duration,
+    delay_after_send: Duration
+}
+
+impl<P: SerialPort> RtsToggle<P> {
+    fn new(mut port: P, config: &ModbusRs485Config) -> Self {
+        let rts_on_send = config.rts_on_send.unwrap_or(true);
+        if let Err(e) = port.write_request_to_send(!rts_on_send) {
+            log::error!("Error setting RTS: {:?}", e);
+        }
+        Self {
+            port,
+            rts_on_send,
+            delay_before_send: Duration::from_millis(config.delay_before_send.unwrap_or(0).into()),
+            delay_after_send: Duration::from_millis(config.delay_after_send.unwrap_or(0).into())
+        }
+    }
+}
+
+impl<P: SerialPort> Read for RtsToggle<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl<P: SerialPort> Write for RtsToggle<P> {
+    /// Whole frame has to be written at once, receiving is enabled right after it was transmitted
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write_request_to_send(self.rts_on_send)?;
+        thread::sleep(self.delay_before_send);
+        let written = self.port.write_all(buf).and_then(|_| self.port.flush());
+        thread::sleep(self.delay_after_send);
+        self.port.write_request_to_send(!self.rts_on_send)?;
+        written.map(|_| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<P: SerialPort> RtuStream for RtsToggle<P> {
+    fn clear(&mut self, buffer_to_clear: serialport::ClearBuffer) -> io::Result<()> {
+        SerialPort::clear(&self.port, buffer_to_clear).map_err(io::Error::from)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_timeout(timeout).map_err(io::Error::from)
+    }
+}
+
+/// Layout of struct serial_rs485 from linux/serial.h
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct SerialRs485 {
+    flags: u32,
+    delay_rts_before_send: u32,
+    delay_rts_after_send: u32,
+    padding: [u32; 5]
+}
+
+/// Lets the UART driver toggle RTS while sending
+#[cfg(target_os = "linux")]
+fn enable_rs485(port: &serialport::TTYPort, config: &ModbusRs485Config) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    const SER_RS485_ENABLED: u32 = 1;
+    const SER_RS485_RTS_ON_SEND: u32 = 1 << 1;
+    const SER_RS485_RTS_AFTER_SEND: u32 = 1 << 2;
+
+    let mut rs485 = SerialRs485 {
+        flags: SER_RS485_ENABLED,
+        delay_rts_before_send: config.delay_before_send.unwrap_or(0),
+        delay_rts_after_send: config.delay_after_send.unwrap_or(0),
+        ..Default::default()
+    };
+    rs485.flags |= if config.rts_on_send.unwrap_or(true) { SER_RS485_RTS_ON_SEND } else { SER_RS485_RTS_AFTER_SEND };
+    set_serial_rs485(port.as_raw_fd(), &rs485)
+}
+
+/// Neither serialport nor nix wrap TIOCSRS485, so this is the only unsafe code of the gateway.
+/// It is sound because `rs485` has the layout the kernel expects and the ioctl only reads it during the call
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+fn set_serial_rs485(fd: std::os::unix::io::RawFd, rs485: &SerialRs485) -> io::Result<()> {
+    match unsafe { libc::ioctl(fd, libc::TIOCSRS485, rs485 as *const SerialRs485) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_rs485<P>(_port: &P, _config: &ModbusRs485Config) -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Unsupported, "RS-485 kernel mode is only supported on linux, use software mode"))
+}
+
+/// Opens serial port with settings of the channel, requests are framed as RTU or ASCII by `mode`
+pub fn open_transport(config: &ModbusClientRtuConfig) -> Result<Box<dyn ModbusTransport>, String> {
+    // Settings were validated when the config was loaded
+    let timing = RtuTiming::serial(
+        config.baudrate,
+        config.character_bits(),
+        Duration::from_millis(config.response_timeout.unwrap_or(DEFAULT_RESPONSE_TIMEOUT)),
+        Duration::from_millis(config.request_delay.unwrap_or(0)));
+    log::debug!("Timing of serial line: {:?}", timing);
+    let port = serialport::new(config.port.clone(), config.baudrate)
+        .timeout(timing.response_timeout)
+        .data_bits(config.serial_data_bits()?)
+        .parity(config.serial_parity()?)
+        .stop_bits(config.serial_stop_bits()?)
+        .flow_control(config.serial_flow_control())
+        .open_native()
+        .map_err(|e| format!("Could not open serial port {:?}: {:?}", config.port, e))?;
+    match &config.rs485 {
+        Some(rs485) if rs485.mode == ModbusRs485Mode::Software => Ok(serial_transport(config.mode, RtsToggle::new(port, rs485), timing)),
+        Some(rs485) => {
+            enable_rs485(&port, rs485).map_err(|e| format!("Could not enable RS-485 on serial port {:?}: {:?}", config.port, e))?;
+            Ok(serial_transport(config.mode, port, timing))
+        },
+        None => Ok(serial_transport(config.mode, port, timing))
+    }
+}
+
+fn serial_transport<S: RtuStream + 'static>(mode: ModbusSerialMode, port: S, timing: RtuTiming) -> Box<dyn ModbusTransport> {
+    match mode {
+        ModbusSerialMode::Rtu => Box::new(RtuTransport::new(port, timing)),
+        ModbusSerialMode::Ascii => {
+            // Characters of ASCII frame may be up to a second apart, frames are received whole by AsciiStream
+            let timing = RtuTiming { silent_interval: timing.response_timeout, ..timing };
+            Box::new(RtuTransport::new(AsciiStream::new(port), timing))
+        }
+    }
+}
+
+/// Timing of requests and responses on the line
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtuTiming {
+    // Wait for the first byte of the response
+    pub response_timeout: Duration,
+    // Silence which ends a frame
+    pub silent_interval: Duration,
+    // Pause before every request
+    pub request_delay: Duration
+}
+
+impl RtuTiming {
+    /// Frame ends after 3.5 characters of silence, it is fixed to 1.75 ms above 19200 baud.
+    /// Interval is rounded up to whole milliseconds, serial port timeouts are not more precise
+    pub fn serial(baudrate: u32, character_bits: u32, response_timeout: Duration, request_delay: Duration) -> Self {
+        let micros = if baudrate > 19200 {
+            1750
+        } else {
+            (3_500_000 * character_bits as u64) / baudrate.max(1) as u64
+        };
+        Self {
+            response_timeout,
+            silent_interval: Duration::from_millis(micros.div_ceil(1000)),
+            request_delay
+        }
+    }
+}
+
+/// Frames PDUs with unit id and CRC, used by serial and socket channels
+pub struct RtuTransport<S: RtuStream> {
+    port: S,
+    timing: RtuTiming
+}
+
+impl<S: RtuStream> RtuTransport<S> {
+    pub fn new(port: S, timing: RtuTiming) -> Self {
+        Self { port, timing }
+    }
+}
+
+impl<S: RtuStream> ModbusTransport for RtuTransport<S> {
+    fn transact(&mut self, modbus_id: ModbusSlaveId, request: &[u8]) -> Result<Vec<u8>, ModbusError> {
+        let mut frame = vec![modbus_id];
+        frame.extend_from_slice(request);
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        let response = exchange(&mut self.port, &self.timing, &frame)?;
+        Ok(response[1..response.len() - 2].to_vec())
+    }
+}
+
+/// Sends request frame and receives response to it
+fn exchange<S: RtuStream + ?Sized>(port: &mut S, timing: &RtuTiming, request: &[u8]) -> Result<Vec<u8>, ModbusError> {
+    // Late or unsolicited bytes would be taken for the response
+    if let Err(e) = port.clear(serialport::ClearBuffer::All) {
+        log::error!("Error clearing buffers! {:?}", e);
+    }
+    thread::sleep(timing.request_delay);
+    port.write_all(request).map_err(stream_error)?;
+    if port.flush().is_err() {log::warn!("Error flush write buffer...")};
+    let response = receive_frame(port, timing)?;
+    check_response(request, &response)?;
+    Ok(response)
+}
+
+/// Receives one frame, it is complete when its expected length was received,
+/// otherwise it ends with the silent interval. Only its first byte is awaited
+/// until the response timeout, so that short or corrupted frames end early
+pub(super) fn receive_frame<S: RtuStream + ?Sized>(port: &mut S, timing: &RtuTiming) -> Result<Vec<u8>, ModbusError> {
+    let mut frame = Vec::new();
+    let mut buf = [0u8; MAX_RTU_FRAME];
+    loop {
+        let timeout = if frame.is_empty() { timing.response_timeout } else { timing.silent_interval };
+        // Zero timeout would make the read non-blocking
+        if timeout.is_zero() {
+            if frame.is_empty() {
+                return Err(ModbusError::Timeout);
+            }
+            break;
+        }
+        port.set_timeout(timeout)?;
+        match port.read(&mut buf) {
+            Ok(0) => return Err(ModbusError::Io(io::Error::from(ErrorKind::UnexpectedEof))),
+            Ok(count) => frame.extend_from_slice(&buf[..count]),
+            Err(e) if e.kind() == ErrorKind::TimedOut && frame.is_empty() => return Err(ModbusError::Timeout),
+            Err(e) if e.kind() == ErrorKind::TimedOut => break,
+            // AsciiStream rejects frames with bad hex or LRC, they are requested again like bad CRC
+            Err(e) if e.kind() == ErrorKind::InvalidData => return Err(ModbusError::Garbled(e.to_string())),
+            Err(e) => return Err(stream_error(e))
+        }
+        if let Some(len) = expected_frame_len(&frame) {
+            if frame.len() >= len {
+                frame.truncate(len);
+                break;
+            }
+        }
+        if frame.len() >= MAX_RTU_FRAME {
+            break;
+        }
+    }
+    log::trace!("Received frame: {:?}", frame);
+    if frame.len() < 4 {
+        return Err(ModbusError::Garbled(format!("Frame is too short: {:?}", frame)));
+    }
+    if let Some(len) = expected_frame_len(&frame) {
+        if frame.len() < len {
+            return Err(ModbusError::Garbled(format!("Incomplete frame, received {} of {} bytes", frame.len(), len)));
+        }
+    }
+    let (payload, crc) = frame.split_at(frame.len() - 2);
+    if crc16(payload).to_le_bytes() != crc {
+        return Err(ModbusError::Garbled(format!("Invalid CRC of frame: {:?}", frame)));
+    }
+    Ok(frame)
+}
+
+/// Stream which can not reach the line (e.g. socket to a converter) stops polling of the other slaves
+fn stream_error(e: io::Error) -> ModbusError {
+    match e.kind() {
+        ErrorKind::NotConnected => ModbusError::NotConnected(e.to_string()),
+        _ => ModbusError::Io(e)
+    }
+}
+
+/// Length of response frame guessed from its header
+fn expected_frame_len(frame: &[u8]) -> Option<usize> {
+    let function = *frame.get(1)?;
+    if function & 0x80 != 0 {
+        return Some(5);
+    }
+    match function {
+        0x01..=0x04 => frame.get(2).map(|count| 5 + *count as usize),
+        0x05 | 0x06 | 0x0F | 0x10 => Some(8),
+        _ => None
+    }
+}
+
+/// Response has to come from the requested slave with the requested function, exceptions are decoded by the engine
+fn check_response(request: &[u8], response: &[u8]) -> Result<(), ModbusError> {
+    if response[0] != request[0] {
+        return Err(ModbusError::Garbled(format!("Response from slave {} to request for slave {}", response[0], request[0])));
+    }
+    if response[1] & 0x7F != request[1] {
+        return Err(ModbusError::Garbled(format!("Response with function {} to request with function {}", response[1] & 0x7F, request[1])));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::{RtuStream, RtuTiming, RtuTransport, receive_frame};
+    use crate::channels::modbus::engine::{ModbusError, ModbusTransport};
+
+    /// Returns received chunks one per read, reads time out when there are none left
+    struct FakeLine {
+        chunks: VecDeque<Vec<u8>>,
+        written: Vec<u8>
+    }
+
+    impl Read for FakeLine {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = self.chunks.pop_front().ok_or_else(|| io::Error::from(ErrorKind::TimedOut))?;
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    impl Write for FakeLine {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl RtuStream for FakeLine {
+        fn clear(&mut self, _buffer_to_clear: serialport::ClearBuffer) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Returns chunks after their delay, reads time out when the delay is longer than the timeout
+    struct DelayedLine {
+        chunks: VecDeque<(Duration, Vec<u8>)>,
+        timeout: Duration
+    }
+
+    impl Read for DelayedLine {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let (delay, chunk) = self.chunks.front_mut().ok_or_else(|| io::Error::from(ErrorKind::TimedOut))?;
+            if *delay > self.timeout {
+                thread::sleep(self.timeout);
+                *delay -= self.timeout;
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            thread::sleep(*delay);
+            let count = chunk.len();
+            buf[..count].copy_from_slice(chunk);
+            self.chunks.pop_front();
+            Ok(count)
+        }
+    }
+
+    impl Write for DelayedLine {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl RtuStream for DelayedLine {
+        fn clear(&mut self, _buffer_to_clear: serialport::ClearBuffer) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+    }
+
+    fn line(chunks: Vec<Vec<u8>>) -> FakeLine {
+        FakeLine { chunks: chunks.into(), written: vec![] }
+    }
+
+    fn timing() -> RtuTiming {
+        RtuTiming::serial(9600, 11, Duration::from_secs(1), Duration::ZERO)
+    }
+
+    #[test]
+    fn silent_interval_from_baudrate() {
+        assert_eq!(timing().silent_interval, Duration::from_millis(5));
+        assert_eq!(RtuTiming::serial(115200, 11, Duration::from_secs(1), Duration::ZERO).silent_interval, Duration::from_millis(2));
+    }
+
+    #[test]
+    fn frame_is_received_in_parts() {
+        let mut port = line(vec![vec![1, 3, 4, 0], vec![1, 0, 2, 42, 50]]);
+        assert_eq!(receive_frame(&mut port, &timing()).unwrap(), vec![1, 3, 4, 0, 1, 0, 2, 42, 50]);
+    }
+
+    #[test]
+    fn frame_ends_with_silence() {
+        let chunks = vec![(Duration::from_millis(50), vec![1, 3, 4, 0]), (Duration::from_millis(2), vec![1, 0, 2, 42, 50])];
+        let mut port = DelayedLine { chunks: chunks.into(), timeout: Duration::ZERO };
+        assert_eq!(receive_frame(&mut port, &timing()).unwrap(), vec![1, 3, 4, 0, 1, 0, 2, 42, 50]);
+
+        // Rest of the frame comes after the silent interval, it is not awaited until the response timeout
+        let chunks = vec![(Duration::ZERO, vec![1, 3, 4, 0]), (Duration::from_millis(50), vec![1, 0, 2, 42, 50])];
+        let mut port = DelayedLine { chunks: chunks.into(), timeout: Duration::ZERO };
+        let started = Instant::now();
+        assert!(matches!(receive_frame(&mut port, &timing()), Err(ModbusError::Garbled(_))));
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn broken_frames_are_rejected() {
+        assert!(matches!(receive_frame(&mut line(vec![]), &timing()), Err(ModbusError::Timeout)));
+        // Silence in the middle of a frame
+        assert!(matches!(receive_frame(&mut line(vec![vec![1, 3, 4, 0]]), &timing()), Err(ModbusError::Garbled(_))));
+        assert!(matches!(receive_frame(&mut line(vec![vec![1, 3, 4, 0, 1, 0, 2, 42, 51]]), &timing()), Err(ModbusError::Garbled(_))));
+    }
+
+    #[test]
+    fn pdu_is_framed_with_unit_id_and_crc() {
+        let mut transport = RtuTransport::new(line(vec![vec![1, 0x83, 0x02, 0xC0, 0xF1]]), timing());
+        assert_eq!(transport.transact(1, &[3, 0, 0, 0, 2]).unwrap(), vec![0x83, 0x02]);
+        assert_eq!(transport.port.written, vec![1, 3, 0, 0, 0, 2, 0xC4, 0x0B]);
+
+        // Response of another slave
+        let mut transport = RtuTransport::new(line(vec![vec![1, 0x83, 0x02, 0xC0, 0xF1]]), timing());
+        assert!(matches!(transport.transact(2, &[3, 0, 0, 0, 2]), Err(ModbusError::Garbled(_))));
+    }
+}
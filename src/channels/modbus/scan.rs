@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+use super::engine::{check_function, parse_read_response, read_request, ModbusError, ModbusTransport};
+use super::{ModbusReadFunction, ModbusSlaveId};
+
+// Function 43 with MEI type 14, read code 1 reads basic identification objects
+const READ_DEVICE_IDENTIFICATION: u8 = 0x2B;
+const MEI_DEVICE_IDENTIFICATION: u8 = 0x0E;
+const BASIC_IDENTIFICATION: u8 = 0x01;
+// Objects that do not fit into one response are read with more requests
+const MAX_IDENTIFICATION_REQUESTS: usize = 4;
+
+/// Request that is sent to every slave id
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanProbe {
+    HoldingRegisters, // FC3 of a single register
+    InputRegisters,   // FC4 of a single register
+    Identification,   // FC43/14 Read Device Identification
+}
+
+/// How a slave answered the probe, any answer means there is a slave with the id
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeAnswer {
+    Data,
+    Exception(u8),
+    // Answer was broken, eg: two slaves with the same id or a transport that can not receive the function
+    Garbled(String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredSlave {
+    pub modbus_id: ModbusSlaveId,
+    pub answer: ProbeAnswer,
+    // Basic identification objects by object id: 0 vendor name, 1 product code, 2 revision
+    pub identification: BTreeMap<u8, String>
+}
+
+/// Probes every slave id with a single request, slaves that answered are asked for their identification.
+/// `on_probe` is called before every id is probed. Fails only when the transport can not reach the line
+pub fn scan_slaves<T: ModbusTransport + ?Sized>(
+        transport: &mut T,
+        ids: RangeInclusive<ModbusSlaveId>,
+        probe: ScanProbe,
+        address: u16,
+        mut on_probe: impl FnMut(ModbusSlaveId)) -> Result<Vec<DiscoveredSlave>, ModbusError> {
+    let mut discovered = vec![];
+    for modbus_id in ids {
+        on_probe(modbus_id);
+        // Identification is kept when it was the probe
+        let result = match probe {
+            ScanProbe::HoldingRegisters => probe_register(transport, modbus_id, ModbusReadFunction::HoldingRegisters, address).map(|_| None),
+            ScanProbe::InputRegisters => probe_register(transport, modbus_id, ModbusReadFunction::InputRegisters, address).map(|_| None),
+            ScanProbe::Identification => read_identification(transport, modbus_id).map(Some)
+        };
+        let (answer, identification) = match result {
+            Ok(identification) => (ProbeAnswer::Data, identification),
+            Err(ModbusError::Exception(code)) => (ProbeAnswer::Exception(code), None),
+            Err(ModbusError::Garbled(e)) => (ProbeAnswer::Garbled(e), None),
+            Err(ModbusError::NotConnected(e)) => return Err(ModbusError::NotConnected(e)),
+            Err(e) => {
+                log::debug!("Slave id {} did not answer: {}", modbus_id, e);
+                continue;
+            }
+        };
+        log::info!("Slave id {} answered: {:?}", modbus_id, answer);
+        let identification = identification.unwrap_or_else(|| read_identification(transport, modbus_id).unwrap_or_else(|e| {
+            log::debug!("Slave id {} did not send its identification: {}", modbus_id, e);
+            BTreeMap::new()
+        }));
+        discovered.push(DiscoveredSlave { modbus_id, answer, identification });
+    }
+    transport.end_cycle();
+    Ok(discovered)
+}
+
+fn probe_register<T: ModbusTransport + ?Sized>(transport: &mut T, modbus_id: ModbusSlaveId, function: ModbusReadFunction, address: u16) -> Result<(), ModbusError> {
+    let response = transport.transact(modbus_id, &read_request(function, address, 1))?;
+    parse_read_response(function, 1, &response).map(|_| ())
+}
+
+/// Reads basic device identification objects (vendor name, product code, revision) with function 43/14
+pub fn read_identification<T: ModbusTransport + ?Sized>(transport: &mut T, modbus_id: ModbusSlaveId) -> Result<BTreeMap<u8, String>, ModbusError> {
+    let mut objects = BTreeMap::new();
+    let mut object_id = 0;
+    for _ in 0..MAX_IDENTIFICATION_REQUESTS {
+        let request = [READ_DEVICE_IDENTIFICATION, MEI_DEVICE_IDENTIFICATION, BASIC_IDENTIFICATION, object_id];
+        let response = transport.transact(modbus_id, &request)?;
+        match parse_identification(&response, &mut objects)? {
+            Some(next_object_id) => object_id = next_object_id,
+            None => break
+        }
+    }
+    Ok(objects)
+}
+
+/// Adds objects of the response to `objects`, returns id of the next object when more follow
+fn parse_identification(response: &[u8], objects: &mut BTreeMap<u8, String>) -> Result<Option<u8>, ModbusError> {
+    check_function(READ_DEVICE_IDENTIFICATION, response)?;
+    // MEI type, read code, conformity level, more follows, next object id, number of objects
+    if response.len() < 7 || response[1] != MEI_DEVICE_IDENTIFICATION {
+        return Err(ModbusError::Garbled(format!("Device identification response is too short: {:?}", response)));
+    }
+    let mut rest = &response[7..];
+    for _ in 0..response[6] {
+        match rest {
+            [id, length, value @ ..] if value.len() >= *length as usize => {
+                let (value, next) = value.split_at(*length as usize);
+                objects.insert(*id, String::from_utf8_lossy(value).trim().to_string());
+                rest = next;
+            },
+            _ => return Err(ModbusError::Garbled(String::from("Device identification object is cut off")))
+        }
+    }
+    Ok(if response[4] == 0xFF { Some(response[5]) } else { None })
+}
+
+/// Name of basic and regular identification objects
+pub fn object_name(id: u8) -> String {
+    match id {
+        0 => String::from("vendor_name"),
+        1 => String::from("product_code"),
+        2 => String::from("revision"),
+        3 => String::from("vendor_url"),
+        4 => String::from("product_name"),
+        5 => String::from("model_name"),
+        6 => String::from("application_name"),
+        _ => format!("object_{}", id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::{scan_slaves, ProbeAnswer, ScanProbe};
+    use crate::channels::modbus::ModbusSlaveId;
+    use crate::channels::modbus::engine::{ModbusError, ModbusTransport};
+
+    // Slave 2 answers registers and identification in two parts, slave 5 answers only with exceptions
+    struct FakeBus;
+
+    impl ModbusTransport for FakeBus {
+        fn transact(&mut self, modbus_id: ModbusSlaveId, request: &[u8]) -> Result<Vec<u8>, ModbusError> {
+            match (modbus_id, request) {
+                (2, [3, 0, 0, 0, 1]) => Ok(vec![3, 2, 0, 1]),
+                (2, [0x2B, 0x0E, 1, 0]) => Ok(vec![0x2B, 0x0E, 1, 1, 0xFF, 2, 2, 0, 4, b'A', b'c', b'm', b'e', 1, 4, b'X', b'1', b'0', b'0']),
+                (2, [0x2B, 0x0E, 1, 2]) => Ok(vec![0x2B, 0x0E, 1, 1, 0, 0, 1, 2, 4, b'1', b'.', b'0', b' ']),
+                (5, [function, ..]) => Ok(vec![function | 0x80, 1]),
+                _ => Err(ModbusError::Timeout)
+            }
+        }
+    }
+
+    #[test]
+    fn slaves_are_discovered() {
+        let mut probed = vec![];
+        let discovered = scan_slaves(&mut FakeBus, 1..=6, ScanProbe::HoldingRegisters, 0, |id| probed.push(id)).unwrap();
+        assert_eq!(probed, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(discovered.len(), 2);
+        assert_eq!(discovered[0].modbus_id, 2);
+        assert_eq!(discovered[0].answer, ProbeAnswer::Data);
+        let identification: BTreeMap<u8, String> = [(0, "Acme"), (1, "X100"), (2, "1.0")].iter()
+            .map(|(id, value)| (*id, value.to_string()))
+            .collect();
+        assert_eq!(discovered[0].identification, identification);
+        assert_eq!(discovered[1].answer, ProbeAnswer::Exception(1));
+        assert!(discovered[1].identification.is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use job_scheduler::Schedule;
+use serde::{Deserialize, Serialize};
+
+use super::{ModbusRegisterGroup, ModbusRegisterMap, ModbusSlave};
+
+/// Used when neither register group nor slave sets its poll_interval
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10000);
+
+/// How often a slave or a register group is polled.
+/// Number is an interval in milliseconds, string is a cron expression
+/// in the same format as storage jobs eg: "0 */5 * * * *"
+#[derive(Serialize, Deserialize, Debug, PartialEq, Hash, Eq, Clone)]
+#[serde(untagged)]
+pub enum ModbusPollInterval {
+    Millis(u64),
+    Cron(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RegisterGroupKind {
+    Attributes,
+    Timeseries,
+}
+
+enum PollSchedule {
+    Interval(Duration),
+    Cron(Box<Schedule>),
+}
+
+enum NextPoll {
+    Instant(Instant),
+    Time(DateTime<Utc>),
+    Never,
+}
+
+struct PollEntry {
+    slave: ModbusSlave,
+    kind: RegisterGroupKind,
+    index: usize,
+    schedule: PollSchedule,
+    next: NextPoll,
+}
+
+impl PollEntry {
+    fn is_due(&self, now: Instant, now_time: DateTime<Utc>) -> bool {
+        match self.next {
+            NextPoll::Instant(next) => next <= now,
+            NextPoll::Time(next) => next <= now_time,
+            NextPoll::Never => false,
+        }
+    }
+
+    fn time_until(&self, now: Instant, now_time: DateTime<Utc>) -> Option<Duration> {
+        match self.next {
+            NextPoll::Instant(next) => Some(next.saturating_duration_since(now)),
+            NextPoll::Time(next) => Some((next - now_time).to_std().unwrap_or(Duration::ZERO)),
+            NextPoll::Never => None,
+        }
+    }
+
+    fn reschedule(&mut self, now: Instant) {
+        self.next = match &self.schedule {
+            PollSchedule::Interval(interval) => NextPoll::Instant(now + *interval),
+            PollSchedule::Cron(schedule) => match schedule.upcoming(Utc).next() {
+                Some(time) => NextPoll::Time(time),
+                None => NextPoll::Never,
+            },
+        };
+    }
+}
+
+/// Register groups of one slave that are due to be polled
+#[derive(Debug, PartialEq)]
+pub struct DuePoll {
+    pub slave: ModbusSlave,
+    pub attributes: Vec<usize>, // Indexes into ModbusRegisterMap.attributes
+    pub timeseries: Vec<usize>, // Indexes into ModbusRegisterMap.timeseries
+}
+
+impl DuePoll {
+    pub fn attribute_groups<'a>(&'a self, reg_map: &'a ModbusRegisterMap) -> impl Iterator<Item = &'a ModbusRegisterGroup> + 'a {
+        self.attributes.iter().filter_map(move |i| reg_map.attributes.get(*i))
+    }
+
+    pub fn timeseries_groups<'a>(&'a self, reg_map: &'a ModbusRegisterMap) -> impl Iterator<Item = &'a ModbusRegisterGroup> + 'a {
+        self.timeseries.iter().filter_map(move |i| reg_map.timeseries.get(*i))
+    }
+}
+
+/// Keeps track of when each register group of every slave should be polled.
+/// Interval of a register group overrides interval of its slave.
+/// Every group is due right after the scheduler is created.
+pub struct PollScheduler {
+    entries: Vec<PollEntry>,
+}
+
+impl PollScheduler {
+    pub fn new(register_maps: &HashMap<ModbusSlave, ModbusRegisterMap>) -> Self {
+        let mut entries = vec![];
+        let now = Instant::now();
+        for (slave, reg_map) in register_maps {
+            let groups = reg_map.attributes.iter().map(|g| (RegisterGroupKind::Attributes, g)).enumerate()
+                .chain(reg_map.timeseries.iter().map(|g| (RegisterGroupKind::Timeseries, g)).enumerate());
+            for (index, (kind, group)) in groups {
+                let interval = group.poll_interval.as_ref().or(slave.poll_interval.as_ref());
+                entries.push(PollEntry {
+                    slave: slave.clone(),
+                    kind,
+                    index,
+                    schedule: poll_schedule(interval, &slave.device_name),
+                    next: NextPoll::Instant(now),
+                });
+            }
+        }
+        Self { entries }
+    }
+
+    /// How long to wait until any of the register groups is due
+    pub fn time_until_next(&self) -> Duration {
+        let now = Instant::now();
+        let now_time = Utc::now();
+        self.entries.iter()
+            .filter_map(|e| e.time_until(now, now_time))
+            .min()
+            .unwrap_or(DEFAULT_POLL_INTERVAL)
+    }
+
+    /// Returns register groups that are due grouped by slave and schedules their next poll
+    pub fn take_due(&mut self) -> Vec<DuePoll> {
+        let now = Instant::now();
+        let now_time = Utc::now();
+        let mut due: Vec<DuePoll> = vec![];
+        for entry in self.entries.iter_mut().filter(|e| e.is_due(now, now_time)) {
+            entry.reschedule(now);
+            let position = match due.iter().position(|d| d.slave == entry.slave) {
+                Some(p) => p,
+                None => {
+                    due.push(DuePoll { slave: entry.slave.clone(), attributes: vec![], timeseries: vec![] });
+                    due.len() - 1
+                }
+            };
+            match entry.kind {
+                RegisterGroupKind::Attributes => due[position].attributes.push(entry.index),
+                RegisterGroupKind::Timeseries => due[position].timeseries.push(entry.index),
+            }
+        }
+        due
+    }
+}
+
+fn poll_schedule(interval: Option<&ModbusPollInterval>, device_name: &str) -> PollSchedule {
+    match interval {
+        None => PollSchedule::Interval(DEFAULT_POLL_INTERVAL),
+        // Zero interval would poll the bus in a tight loop
+        Some(ModbusPollInterval::Millis(0)) => {
+            log::error!("poll_interval 0 of device {} is not allowed, using default interval", device_name);
+            PollSchedule::Interval(DEFAULT_POLL_INTERVAL)
+        },
+        Some(ModbusPollInterval::Millis(millis)) => PollSchedule::Interval(Duration::from_millis(*millis)),
+        Some(ModbusPollInterval::Cron(expression)) => match Schedule::from_str(expression) {
+            Ok(schedule) => PollSchedule::Cron(Box::new(schedule)),
+            Err(e) => {
+                log::error!("Invalid poll_interval {:?} of device {}: {:?}, using default interval", expression, device_name, e);
+                PollSchedule::Interval(DEFAULT_POLL_INTERVAL)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{poll_schedule, ModbusPollInterval, PollSchedule, PollScheduler, DEFAULT_POLL_INTERVAL};
+    use crate::channels::modbus::{ModbusRegisterMap, ModbusSlave};
+
+    #[test]
+    fn group_interval_overrides_slave_interval() {
+        let register_map = ModbusRegisterMap::from_yaml(r#"
+            attributes:
+              - starting_address: 0
+                elements_count: 1
+                poll_interval: "0 0 0 * * *"
+                data_points: []
+            timeseries:
+              - starting_address: 0
+                elements_count: 1
+                data_points: []
+              - starting_address: 10
+                elements_count: 1
+                poll_interval: 20
+                data_points: []
+        "#).unwrap();
+        let slave = ModbusSlave {
+            device_name: String::from("Meter1"),
+            device_type: None,
+            modbus_id: 1,
+            register_map: String::from("map.yml"),
+            poll_interval: Some(ModbusPollInterval::Millis(60000)),
+            host: None,
+            port: None,
+        };
+        let mut register_maps = HashMap::new();
+        register_maps.insert(slave.clone(), register_map);
+
+        let mut scheduler = PollScheduler::new(&register_maps);
+        let due = scheduler.take_due();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attributes, vec![0]);
+        assert_eq!(due[0].timeseries, vec![0, 1]);
+        assert!(scheduler.time_until_next() <= Duration::from_millis(20));
+
+        thread::sleep(scheduler.time_until_next());
+        let due = scheduler.take_due();
+        assert_eq!(due.len(), 1);
+        assert!(due[0].attributes.is_empty());
+        assert_eq!(due[0].timeseries, vec![1]);
+    }
+
+    #[test]
+    fn zero_interval_uses_default() {
+        assert!(matches!(poll_schedule(Some(&ModbusPollInterval::Millis(0)), "Meter1"), PollSchedule::Interval(interval) if interval == DEFAULT_POLL_INTERVAL));
+        assert!(matches!(poll_schedule(Some(&ModbusPollInterval::Millis(20)), "Meter1"), PollSchedule::Interval(interval) if interval == Duration::from_millis(20)));
+    }
+}
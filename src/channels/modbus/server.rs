@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::channels::{Channel, ChannelAction, ChannelStatus, DeviceValues};
+use crate::definitions::{AggregatorAction, RpcResponse};
+use super::ascii::crc16;
+use super::blocks::read_limit;
+use super::{ModbusGroupData, ModbusReadFunction, ModbusRegisterGroup, ModbusRegisterMap, ModbusServerTcpConfig, ModbusServerUnit, ModbusSlaveId};
+
+// How often are idle connections and the listener checked for stop
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+const MBAP_HEADER: usize = 7;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+const GATEWAY_TARGET_FAILED: u8 = 0x0B;
+// Milliseconds between updates of served values when `update_interval` is not set
+const DEFAULT_UPDATE_INTERVAL: u64 = 1000;
+
+/// Coils, discrete inputs and registers a server unit answers with,
+/// addresses that are not in the table are answered with exception 2
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModbusDataTable {
+    pub coils: HashMap<u16, bool>,
+    pub discrete_inputs: HashMap<u16, bool>,
+    pub holding_registers: HashMap<u16, u16>,
+    pub input_registers: HashMap<u16, u16>,
+    // Writes are answered with exception 1
+    pub read_only: bool,
+    // Every request is answered with exception 11, eg: while the device behind a gateway unit does not answer
+    pub unavailable: bool
+}
+
+/// Units of a server by their unit id, shared with the thread that updates values
+pub type ModbusServerUnits = Arc<Mutex<HashMap<ModbusSlaveId, ModbusDataTable>>>;
+
+impl ModbusDataTable {
+    /// Adds every address of the register group, new addresses are zero
+    pub fn add_group(&mut self, group: &ModbusRegisterGroup) {
+        let addresses = (0..group.elements_count).filter_map(|i| group.starting_address.checked_add(i));
+        for address in addresses {
+            match group.function {
+                ModbusReadFunction::Coils => { self.coils.entry(address).or_insert(false); },
+                ModbusReadFunction::DiscreteInputs => { self.discrete_inputs.entry(address).or_insert(false); },
+                ModbusReadFunction::HoldingRegisters => { self.holding_registers.entry(address).or_insert(0); },
+                ModbusReadFunction::InputRegisters => { self.input_registers.entry(address).or_insert(0); }
+            }
+        }
+    }
+
+    /// Data as it would be read by the function, None when any of the addresses is missing
+    pub fn read(&self, function: ModbusReadFunction, address: u16, count: u16) -> Option<ModbusGroupData> {
+        let addresses = (0..count).map(|i| address.checked_add(i));
+        match function {
+            ModbusReadFunction::Coils => addresses.map(|a| self.coils.get(&a?).copied()).collect::<Option<_>>().map(ModbusGroupData::Bits),
+            ModbusReadFunction::DiscreteInputs => addresses.map(|a| self.discrete_inputs.get(&a?).copied()).collect::<Option<_>>().map(ModbusGroupData::Bits),
+            ModbusReadFunction::HoldingRegisters => addresses.map(|a| self.holding_registers.get(&a?).copied()).collect::<Option<_>>().map(ModbusGroupData::Registers),
+            ModbusReadFunction::InputRegisters => addresses.map(|a| self.input_registers.get(&a?).copied()).collect::<Option<_>>().map(ModbusGroupData::Registers)
+        }
+    }
+
+    /// Stores data at addresses of the function, data is not stored when any of the addresses is missing
+    pub fn write(&mut self, function: ModbusReadFunction, address: u16, data: &ModbusGroupData) -> bool {
+        fn store<T: Copy>(table: &mut HashMap<u16, T>, address: u16, values: &[T]) -> bool {
+            let fits = (0..values.len()).all(|i| matches!(address.checked_add(i as u16), Some(a) if table.contains_key(&a)));
+            if fits {
+                for (i, value) in values.iter().enumerate() {
+                    table.insert(address + i as u16, *value);
+                }
+            }
+            fits
+        }
+        match (function, data) {
+            (ModbusReadFunction::Coils, ModbusGroupData::Bits(bits)) => store(&mut self.coils, address, bits),
+            (ModbusReadFunction::DiscreteInputs, ModbusGroupData::Bits(bits)) => store(&mut self.discrete_inputs, address, bits),
+            (ModbusReadFunction::HoldingRegisters, ModbusGroupData::Registers(registers)) => store(&mut self.holding_registers, address, registers),
+            (ModbusReadFunction::InputRegisters, ModbusGroupData::Registers(registers)) => store(&mut self.input_registers, address, registers),
+            _ => false
+        }
+    }
+
+    /// Encodes values by key_name into data points of the register map groups, values without
+    /// a data point are ignored. Returns errors of data points that could not be encoded
+    pub fn encode_values(&mut self, register_map: &ModbusRegisterMap, values: &HashMap<String, Value>) -> Vec<String> {
+        let mut errors = Vec::new();
+        // Scaled values are written last, scale factors they are encoded with have to be set first
+        for scaled in [false, true] {
+            for group in register_map.attributes.iter().chain(register_map.timeseries.iter()) {
+                let mut data = match self.read(group.function, group.starting_address, group.elements_count) {
+                    Some(data) => data,
+                    None => {
+                        if !scaled {
+                            errors.push(format!("Register group at {} is not in the table", group.starting_address));
+                        }
+                        continue;
+                    }
+                };
+                let points = group.data_points.iter()
+                    .filter(|point| (point.scale.is_some() || point.offset.is_some() || point.scale_factor_register.is_some()) == scaled);
+                for point in points {
+                    if let Some(value) = values.get(&point.key_name) {
+                        if let Err(e) = point.encode_data(value, &mut data) {
+                            errors.push(format!("Could not encode {}: {}", point.key_name, e));
+                        }
+                    }
+                }
+                self.write(group.function, group.starting_address, &data);
+            }
+        }
+        errors
+    }
+}
+
+/// Answers request PDU from the table, reads with function 1 to 4 and writes with function 5, 6, 15 and 16
+pub fn handle_request(table: &mut ModbusDataTable, request: &[u8]) -> Vec<u8> {
+    let function = match request.first() {
+        Some(function) => *function,
+        None => return vec![0x80, ILLEGAL_FUNCTION]
+    };
+    match process_request(table, request) {
+        Ok(response) => response,
+        Err(code) => vec![function | 0x80, code]
+    }
+}
+
+fn process_request(table: &mut ModbusDataTable, request: &[u8]) -> Result<Vec<u8>, u8> {
+    if table.unavailable {
+        return Err(GATEWAY_TARGET_FAILED);
+    }
+    let field = |index: usize| request.get(index..index + 2).map(|word| u16::from_be_bytes([word[0], word[1]])).ok_or(ILLEGAL_DATA_VALUE);
+    let read_function = match request[0] {
+        1 => Some(ModbusReadFunction::Coils),
+        2 => Some(ModbusReadFunction::DiscreteInputs),
+        3 => Some(ModbusReadFunction::HoldingRegisters),
+        4 => Some(ModbusReadFunction::InputRegisters),
+        _ => None
+    };
+    if let Some(function) = read_function {
+        let (address, count) = (field(1)?, field(3)?);
+        if request.len() != 5 || count == 0 || count > read_limit(function) {
+            return Err(ILLEGAL_DATA_VALUE);
+        }
+        let mut response = vec![request[0]];
+        match table.read(function, address, count).ok_or(ILLEGAL_DATA_ADDRESS)? {
+            ModbusGroupData::Bits(bits) => {
+                let packed: Vec<u8> = bits.chunks(8)
+                    .map(|byte| byte.iter().enumerate().fold(0u8, |packed, (bit, value)| packed | ((*value as u8) << bit)))
+                    .collect();
+                response.push(packed.len() as u8);
+                response.extend(packed);
+            },
+            ModbusGroupData::Registers(registers) => {
+                response.push((2 * registers.len()) as u8);
+                for register in registers {
+                    response.extend_from_slice(&register.to_be_bytes());
+                }
+            }
+        }
+        return Ok(response);
+    }
+
+    if table.read_only || !matches!(request[0], 5 | 6 | 15 | 16) {
+        return Err(ILLEGAL_FUNCTION);
+    }
+    let address = field(1)?;
+    let (function, data) = match request[0] {
+        5 => match field(3)? {
+            0xFF00 if request.len() == 5 => (ModbusReadFunction::Coils, ModbusGroupData::Bits(vec![true])),
+            0x0000 if request.len() == 5 => (ModbusReadFunction::Coils, ModbusGroupData::Bits(vec![false])),
+            _ => return Err(ILLEGAL_DATA_VALUE)
+        },
+        6 if request.len() == 5 => (ModbusReadFunction::HoldingRegisters, ModbusGroupData::Registers(vec![field(3)?])),
+        15 | 16 => {
+            let count = field(3)? as usize;
+            let byte_count = *request.get(5).ok_or(ILLEGAL_DATA_VALUE)? as usize;
+            let values = request.get(6..).filter(|values| values.len() == byte_count).ok_or(ILLEGAL_DATA_VALUE)?;
+            if request[0] == 15 && count > 0 && byte_count == count.div_ceil(8) {
+                let bits = (0..count).map(|bit| values[bit / 8] & (1 << (bit % 8)) != 0).collect();
+                (ModbusReadFunction::Coils, ModbusGroupData::Bits(bits))
+            } else if request[0] == 16 && count > 0 && byte_count == 2 * count {
+                let registers = values.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect();
+                (ModbusReadFunction::HoldingRegisters, ModbusGroupData::Registers(registers))
+            } else {
+                return Err(ILLEGAL_DATA_VALUE);
+            }
+        },
+        _ => return Err(ILLEGAL_DATA_VALUE)
+    };
+    if !table.write(function, address, &data) {
+        return Err(ILLEGAL_DATA_ADDRESS);
+    }
+    // Write responses echo function code, address and value or count of the request
+    Ok(request[..5].to_vec())
+}
+
+/// Answers request of the unit, None for unknown units which do not answer at all
+fn answer(units: &ModbusServerUnits, unit_id: ModbusSlaveId, request: &[u8]) -> Option<Vec<u8>> {
+    let mut units = units.lock().unwrap_or_else(|e| e.into_inner());
+    match units.get_mut(&unit_id) {
+        Some(table) => Some(handle_request(table, request)),
+        None => {
+            log::debug!("Request for unknown unit {} is not answered", unit_id);
+            None
+        }
+    }
+}
+
+/// Accepts Modbus TCP clients until `running` is cleared, every client is served by its own thread
+pub fn serve_tcp(listener: TcpListener, units: ModbusServerUnits, running: Arc<AtomicBool>) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    while running.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, address)) => {
+                log::info!("Modbus TCP client {} connected", address);
+                let units = units.clone();
+                let running = running.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_tcp_client(stream, &units, &running) {
+                        log::warn!("Modbus TCP client {} disconnected: {}", address, e);
+                    }
+                });
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(STOP_CHECK_INTERVAL),
+            Err(e) => log::error!("Error accepting Modbus TCP client: {:?}", e)
+        }
+    }
+    Ok(())
+}
+
+/// Answers requests of one client, ends when the client disconnects or the server is stopped
+fn serve_tcp_client(mut stream: TcpStream, units: &ModbusServerUnits, running: &AtomicBool) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(STOP_CHECK_INTERVAL))?;
+    let mut header = [0u8; MBAP_HEADER];
+    loop {
+        if !read_request_part(&mut stream, &mut header, running)? {
+            return Ok(());
+        }
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        // Protocol id is 0, length counts unit id and PDU
+        if header[2..4] != [0, 0] || !(2..=254).contains(&length) {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid MBAP header: {:?}", header)));
+        }
+        let mut request = vec![0u8; length - 1];
+        if !read_request_part(&mut stream, &mut request, running)? {
+            return Ok(());
+        }
+        if let Some(response) = answer(units, header[6], &request) {
+            let mut frame = header[..4].to_vec();
+            frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend(response);
+            stream.write_all(&frame)?;
+        }
+    }
+}
+
+/// Fills the buffer, returns false when the client disconnected or the server was stopped
+fn read_request_part(stream: &mut TcpStream, buf: &mut [u8], running: &AtomicBool) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Ok(false),
+            Ok(count) => filled += count,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                if !running.load(Ordering::Relaxed) {
+                    return Ok(false);
+                }
+            },
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(true)
+}
+
+/// Answers RTU frames received on the port until it is closed, broadcasts are processed without answer.
+/// Read timeout of the port only means that the line is idle
+pub fn serve_rtu<S: Read + Write>(mut port: S, units: &ModbusServerUnits) -> io::Result<()> {
+    let mut frame: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let count = match port.read(&mut buf) {
+            Ok(count) => count,
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => continue,
+            Err(e) => return Err(e)
+        };
+        if count == 0 {
+            return Ok(());
+        }
+        frame.extend_from_slice(&buf[..count]);
+        while let Some(len) = expected_request_len(&frame) {
+            if frame.len() < len {
+                break;
+            }
+            let request: Vec<u8> = frame.drain(..len).collect();
+            let (payload, crc) = request.split_at(len - 2);
+            if crc16(payload).to_le_bytes() != crc {
+                log::warn!("Invalid CRC of request: {:?}", request);
+                frame.clear();
+                break;
+            }
+            let unit_id = payload[0];
+            if unit_id == 0 {
+                let mut units = units.lock().unwrap_or_else(|e| e.into_inner());
+                for table in units.values_mut() {
+                    handle_request(table, &payload[1..]);
+                }
+                continue;
+            }
+            if let Some(response) = answer(units, unit_id, &payload[1..]) {
+                let mut response_frame = vec![unit_id];
+                response_frame.extend(response);
+                response_frame.extend_from_slice(&crc16(&response_frame).to_le_bytes());
+                port.write_all(&response_frame)?;
+                port.flush()?;
+            }
+        }
+        // Frames of unsupported functions can not be separated, they are dropped
+        if frame.len() >= 2 && expected_request_len(&frame).is_none() {
+            log::warn!("Dropping request with unsupported function: {:?}", frame);
+            frame.clear();
+        }
+    }
+}
+
+/// Length of request frame guessed from its header
+fn expected_request_len(frame: &[u8]) -> Option<usize> {
+    match *frame.get(1)? {
+        0x01..=0x06 => Some(8),
+        0x0F | 0x10 => frame.get(6).map(|count| 9 + *count as usize),
+        _ => None
+    }
+}
+
+/// Serves latest values of devices polled by other channels over Modbus TCP, every device
+/// is a unit with the register table of its register map. Served values are read only
+pub struct ModbusTcpServerChannel {
+    config: ModbusServerTcpConfig,
+    status: ChannelStatus,
+    register_maps: HashMap<ModbusServerUnit, ModbusRegisterMap>,
+    device_values: DeviceValues,
+    aggregator_tx: mpsc::Sender<AggregatorAction>,
+    channel_rx: mpsc::Receiver<ChannelAction>
+}
+
+impl ModbusTcpServerChannel {
+    pub fn new(
+            config: ModbusServerTcpConfig,
+            register_maps: HashMap<ModbusServerUnit, ModbusRegisterMap>,
+            device_values: DeviceValues,
+            aggregator_tx: mpsc::Sender<AggregatorAction>,
+            channel_rx: mpsc::Receiver<ChannelAction>
+        ) -> Self {
+
+        Self {
+            config,
+            status: ChannelStatus::Stopped,
+            register_maps,
+            device_values,
+            aggregator_tx,
+            channel_rx
+        }
+    }
+}
+
+impl Channel for ModbusTcpServerChannel {
+    fn run(mut self) -> JoinHandle<()> {
+        self.status = ChannelStatus::Running;
+        thread::Builder::new()
+            .name(self.config.name.clone())
+            .spawn(move || {
+            let listener = match TcpListener::bind((self.config.host.as_str(), self.config.port)) {
+                Ok(listener) => listener,
+                // Channel is restarted by ChannelController
+                Err(e) => panic!("Could not listen on {}:{}: {}", self.config.host, self.config.port, e)
+            };
+            let units: ModbusServerUnits = Arc::new(Mutex::new(unit_tables(&self.register_maps)));
+            let running = Arc::new(AtomicBool::new(true));
+            let server = {
+                let (units, running) = (units.clone(), running.clone());
+                thread::spawn(move || serve_tcp(listener, units, running))
+            };
+            log::info!("Serving {} units over Modbus TCP on {}:{}", self.register_maps.len(), self.config.host, self.config.port);
+
+            let interval = Duration::from_millis(self.config.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL));
+            let mut reported_errors = HashMap::new();
+            loop {
+                update_unit_tables(&units, &self.register_maps, &self.device_values, &mut reported_errors);
+                match self.channel_rx.recv_timeout(interval) {
+                    Ok(ChannelAction::Rpc(request)) => {
+                        let response = RpcResponse { device: request.device.clone(), id: request.id, result: Err(format!("Device {} is served by a Modbus server channel, it has no RPC", request.device)) };
+                        if let Err(e) = self.aggregator_tx.send(AggregatorAction::SendRpcResponse(response)) {
+                            log::error!("Error sending RPC response to aggregation thread! Did it panic? : {:#?}", e);
+                        }
+                    },
+                    Ok(ChannelAction::Stop) => {
+                        log::info!("Stopping channel...");
+                        break;
+                    },
+                    Err(mpsc::RecvTimeoutError::Timeout) => {},
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        log::warn!("Channel was dropped by its controller, stopping...");
+                        break;
+                    }
+                }
+            }
+            running.store(false, Ordering::Relaxed);
+            match server.join() {
+                Ok(Err(e)) => log::error!("Modbus TCP server stopped: {:?}", e),
+                Err(_) => log::error!("Modbus TCP server panicked"),
+                Ok(Ok(())) => {}
+            }
+        }).unwrap()
+    }
+
+    fn status(&self) -> ChannelStatus {
+        self.status.clone()
+    }
+}
+
+/// Read only table of every unit with addresses of its register groups,
+/// units are unavailable until values of their device are collected
+fn unit_tables(register_maps: &HashMap<ModbusServerUnit, ModbusRegisterMap>) -> HashMap<ModbusSlaveId, ModbusDataTable> {
+    let mut tables = HashMap::new();
+    for (unit, register_map) in register_maps {
+        let mut table = ModbusDataTable { read_only: true, unavailable: true, ..Default::default() };
+        for group in register_map.attributes.iter().chain(register_map.timeseries.iter()) {
+            table.add_group(group);
+        }
+        tables.insert(unit.unit_id, table);
+    }
+    tables
+}
+
+/// Encodes latest values of devices into tables of their units, devices that are not
+/// connected make their units unavailable. Errors are logged when they change
+fn update_unit_tables(
+    units: &ModbusServerUnits,
+    register_maps: &HashMap<ModbusServerUnit, ModbusRegisterMap>,
+    device_values: &DeviceValues,
+    reported_errors: &mut HashMap<ModbusSlaveId, Vec<String>>
+) {
+    let device_values = device_values.lock().unwrap_or_else(|e| e.into_inner());
+    let mut units = units.lock().unwrap_or_else(|e| e.into_inner());
+    for (unit, register_map) in register_maps {
+        let table = match units.get_mut(&unit.unit_id) {
+            Some(table) => table,
+            None => continue
+        };
+        let values = match device_values.get(&unit.device_name) {
+            Some(values) => values,
+            None => {
+                table.unavailable = true;
+                continue;
+            }
+        };
+        let values: HashMap<String, Value> = values.iter()
+            .filter_map(|(key, value)| serde_json::to_value(value).ok().map(|value| (key.clone(), value)))
+            .collect();
+        let errors = table.encode_values(register_map, &values);
+        table.unavailable = false;
+        if reported_errors.get(&unit.unit_id) != Some(&errors) {
+            for e in &errors {
+                log::warn!("Unit {} serving device {}: {}", unit.unit_id, unit.device_name, e);
+            }
+            reported_errors.insert(unit.unit_id, errors);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::{handle_request, serve_tcp, unit_tables, update_unit_tables, ModbusDataTable};
+    use crate::channels::modbus::{ModbusRegisterGroup, ModbusRegisterMap, ModbusServerUnit};
+    use crate::definitions::DataPointValue;
+
+    fn table() -> ModbusDataTable {
+        let mut table = ModbusDataTable::default();
+        for group in [
+            "{ starting_address: 10, elements_count: 2, data_points: [] }",
+            "{ function: coils, starting_address: 0, elements_count: 10, data_points: [] }"
+        ] {
+            table.add_group(&serde_yaml::from_str::<ModbusRegisterGroup>(group).unwrap());
+        }
+        table
+    }
+
+    #[test]
+    fn requests_are_answered_from_table() {
+        let mut table = table();
+        assert_eq!(handle_request(&mut table, &[6, 0, 11, 0x12, 0x34]), vec![6, 0, 11, 0x12, 0x34]);
+        assert_eq!(handle_request(&mut table, &[3, 0, 10, 0, 2]), vec![3, 4, 0, 0, 0x12, 0x34]);
+        assert_eq!(handle_request(&mut table, &[15, 0, 1, 0, 9, 2, 0b0000_0101, 1]), vec![15, 0, 1, 0, 9]);
+        assert_eq!(handle_request(&mut table, &[1, 0, 0, 0, 10]), vec![1, 2, 0b0000_1010, 0b10]);
+
+        // Register 12 is not in the table, so the write does not change register 11 either
+        assert_eq!(handle_request(&mut table, &[16, 0, 11, 0, 2, 4, 0, 1, 0, 2]), vec![0x90, 2]);
+        assert_eq!(table.holding_registers[&11], 0x1234);
+        assert_eq!(handle_request(&mut table, &[4, 0, 10, 0, 1]), vec![0x84, 2]);
+        assert_eq!(handle_request(&mut table, &[3, 0, 10, 0, 0]), vec![0x83, 3]);
+        assert_eq!(handle_request(&mut table, &[0x2B, 0x0E, 1, 0]), vec![0xAB, 1]);
+    }
+
+    #[test]
+    fn tcp_requests_are_framed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let units = Arc::new(Mutex::new(HashMap::from([(3, table())])));
+        let running = Arc::new(AtomicBool::new(true));
+        let server = {
+            let running = running.clone();
+            thread::spawn(move || serve_tcp(listener, units, running))
+        };
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(&[0, 7, 0, 0, 0, 6, 3, 3, 0, 10, 0, 1]).unwrap();
+        let mut response = [0u8; 11];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(response, [0, 7, 0, 0, 0, 5, 3, 3, 2, 0, 0]);
+
+        running.store(false, Ordering::Relaxed);
+        server.join().unwrap().unwrap();
+        // Unknown unit is not answered, connection is closed once the server stopped
+        client.write_all(&[0, 8, 0, 0, 0, 6, 4, 3, 0, 10, 0, 1]).unwrap();
+        assert_eq!(client.read(&mut response).unwrap(), 0);
+    }
+
+    #[test]
+    fn units_serve_latest_device_values() {
+        let register_map = ModbusRegisterMap::from_yaml(r#"
+timeseries_points:
+  - { address: 0, data_type: float, key_name: Voltage }
+  - { address: 2, data_type: int16, key_name: Power, scale: 0.1 }
+  - { address: 3, data_type: uint16, key_name: Status }
+"#).unwrap();
+        let unit = |device_name: &str, unit_id| ModbusServerUnit { device_name: device_name.to_string(), unit_id, register_map: String::from("map.yml") };
+        let register_maps = HashMap::from([(unit("Meter1", 1), register_map.clone()), (unit("Meter2", 2), register_map)]);
+        let units = Arc::new(Mutex::new(unit_tables(&register_maps)));
+        let device_values = Arc::new(Mutex::new(HashMap::from([(String::from("Meter1"), HashMap::from([
+            (String::from("Voltage"), DataPointValue::Float(230.5)),
+            (String::from("Power"), DataPointValue::Float(-12.3)),
+            (String::from("Status"), DataPointValue::String(String::from("ok")))
+        ]))])));
+        let mut reported_errors = HashMap::new();
+        update_unit_tables(&units, &register_maps, &device_values, &mut reported_errors);
+
+        let mut units = units.lock().unwrap();
+        let meter1 = units.get_mut(&1).unwrap();
+        assert_eq!(handle_request(meter1, &[3, 0, 0, 0, 4]), vec![3, 8, 0x43, 0x66, 0x80, 0x00, 0xFF, 0x85, 0, 0]);
+        // Values are read only, Meter2 has no values yet
+        assert_eq!(handle_request(meter1, &[6, 0, 3, 0, 1]), vec![0x86, 1]);
+        assert_eq!(reported_errors[&1].len(), 1);
+        assert_eq!(handle_request(units.get_mut(&2).unwrap(), &[3, 0, 0, 0, 1]), vec![0x83, 0x0B]);
+    }
+}
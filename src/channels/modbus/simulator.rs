@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::server::ModbusDataTable;
+use super::rpc::ModbusWriteFunction;
+use super::{ModbusDataType, ModbusReadFunction, ModbusRegisterGroup, ModbusRegisterMap, ModbusSlaveId};
+
+// Milliseconds between updates of generated values when `update_interval` is not set
+pub const DEFAULT_UPDATE_INTERVAL: u64 = 1000;
+
+/// Slaves served by the `simulate` subcommand
+#[derive(Deserialize, Debug, Clone)]
+pub struct SimulatorConfig {
+    pub tcp: Option<SimulatorTcpConfig>,
+    pub rtu: Option<SimulatorRtuConfig>,
+    pub update_interval: Option<u64>, // Milliseconds, default 1000
+    pub units: Vec<SimulatedUnitConfig>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SimulatorTcpConfig {
+    pub host: String,
+    pub port: u16
+}
+
+/// RTU is served on a pseudo-terminal, `link` is a symlink to it which serial channels can use as their port
+#[derive(Deserialize, Debug, Clone)]
+pub struct SimulatorRtuConfig {
+    pub link: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SimulatedUnitConfig {
+    pub modbus_id: ModbusSlaveId,
+    pub register_map: String,
+    // Values by key_name of data points, data points without a value are zero
+    #[serde(default)]
+    pub values: HashMap<String, SimulatedValue>
+}
+
+/// Engineering value of a data point, static values are set once and may be changed by writes of clients
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SimulatedValue {
+    Generated(ValueGenerator),
+    Static(Value)
+}
+
+/// Value that changes with every update, periods are in seconds
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ValueGenerator {
+    Sine { min: f64, max: f64, period: f64 },
+    Sawtooth { min: f64, max: f64, period: f64 },
+    Square { min: f64, max: f64, period: f64 },
+    // Moves by up to `step` in either direction every update
+    RandomWalk { start: f64, step: f64, min: f64, max: f64 },
+    // Steps through the values, one per update
+    Sequence { values: Vec<Value> }
+}
+
+/// Units with tables they are served from, generated values are updated every `update_interval`
+pub struct Simulator {
+    units: Vec<SimulatedUnit>,
+    random: Random,
+    updates: u64
+}
+
+impl Simulator {
+    pub fn load(config: &SimulatorConfig) -> Result<Self, String> {
+        let units = config.units.iter().map(SimulatedUnit::load).collect::<Result<Vec<_>, String>>()?;
+        Self::new(units)
+    }
+
+    pub fn new(units: Vec<SimulatedUnit>) -> Result<Self, String> {
+        for (i, unit) in units.iter().enumerate() {
+            if units[..i].iter().any(|other| other.modbus_id == unit.modbus_id) {
+                return Err(format!("Unit {} is simulated more than once", unit.modbus_id));
+            }
+        }
+        Ok(Self { units, random: Random::from_time(), updates: 0 })
+    }
+
+    /// Tables of all units with their static values and the first generated ones
+    pub fn data_tables(&mut self) -> Result<HashMap<ModbusSlaveId, ModbusDataTable>, String> {
+        let random = &mut self.random;
+        self.units.iter_mut()
+            .map(|unit| unit.data_table(random).map(|table| (unit.modbus_id, table)))
+            .collect()
+    }
+
+    /// Writes generated values for time since start of the simulator
+    pub fn update(&mut self, tables: &mut HashMap<ModbusSlaveId, ModbusDataTable>, elapsed: Duration) -> Result<(), String> {
+        self.updates += 1;
+        for unit in self.units.iter_mut() {
+            if let Some(table) = tables.get_mut(&unit.modbus_id) {
+                unit.write_values(table, self.updates, elapsed, &mut self.random)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Register map of a unit with the values it is simulated with
+pub struct SimulatedUnit {
+    pub modbus_id: ModbusSlaveId,
+    register_map: ModbusRegisterMap,
+    values: HashMap<String, SimulatedValue>,
+    // Current value of random walks by key_name
+    walks: HashMap<String, f64>
+}
+
+impl SimulatedUnit {
+    pub fn load(config: &SimulatedUnitConfig) -> Result<Self, String> {
+        let register_map = fs::read_to_string(&config.register_map)
+            .map_err(|e| format!("Could not read {}: {}", config.register_map, e))
+            .and_then(|raw| ModbusRegisterMap::from_yaml(&raw).map_err(|e| format!("Error in register map {}: {}", config.register_map, e)))?;
+        Ok(Self::new(config.modbus_id, register_map, config.values.clone()))
+    }
+
+    pub fn new(modbus_id: ModbusSlaveId, register_map: ModbusRegisterMap, values: HashMap<String, SimulatedValue>) -> Self {
+        for key in values.keys() {
+            if !register_map.attributes.iter().chain(register_map.timeseries.iter()).any(|group| group.data_points.iter().any(|point| &point.key_name == key)) {
+                log::warn!("Simulated value {} of unit {} has no data point in its register map", key, modbus_id);
+            }
+        }
+        let walks = values.iter()
+            .filter_map(|(key, value)| match value {
+                SimulatedValue::Generated(ValueGenerator::RandomWalk { start, .. }) => Some((key.clone(), *start)),
+                _ => None
+            })
+            .collect();
+        Self { modbus_id, register_map, values, walks }
+    }
+
+    /// Table with addresses of every register group and RPC method, filled with values of the first update
+    fn data_table(&mut self, random: &mut Random) -> Result<ModbusDataTable, String> {
+        let mut table = ModbusDataTable::default();
+        for group in self.groups() {
+            table.add_group(group);
+        }
+        for method in &self.register_map.rpc {
+            let count = match method.data_type {
+                ModbusDataType::String => method.register_count.unwrap_or(1),
+                _ => method.data_type.register_count().unwrap_or(1)
+            };
+            let (function, count) = match method.function {
+                ModbusWriteFunction::SingleCoil => (ModbusReadFunction::Coils, 1),
+                ModbusWriteFunction::MultipleCoils => (ModbusReadFunction::Coils, count),
+                ModbusWriteFunction::SingleRegister | ModbusWriteFunction::MultipleRegisters => (ModbusReadFunction::HoldingRegisters, count)
+            };
+            let group = ModbusRegisterGroup { function, starting_address: method.address, elements_count: count as u16, poll_interval: None, data_points: vec![], data: None };
+            table.add_group(&group);
+        }
+        self.write_values(&mut table, 0, Duration::ZERO, random)?;
+        Ok(table)
+    }
+
+    fn groups(&self) -> impl Iterator<Item = &ModbusRegisterGroup> {
+        self.register_map.attributes.iter().chain(self.register_map.timeseries.iter())
+    }
+
+    /// Generated values of unscaled integer data points are rounded, they are not written otherwise
+    fn whole_if_integer(&self, key: &str, value: Value) -> Value {
+        let is_integer = self.groups()
+            .flat_map(|group| group.data_points.iter())
+            .find(|point| point.key_name == key)
+            .is_some_and(|point| !point.is_scaled() && !matches!(point.data_type, ModbusDataType::Float | ModbusDataType::Double));
+        match value.as_f64() {
+            Some(number) if is_integer && value.is_f64() => serde_json::json!(number.round()),
+            _ => value
+        }
+    }
+
+    /// Static values are written only by the first update, so that writes of clients are kept
+    fn write_values(&mut self, table: &mut ModbusDataTable, update: u64, elapsed: Duration, random: &mut Random) -> Result<(), String> {
+        let mut current = HashMap::new();
+        for (key, value) in &self.values {
+            match value {
+                SimulatedValue::Static(value) if update == 0 => { current.insert(key.clone(), value.clone()); },
+                SimulatedValue::Static(_) => {},
+                SimulatedValue::Generated(generator) => {
+                    let walk = self.walks.get_mut(key);
+                    let value = generator.value(update, elapsed, walk, random).map_err(|e| format!("Could not generate {} of unit {}: {}", key, self.modbus_id, e))?;
+                    current.insert(key.clone(), self.whole_if_integer(key, value));
+                }
+            }
+        }
+        match table.encode_values(&self.register_map, &current).first() {
+            Some(e) => Err(format!("Unit {}: {}", self.modbus_id, e)),
+            None => Ok(())
+        }
+    }
+}
+
+impl ValueGenerator {
+    /// Value of `update` done at `elapsed` time, random walk moves from its current value
+    fn value(&self, update: u64, elapsed: Duration, walk: Option<&mut f64>, random: &mut Random) -> Result<Value, String> {
+        let phase = |period: f64| if period > 0.0 { (elapsed.as_secs_f64() / period).fract() } else { 0.0 };
+        let value = match self {
+            ValueGenerator::Sine { min, max, period } => min + (max - min) * (1.0 - (2.0 * PI * phase(*period)).cos()) / 2.0,
+            ValueGenerator::Sawtooth { min, max, period } => min + (max - min) * phase(*period),
+            ValueGenerator::Square { min, max, period } => if phase(*period) < 0.5 { *min } else { *max },
+            ValueGenerator::RandomWalk { step, min, max, .. } => {
+                let walk = walk.ok_or("Random walk has no current value")?;
+                if update > 0 {
+                    *walk = (*walk + step * (2.0 * random.next_f64() - 1.0)).clamp(*min, *max);
+                }
+                *walk
+            },
+            ValueGenerator::Sequence { values } => {
+                return values.get(update as usize % values.len().max(1)).cloned().ok_or_else(|| String::from("Sequence has no values"));
+            }
+        };
+        Ok(serde_json::json!(value))
+    }
+}
+
+/// Xorshift generator for random walks, there is no need for good randomness
+pub struct Random {
+    state: u64
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    pub fn from_time() -> Self {
+        Self::new(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1))
+    }
+
+    /// Uniform value in 0..1
+    pub fn next_f64(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+
+/// Pseudo-terminal whose other side is opened by serial channels as their port
+#[cfg(unix)]
+pub struct PseudoTerminal {
+    pub master: serialport::TTYPort,
+    // Kept open, otherwise reads of master fail while no channel has the port open
+    _slave: serialport::TTYPort,
+    pub path: String
+}
+
+/// Opens a pseudo-terminal in raw mode, so that frames pass through unchanged
+#[cfg(unix)]
+pub fn open_pseudo_terminal() -> Result<PseudoTerminal, serialport::Error> {
+    use serialport::SerialPort;
+
+    let (master, slave) = serialport::TTYPort::pair()?;
+    let path = slave.name().ok_or_else(|| serialport::Error::new(serialport::ErrorKind::Unknown, "Pseudo-terminal has no name"))?;
+    Ok(PseudoTerminal { master, _slave: slave, path })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use super::{open_pseudo_terminal, SimulatedUnit, SimulatedValue, Simulator};
+    use crate::channels::{Channel, ChannelAction, ChannelConfig};
+    use crate::channels::modbus::{rtu, ModbusClientRtuConfig, ModbusClientTcpConfig, ModbusRegisterMap, ModbusSlave};
+    use crate::channels::modbus::engine::read_group;
+    use crate::channels::modbus::rtu::ModbusRtuChannel;
+    use crate::channels::modbus::server::{serve_rtu, serve_tcp};
+    use crate::channels::modbus::tcp::ModbusTcpChannel;
+    use crate::definitions::{AggregatorAction, DataPointValue};
+
+    fn simulator() -> Simulator {
+        let register_map = ModbusRegisterMap::from_yaml(r#"
+            timeseries_points:
+              - { address: 0, data_type: float, key_name: Voltage }
+              - { address: 2, data_type: int16, key_name: PowerSf }
+              - { address: 3, data_type: uint16, key_name: Power, scale_factor_address: 2 }
+              - { address: 4, data_type: uint16, key_name: Step }
+              - { address: 5, data_type: uint16, key_name: Level }
+        "#).unwrap();
+        let values: HashMap<String, SimulatedValue> = serde_yaml::from_str(r#"
+            Voltage: { type: sine, min: 220, max: 240, period: 4 }
+            PowerSf: -1
+            Power: 150.5
+            Step: { type: sequence, values: [1, 2, 3] }
+            Level: { type: random_walk, start: 50, step: 5, min: 0, max: 52 }
+        "#).unwrap();
+        Simulator::new(vec![SimulatedUnit::new(1, register_map, values)]).unwrap()
+    }
+
+    /// Register map of the simulated unit read by device Meter1
+    fn register_maps(simulator: &Simulator) -> HashMap<ModbusSlave, ModbusRegisterMap> {
+        let slave: ModbusSlave = serde_yaml::from_str("{ device_name: Meter1, modbus_id: 1, register_map: map.yml }").unwrap();
+        HashMap::from([(slave, simulator.units[0].register_map.clone())])
+    }
+
+    /// Actions sent by the channel until its first poll was sent, the channel is stopped afterwards
+    fn first_poll(aggregator_rx: mpsc::Receiver<AggregatorAction>, channel_tx: mpsc::Sender<ChannelAction>, handle: JoinHandle<()>) -> Vec<AggregatorAction> {
+        let mut sent = vec![];
+        while !matches!(sent.last(), Some(AggregatorAction::SendBoth(..))) {
+            sent.push(aggregator_rx.recv_timeout(Duration::from_secs(5)).expect("Channel sent no data"));
+        }
+        channel_tx.send(ChannelAction::Stop).unwrap();
+        handle.join().unwrap();
+        sent
+    }
+
+    fn assert_simulated_values(sent: &[AggregatorAction]) {
+        assert!(matches!(&sent[0], AggregatorAction::DeviceConnected(device, _) if device == "Meter1"));
+        match &sent[1] {
+            AggregatorAction::SendBoth(_, timeseries) => {
+                assert_eq!(timeseries.0, "Meter1");
+                let values = &timeseries.1[0].values;
+                assert_eq!(values.get("Voltage"), Some(&DataPointValue::Float(220.0)));
+                assert_eq!(values.get("PowerSf"), Some(&DataPointValue::Int(-1)));
+                assert_eq!(values.get("Power"), Some(&DataPointValue::Float(150.5)));
+                assert_eq!(values.get("Step"), Some(&DataPointValue::UInt(1)));
+            },
+            _ => panic!("Expected data of the simulated unit")
+        }
+    }
+
+    #[test]
+    fn values_are_generated() {
+        let mut simulator = simulator();
+        let mut tables = simulator.data_tables().unwrap();
+        let registers = |tables: &HashMap<u8, super::ModbusDataTable>| (0..6).map(|a| tables[&1].holding_registers[&a]).collect::<Vec<u16>>();
+        assert_eq!(registers(&tables), vec![0x435C, 0, 0xFFFF, 1505, 1, 50]);
+
+        simulator.update(&mut tables, Duration::from_secs(1)).unwrap();
+        let updated = registers(&tables);
+        // Sine is halfway at a quarter of its period, static values are kept
+        assert_eq!(updated[..5], [0x4366, 0, 0xFFFF, 1505, 2]);
+        assert!((45..=52).contains(&updated[5]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rtu_channel_reads_simulated_unit() {
+        let mut simulator = simulator();
+        let units = Arc::new(Mutex::new(simulator.data_tables().unwrap()));
+        let terminal = open_pseudo_terminal().unwrap();
+        let config = ModbusClientRtuConfig::serialize(format!("
+            name: Simulated line
+            port: {}
+            baudrate: 19200
+            parity: 'N'
+            data_bits: 8
+            stop_bits: 1
+            slaves: []
+        ", terminal.path)).unwrap();
+        thread::spawn(move || serve_rtu(terminal.master, &units));
+
+        let mut transport = rtu::open_transport(&config).unwrap();
+        let register_map = simulator.units[0].register_map.clone();
+        let group = &register_map.timeseries[0];
+        let data = read_group(transport.as_mut(), 1, group).unwrap();
+        let values: Vec<DataPointValue> = group.data_points.iter().map(|point| point.parse_data(&data).unwrap().value).collect();
+        assert_eq!(values[..4], [DataPointValue::Float(220.0), DataPointValue::Int(-1), DataPointValue::Float(150.5), DataPointValue::UInt(1)]);
+        // Unit 2 is not simulated
+        assert!(read_group(transport.as_mut(), 2, group).is_err());
+    }
+
+    #[test]
+    fn tcp_channel_polls_simulator() {
+        let mut simulator = simulator();
+        let units = Arc::new(Mutex::new(simulator.data_tables().unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_running = running.clone();
+        let server = thread::spawn(move || serve_tcp(listener, units, server_running));
+        let config = ModbusClientTcpConfig::serialize(format!("
+            name: Simulated server
+            host: 127.0.0.1
+            port: {}
+            slaves: []
+        ", port)).unwrap();
+
+        let (aggregator_tx, aggregator_rx) = mpsc::channel();
+        let (channel_tx, channel_rx) = mpsc::channel();
+        let handle = ModbusTcpChannel::new(config, register_maps(&simulator), aggregator_tx, channel_rx).run();
+        assert_simulated_values(&first_poll(aggregator_rx, channel_tx, handle));
+        running.store(false, Ordering::Relaxed);
+        server.join().unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rtu_channel_polls_simulator() {
+        let mut simulator = simulator();
+        let units = Arc::new(Mutex::new(simulator.data_tables().unwrap()));
+        let terminal = open_pseudo_terminal().unwrap();
+        let config = ModbusClientRtuConfig::serialize(format!("
+            name: Simulated line
+            port: {}
+            baudrate: 19200
+            parity: 'N'
+            data_bits: 8
+            stop_bits: 1
+            slaves: []
+        ", terminal.path)).unwrap();
+        thread::spawn(move || serve_rtu(terminal.master, &units));
+
+        let (aggregator_tx, aggregator_rx) = mpsc::channel();
+        let (channel_tx, channel_rx) = mpsc::channel();
+        let handle = ModbusRtuChannel::new(config, register_maps(&simulator), aggregator_tx, channel_rx).run();
+        assert_simulated_values(&first_poll(aggregator_rx, channel_tx, handle));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, UdpSocket, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::channels::{Channel, ChannelAction, ChannelStatus};
+use crate::definitions::AggregatorAction;
+
+use super::{ModbusClientRtuSocketConfig, ModbusRegisterMap, ModbusSlave};
+use super::engine::poll_slaves;
+use super::rtu::{RtuStream, RtuTiming, RtuTransport, DEFAULT_RESPONSE_TIMEOUT};
+
+// Failed connection is not retried sooner, so that every request does not wait for the connect timeout
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+// Largest RTU frame is 256 bytes
+const MAX_DATAGRAM: usize = 512;
+
+/// Socket carrying raw RTU frames (with CRC) instead of Modbus TCP frames with MBAP header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtuSocketProtocol {
+    Tcp,
+    Udp
+}
+
+pub struct ModbusRtuSocketChannel {
+    protocol: RtuSocketProtocol,
+    config: ModbusClientRtuSocketConfig,
+    status: ChannelStatus,
+    register_maps: HashMap<ModbusSlave, ModbusRegisterMap>,
+    aggregator_tx: mpsc::Sender<AggregatorAction>,
+    channel_rx: mpsc::Receiver<ChannelAction>
+}
+
+impl ModbusRtuSocketChannel {
+    pub fn new(
+        protocol: RtuSocketProtocol,
+        config: ModbusClientRtuSocketConfig,
+        register_maps: HashMap<ModbusSlave, ModbusRegisterMap>,
+        aggregator_tx: mpsc::Sender<AggregatorAction>,
+        channel_rx: mpsc::Receiver<ChannelAction>
+    ) -> Self {
+        Self {
+            protocol,
+            config,
+            status: ChannelStatus::Stopped,
+            register_maps,
+            aggregator_tx,
+            channel_rx
+        }
+    }
+}
+
+impl Channel for ModbusRtuSocketChannel {
+    fn run(mut self) -> JoinHandle<()> {
+        self.status = ChannelStatus::Running;
+        thread::Builder::new()
+            .name(self.config.name.clone())
+            .spawn(move || {
+                log::info!("Polling RTU slaves over {:?} socket: {}:{}", self.protocol, self.config.host, self.config.port);
+                poll_slaves(&mut open_transport(self.protocol, &self.config), &self.register_maps, &self.aggregator_tx, &self.channel_rx);
+            }).unwrap()
+    }
+
+    fn status(&self) -> ChannelStatus {
+        self.status.clone()
+    }
+}
+
+/// Socket to the converter, it is connected on the first request
+pub fn open_transport(protocol: RtuSocketProtocol, config: &ModbusClientRtuSocketConfig) -> RtuTransport<RtuSocket> {
+    let timeout = Duration::from_millis(config.response_timeout.unwrap_or(DEFAULT_RESPONSE_TIMEOUT));
+    // Frame may be split by the network, so it does not end with silence on the serial line
+    let timing = RtuTiming {
+        response_timeout: timeout,
+        silent_interval: timeout,
+        request_delay: Duration::from_millis(config.request_delay.unwrap_or(0))
+    };
+    RtuTransport::new(RtuSocket::new(protocol, format!("{}:{}", config.host, config.port), timeout), timing)
+}
+
+enum Connected {
+    Tcp(TcpStream),
+    Udp(UdpSocket)
+}
+
+/// Socket is connected lazily before a request and dropped after an I/O error,
+/// so that a converter which was restarted is reconnected on the next request
+pub struct RtuSocket {
+    protocol: RtuSocketProtocol,
+    address: String,
+    timeout: Duration,
+    connected: Option<Connected>,
+    next_attempt: Instant,
+    // Received datagrams, RTU frames are read in parts
+    buffer: VecDeque<u8>
+}
+
+impl RtuSocket {
+    pub fn new(protocol: RtuSocketProtocol, address: String, timeout: Duration) -> Self {
+        Self {
+            protocol,
+            address,
+            timeout,
+            connected: None,
+            next_attempt: Instant::now(),
+            buffer: VecDeque::new()
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<&mut Connected> {
+        if self.connected.is_none() {
+            if Instant::now() < self.next_attempt {
+                return Err(io::Error::new(ErrorKind::NotConnected, format!("Not connected to {}", self.address)));
+            }
+            match self.open() {
+                Ok(connected) => {
+                    log::info!("Connected to {}", self.address);
+                    self.connected = Some(connected);
+                },
+                Err(e) => {
+                    log::error!("Could not connect to {}: {:?}", self.address, e);
+                    self.next_attempt = Instant::now() + RECONNECT_INTERVAL;
+                    // Reported like the back-off, so that polling of the other slaves is skipped
+                    return Err(io::Error::new(ErrorKind::NotConnected, format!("Could not connect to {}: {}", self.address, e)));
+                }
+            }
+        }
+        Ok(self.connected.as_mut().unwrap())
+    }
+
+    fn open(&self) -> io::Result<Connected> {
+        let address = self.address.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Could not resolve {}", self.address)))?;
+        match self.protocol {
+            RtuSocketProtocol::Tcp => {
+                let stream = TcpStream::connect_timeout(&address, self.timeout)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                stream.set_nodelay(true)?;
+                Ok(Connected::Tcp(stream))
+            },
+            RtuSocketProtocol::Udp => {
+                let local = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(address)?;
+                socket.set_read_timeout(Some(self.timeout))?;
+                Ok(Connected::Udp(socket))
+            }
+        }
+    }
+
+    /// Drops the connection after an error other than timeout
+    fn check<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &result {
+            if !matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) {
+                log::warn!("Connection to {} lost: {:?}", self.address, e);
+                self.connected = None;
+            }
+        }
+        result
+    }
+
+    /// Discards everything that was already received
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        let connected = match &mut self.connected {
+            Some(connected) => connected,
+            None => return Ok(())
+        };
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let result = match connected {
+            Connected::Tcp(stream) => {
+                stream.set_nonblocking(true)?;
+                let result = loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) => break Err(io::Error::from(ErrorKind::ConnectionReset)),
+                        Ok(_) => continue,
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                        Err(e) => break Err(e)
+                    }
+                };
+                stream.set_nonblocking(false)?;
+                result
+            },
+            Connected::Udp(socket) => {
+                socket.set_nonblocking(true)?;
+                let result = loop {
+                    match socket.recv(&mut buf) {
+                        Ok(_) => continue,
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                        Err(e) => break Err(e)
+                    }
+                };
+                socket.set_nonblocking(false)?;
+                result
+            }
+        };
+        self.check(result)
+    }
+}
+
+impl Read for RtuSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() {
+            let mut datagram = [0u8; MAX_DATAGRAM];
+            let result = match self.connect()? {
+                Connected::Tcp(stream) => match stream.read(&mut datagram) {
+                    Ok(0) => Err(io::Error::from(ErrorKind::ConnectionReset)),
+                    result => result
+                },
+                Connected::Udp(socket) => socket.recv(&mut datagram)
+            };
+            // Socket read timeout is reported as WouldBlock on unix, serial port reports TimedOut
+            let result = result.map_err(|e| match e.kind() {
+                ErrorKind::WouldBlock => io::Error::from(ErrorKind::TimedOut),
+                _ => e
+            });
+            let received = self.check(result)?;
+            self.buffer.extend(&datagram[..received]);
+        }
+        let count = buf.len().min(self.buffer.len());
+        for (byte, received) in buf.iter_mut().zip(self.buffer.drain(..count)) {
+            *byte = received;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for RtuSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = match self.connect()? {
+            Connected::Tcp(stream) => stream.write(buf),
+            // One datagram per frame
+            Connected::Udp(socket) => socket.send(buf)
+        };
+        self.check(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = match &mut self.connected {
+            Some(Connected::Tcp(stream)) => stream.flush(),
+            _ => Ok(())
+        };
+        self.check(result)
+    }
+}
+
+impl RtuStream for RtuSocket {
+    fn clear(&mut self, buffer_to_clear: serialport::ClearBuffer) -> io::Result<()> {
+        match buffer_to_clear {
+            // Written data is sent right away
+            serialport::ClearBuffer::Output => Ok(()),
+            serialport::ClearBuffer::Input | serialport::ClearBuffer::All => self.discard_input()
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        let result = match &self.connected {
+            Some(Connected::Tcp(stream)) => stream.set_read_timeout(Some(timeout)),
+            Some(Connected::Udp(socket)) => socket.set_read_timeout(Some(timeout)),
+            None => Ok(())
+        };
+        self.check(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::Duration;
+    use crate::channels::modbus::engine::{ModbusError, ModbusTransport};
+    use crate::channels::modbus::rtu::{RtuTiming, RtuTransport};
+    use super::{RtuSocket, RtuSocketProtocol};
+
+    #[test]
+    fn udp_frame_is_read_in_parts() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut socket = RtuSocket::new(RtuSocketProtocol::Udp, server.local_addr().unwrap().to_string(), Duration::from_secs(1));
+
+        socket.write_all(&[1, 3, 0, 0, 0, 2, 196, 11]).unwrap();
+        let mut request = [0u8; 16];
+        let (len, client) = server.recv_from(&mut request).unwrap();
+        assert_eq!(&request[..len], &[1, 3, 0, 0, 0, 2, 196, 11]);
+
+        server.send_to(&[1, 3, 4, 0, 1, 0, 2, 42, 50], client).unwrap();
+        let mut first = [0u8; 5];
+        let mut rest = [0u8; 4];
+        socket.read_exact(&mut first).unwrap();
+        socket.read_exact(&mut rest).unwrap();
+        assert_eq!(first, [1, 3, 4, 0, 1]);
+        assert_eq!(rest, [0, 2, 42, 50]);
+    }
+
+    #[test]
+    fn unreachable_converter_is_not_connected() {
+        // Port of a closed listener refuses connections
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let timeout = Duration::from_millis(200);
+        let timing = RtuTiming { response_timeout: timeout, silent_interval: timeout, request_delay: Duration::ZERO };
+        let mut transport = RtuTransport::new(RtuSocket::new(RtuSocketProtocol::Tcp, address, timeout), timing);
+
+        // Failed connect and then the back-off before the next attempt
+        assert!(matches!(transport.transact(1, &[3, 0, 0, 0, 2]), Err(ModbusError::NotConnected(_))));
+        assert!(matches!(transport.transact(2, &[3, 0, 0, 0, 2]), Err(ModbusError::NotConnected(_))));
+    }
+}
@@ -1,38 +1,52 @@
-use crate::channels::{Channel, ChannelStatus, DataPoint};
-use crate::definitions::{AggregatorAction, OneTelemetry};
+use crate::channels::{Channel, ChannelAction, ChannelStatus};
+use crate::definitions::{AggregatorAction, RpcResponse};
 
-use super::{ModbusClientTcpConfig, ModbusRegisterMap, ModbusSlave};
+use super::{ModbusClientTcpConfig, ModbusTcpConnectionMode, ModbusRegisterMap, ModbusSlave, ModbusSlaveId};
+use super::engine::{poll_slaves, ModbusError, ModbusTransport};
 use std::collections::HashMap;
-use std::net::{SocketAddr, IpAddr};
+use std::fmt;
 use std::{sync::mpsc, thread::JoinHandle};
 use std::thread;
-use std::time::Duration;
-use bytebuffer::ByteBuffer;
+use std::time::{Duration, Instant};
+use std::io;
 // use tokio_modbus::prelude::*;
-use libmodbus_rs::{Modbus, ModbusClient, ModbusTCP, Timeout, ErrorRecoveryMode};
+use libmodbus_rs::{Error as LibmodbusError, Modbus, ModbusClient, ModbusTCP, Timeout, ErrorRecoveryMode};
 // use tokio;
 
-use crate::definitions::{AttributeMessage, TimeseriesMessage};
+// Timeouts in milliseconds when they are not configured
+const DEFAULT_BYTE_TIMEOUT: u64 = 50;
+const DEFAULT_RESPONSE_TIMEOUT: u64 = 1050;
+// Backoff between failed connection attempts doubles up to the max
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+// Transaction id, protocol id, length and unit id
+const MBAP_HEADER: usize = 7;
+const MAX_TCP_ADU: usize = 260;
+// How often does the channel thread check for panicked endpoint workers
+const WORKER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct ModbusTcpChannel {
     config: ModbusClientTcpConfig,
     status: ChannelStatus,
     register_maps: HashMap<ModbusSlave, ModbusRegisterMap>,
-    aggregator_tx: mpsc::Sender<AggregatorAction>
+    aggregator_tx: mpsc::Sender<AggregatorAction>,
+    channel_rx: mpsc::Receiver<ChannelAction>
 }
 
 impl ModbusTcpChannel {
     pub fn new(
             config: ModbusClientTcpConfig,
             register_maps: HashMap<ModbusSlave, ModbusRegisterMap>,
-            aggregator_tx: mpsc::Sender<AggregatorAction>
+            aggregator_tx: mpsc::Sender<AggregatorAction>,
+            channel_rx: mpsc::Receiver<ChannelAction>
         ) -> Self {
 
         Self {
             config,
             status: ChannelStatus::Stopped,
             register_maps,
-            aggregator_tx
+            aggregator_tx,
+            channel_rx
         }
     }
 }
@@ -41,156 +55,372 @@ impl Channel for ModbusTcpChannel {
     fn run(mut self) -> JoinHandle<()> {
         self.status = ChannelStatus::Running;
         let builder = thread::Builder::new()
-            .name(self.config.name)
+            .name(self.config.name.clone())
             .spawn(move || {
 
-            // Make connection to ModbusTCP server
-            // let socket_full_string = format!("{}:{}", self.config.host ,self.config.port);
-            // let socket_addr = socket_full_string.parse::<SocketAddr>().unwrap();
-
-            let addr: IpAddr = self.config.host.parse().unwrap();
-            let socket = SocketAddr::from((addr, self.config.port));
-            log::info!("Preparing new modbus tcp connection IP: {} Port: {}", socket.ip(), socket.port());
-
-
             let aggregator = self.aggregator_tx.clone();
-            // let mut socket_addr: SocketAddr = SocketAddr::new(Ipv4Addr::from_str("127.0.0.1").unwrap().into(), 502);
-            // if self.config.host.contains(":") {
-            //     // let new_ip = std::net::Ipv6Addr::from_str(&self.config.host);
-            //     // match new_ip {
-            //     //     Ok(ip) => ip,
-            //     //     Err(e) => log::error!("Error parsing IPV6 host and port: {:?}",e)
-            //     // }
-            //     socket_addr.set_ip(std::net::Ipv6Addr::from_str(&self.config.host).unwrap().into());
-            // }
-            // else {
-            //     socket_addr.set_ip(std::net::Ipv4Addr::from_str(&self.config.host).unwrap().into());
-            // }
-            // socket_addr.set_port(self.config.port);
-            let mut modbus = Modbus::new_tcp(&socket.ip().to_string(), socket.port() as i32).unwrap();
-            modbus.set_byte_timeout(Timeout::new(0,50000)).unwrap();
-            modbus.set_response_timeout(Timeout::new(1,50000)).unwrap();
-            modbus.set_error_recovery(Some(&[ErrorRecoveryMode::Protocol, ErrorRecoveryMode::Link])).unwrap();
-            match modbus.connect() {
-                Ok(_) => log::info!("Connected to modbusTCP "),
-                Err(e) => log::error!("Error connecting to modbusTCP: {:?}", modbus.ctx)    
-            };
-            // let socket_addr = socket_addr.into();
-            loop { 
-                // log::info!("Sleeping for 20s");
-                thread::sleep(Duration::from_millis(10000));
-                
-                // Error Handle
-                for (slave, reg_map) in &mut self.register_maps {
-                    // Set correct ModbusID to call on
-                    match modbus.set_slave(slave.modbus_id) {
-                        Ok(_) => log::trace!("Switched to slave with id: {}", slave.modbus_id),
-                        Err(e) => log::error!("Error switching to modbus slave id: {} error: {:?}", slave.modbus_id, e)
-                    };
-
-                    let mut attributes_message: AttributeMessage = (slave.device_name.clone(), HashMap::new());
-                    let mut timeseries_message: TimeseriesMessage = (slave.device_name.clone(), vec![]);
-                    // Read Attributes 
-                    for reg_group in &reg_map.attributes {
-                        
-                        let mut data_point_vec: Vec<DataPoint> = vec![];
-                        let mut read_buffer =  vec![0u16; 200];
-
-                        // Call group 
-                        log::trace!("Reading starting address: {:}, and register count: {:}", reg_group.starting_address, reg_group.elements_count);
-                        match modbus.flush() {
-                            Ok(_) => log::debug!("Flushed untransmited data..."),
-                            Err(e) => log::error!("Error flushing untransmited data: {:?}", e)
-                        }
-                        match modbus.read_registers(reg_group.starting_address,reg_group.elements_count,&mut read_buffer) {
-                            Ok(c) => {
-                                log::debug!("Success reading register group: {:?} return code: {}",reg_group, c);
+            // Every endpoint is polled by its own worker with its own connection,
+            // so that unreachable endpoint does not stall the others
+            let mut endpoint_maps: HashMap<ModbusTcpEndpoint, HashMap<ModbusSlave, ModbusRegisterMap>> = HashMap::new();
+            for (slave, register_map) in self.register_maps {
+                endpoint_maps.entry(ModbusTcpEndpoint::of(&self.config, &slave))
+                    .or_default()
+                    .insert(slave, register_map);
+            }
+            let (exited_tx, exited_rx) = mpsc::channel::<(ModbusTcpEndpoint, WorkerExit)>();
+            let mut device_endpoints: HashMap<String, ModbusTcpEndpoint> = HashMap::new();
+            let mut workers: HashMap<ModbusTcpEndpoint, (mpsc::Sender<ChannelAction>, JoinHandle<()>)> = HashMap::new();
+            for (endpoint, register_maps) in &endpoint_maps {
+                for slave in register_maps.keys() {
+                    device_endpoints.insert(slave.device_name.clone(), endpoint.clone());
+                }
+                workers.insert(endpoint.clone(), spawn_worker(&self.config, endpoint, register_maps, &aggregator, &exited_tx));
+            }
+            // Failed workers are restarted on their own with backoff, other endpoints keep being polled
+            let mut failures: HashMap<ModbusTcpEndpoint, u32> = HashMap::new();
+            let mut restarts: HashMap<ModbusTcpEndpoint, Instant> = HashMap::new();
+
+            let mut panicked = None;
+            loop {
+                match self.channel_rx.recv_timeout(WORKER_CHECK_INTERVAL) {
+                    Ok(ChannelAction::Rpc(request)) => {
+                        let unavailable = match device_endpoints.get(&request.device) {
+                            Some(endpoint) => match workers.get(endpoint) {
+                                Some((worker_tx, _)) => {
+                                    if let Err(e) = worker_tx.send(ChannelAction::Rpc(request)) {
+                                        log::error!("Error forwarding RPC request to endpoint worker: {:?}", e);
+                                    }
+                                    continue;
+                                },
+                                None => format!("Endpoint {} of device {} is not available", endpoint, request.device)
                             },
-                            Err(e) => {
-                                log::error!("Error reading register group: {:?} return code: {}", reg_group, e);
-                                continue
-                            }
+                            None => format!("Unknown device: {}", request.device)
                         };
-                        let mut buff = ByteBuffer::new();
-                        for word in read_buffer.clone() {
-                            buff.write_u16(word);
+                        let response = RpcResponse { device: request.device.clone(), id: request.id, result: Err(unavailable) };
+                        if let Err(e) = aggregator.send(AggregatorAction::SendRpcResponse(response)) {
+                            log::error!("Error sending RPC response to aggregation thread! Did it panic? : {:#?}", e);
                         }
+                    },
+                    Ok(ChannelAction::Stop) => {
+                        log::info!("Stopping channel...");
+                        break;
+                    },
+                    Err(mpsc::RecvTimeoutError::Timeout) => {},
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        log::warn!("Channel was dropped by its controller, stopping...");
+                        break;
+                    }
+                }
+                while let Ok((endpoint, exit)) = exited_rx.try_recv() {
+                    if let Some((_, handle)) = workers.remove(&endpoint) {
+                        handle.join().ok();
+                    }
+                    match exit {
+                        WorkerExit::Failed(e) => {
+                            let failed = failures.entry(endpoint.clone()).or_default();
+                            let backoff = reconnect_backoff(*failed);
+                            *failed += 1;
+                            log::error!("Worker of endpoint {} failed: {}, restarting in {:?}", endpoint, e, backoff);
+                            restarts.insert(endpoint, Instant::now() + backoff);
+                        },
+                        WorkerExit::Panicked => panicked = Some(endpoint)
+                    }
+                }
+                if panicked.is_some() {
+                    break;
+                }
+                let now = Instant::now();
+                let due: Vec<ModbusTcpEndpoint> = restarts.iter()
+                    .filter(|(_, restart_at)| **restart_at <= now)
+                    .map(|(endpoint, _)| endpoint.clone())
+                    .collect();
+                for endpoint in due {
+                    restarts.remove(&endpoint);
+                    log::info!("Restarting worker of endpoint {}", endpoint);
+                    workers.insert(endpoint.clone(), spawn_worker(&self.config, &endpoint, &endpoint_maps[&endpoint], &aggregator, &exited_tx));
+                }
+            }
+            for (endpoint, (worker_tx, handle)) in workers {
+                worker_tx.send(ChannelAction::Stop).ok();
+                if handle.join().is_err() {
+                    log::error!("Worker of endpoint {} panicked", endpoint);
+                }
+            }
+            // Whole channel is restarted by ChannelController
+            if let Some(endpoint) = panicked {
+                panic!("Worker of endpoint {} panicked", endpoint);
+            }
+        }).unwrap();
+    
 
-                        for data_point in &reg_group.data_points {
-                            if !((data_point.data_offset + 4) <= buff.len())  {
-                                log::warn!("continuing... , would crash otherwise");
-                                continue;
-                            }
-                            match data_point.parse(read_buffer.clone()) {
-                                Some(point) => {
-                                    data_point_vec.push(point.clone());
-                                    // parse into message
-                                    attributes_message.1.insert(point.key, point.value);
-                                },
-                                None => continue
-                            }
-                        }
+        builder
+    }
 
+    fn status(&self) ->  crate::channels::ChannelStatus {
+        self.status.clone()
+    }
 
-                        log::info!("Read and parsed register group with data {} points", data_point_vec.len());
-                        log::debug!("Datapoints in reg group: {:?}", data_point_vec);
-                        log::debug!("Attribute message: {:?}", attributes_message)
+}
 
-                    }
-                    // Read Timeseries
-                    for reg_group in &reg_map.timeseries {
-                        
-                        let mut data_point_vec: Vec<DataPoint> = vec![];
-                        let mut read_buffer =  vec![0u16; 200];
-
-                        // Call group 
-                        match modbus.read_registers(reg_group.starting_address,reg_group.elements_count,&mut read_buffer) {
-                            Ok(c) => {
-                                log::debug!("Success reading register group: {:?} return code: {}",reg_group, c);
-                            },
-                            Err(e) => {
-                                log::error!("Error reading register group: {:?} return code: {}", reg_group, e);
-                                continue;
-                            }
-                        };
-                        match modbus.flush() {
-                            Ok(_) => log::debug!("Flushed untransmited data..."),
-                            Err(e) => log::error!("Error flushing untransmited data: {:?}", e)
-                        }
-                        
+/// Polls slaves of a single endpoint with its own connection,
+/// RPC requests for these slaves are forwarded to channel_rx by the channel thread
+fn poll_endpoint(
+        config: ModbusClientTcpConfig,
+        endpoint: ModbusTcpEndpoint,
+        register_maps: HashMap<ModbusSlave, ModbusRegisterMap>,
+        aggregator: mpsc::Sender<AggregatorAction>,
+        channel_rx: mpsc::Receiver<ChannelAction>) -> Result<(), String> {
+    log::info!("Preparing new modbus tcp connection to: {}", endpoint);
+    let mut connection = TcpConnection::new(&endpoint.host, endpoint.port, &config)
+        .map_err(|e| format!("Could not create modbus tcp context: {}", e))?;
+    poll_slaves(&mut connection, &register_maps, &aggregator, &channel_rx);
+    connection.disconnect();
+    Ok(())
+}
 
+/// Starts worker polling the endpoint, it reports to `exited_tx` when it fails or panics
+fn spawn_worker(
+        config: &ModbusClientTcpConfig,
+        endpoint: &ModbusTcpEndpoint,
+        register_maps: &HashMap<ModbusSlave, ModbusRegisterMap>,
+        aggregator: &mpsc::Sender<AggregatorAction>,
+        exited_tx: &mpsc::Sender<(ModbusTcpEndpoint, WorkerExit)>) -> (mpsc::Sender<ChannelAction>, JoinHandle<()>) {
+    let (worker_tx, worker_rx) = mpsc::channel::<ChannelAction>();
+    let notifier = PanicNotifier(endpoint.clone(), exited_tx.clone());
+    let (config, endpoint, register_maps, aggregator) = (config.clone(), endpoint.clone(), register_maps.clone(), aggregator.clone());
+    let handle = thread::Builder::new()
+        .name(format!("{} {}", config.name, endpoint))
+        .spawn(move || {
+            if let Err(e) = poll_endpoint(config, endpoint.clone(), register_maps, aggregator, worker_rx) {
+                notifier.1.send((endpoint, WorkerExit::Failed(e))).ok();
+            }
+        }).unwrap();
+    (worker_tx, handle)
+}
 
+/// Connection to the endpoint of a slave, or of the channel without slave, it is opened on the first request
+pub fn open_transport(config: &ModbusClientTcpConfig, slave: Option<&ModbusSlave>) -> Result<Box<dyn ModbusTransport>, String> {
+    let endpoint = match slave {
+        Some(slave) => ModbusTcpEndpoint::of(config, slave),
+        None => ModbusTcpEndpoint { host: config.host.clone(), port: config.port }
+    };
+    Ok(Box::new(TcpConnection::new(&endpoint.host, endpoint.port, config)?))
+}
 
-                        for data_point in &reg_group.data_points {
-                            match data_point.parse(read_buffer.clone()) {
-                                Some(point)  => {
-                                    data_point_vec.push(point.clone());
-                                },
-                                None => continue
-                            }
-                        }
+/// Host and port of a modbus TCP server, slaves with the same endpoint share a connection
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ModbusTcpEndpoint {
+    host: String,
+    port: u16
+}
+
+impl ModbusTcpEndpoint {
+    fn of(config: &ModbusClientTcpConfig, slave: &ModbusSlave) -> Self {
+        Self {
+            host: slave.host.clone().unwrap_or_else(|| config.host.clone()),
+            port: slave.port.unwrap_or(config.port)
+        }
+    }
+}
 
+impl fmt::Display for ModbusTcpEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
 
-                        log::info!("Read and parsed register group with data {} points", data_point_vec.len());
-                        log::debug!("Datapoints in reg group: {:?}", data_point_vec);
-                        timeseries_message.1.push(OneTelemetry::from(data_point_vec))
-                    }
-                    // Disconnect
-                    modbus.close();
-                    match aggregator.send(AggregatorAction::SendBoth(attributes_message, timeseries_message)) {
-                        Err(e) => log::error!("Error sending data to aggregation thread! Did it panic? : {:#?}", e),
-                        _ => {}
-                    }
+/// Why a worker of an endpoint exited before it was stopped
+enum WorkerExit {
+    Failed(String),
+    Panicked
+}
+
+/// Tells the channel thread that worker of the endpoint panicked
+struct PanicNotifier(ModbusTcpEndpoint, mpsc::Sender<(ModbusTcpEndpoint, WorkerExit)>);
+
+impl Drop for PanicNotifier {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.1.send((self.0.clone(), WorkerExit::Panicked)).ok();
+        }
+    }
+}
+
+/// Connection to a modbus TCP server, it is reconnected with backoff when it can not be opened
+struct TcpConnection {
+    modbus: Modbus,
+    mode: ModbusTcpConnectionMode,
+    connected: bool,
+    failures: u32,
+    next_attempt: Instant
+}
+
+impl TcpConnection {
+    fn new(host: &str, port: u16, config: &ModbusClientTcpConfig) -> Result<Self, String> {
+        let mut modbus = Modbus::new_tcp(host, port as i32).map_err(|e| e.to_string())?;
+        modbus.set_byte_timeout(timeout(config.byte_timeout.unwrap_or(DEFAULT_BYTE_TIMEOUT))).map_err(|e| e.to_string())?;
+        modbus.set_response_timeout(timeout(config.response_timeout.unwrap_or(DEFAULT_RESPONSE_TIMEOUT))).map_err(|e| e.to_string())?;
+        // Lost connection is reopened by TcpConnection with its own backoff, not by libmodbus
+        modbus.set_error_recovery(Some(&[ErrorRecoveryMode::Protocol])).map_err(|e| e.to_string())?;
+        Ok(Self {
+            modbus,
+            mode: config.connection,
+            connected: false,
+            failures: 0,
+            next_attempt: Instant::now()
+        })
+    }
+
+    /// Returns true when connected, failed attempts are not repeated until backoff elapses
+    fn connect(&mut self) -> bool {
+        if self.connected {
+            return true;
+        }
+        if Instant::now() < self.next_attempt {
+            return false;
+        }
+        match self.modbus.connect() {
+            Ok(_) => {
+                log::info!("Connected to modbusTCP");
+                self.connected = true;
+                self.failures = 0;
+                true
+            },
+            Err(e) => {
+                let backoff = reconnect_backoff(self.failures);
+                self.failures += 1;
+                self.next_attempt = Instant::now() + backoff;
+                log::error!("Error connecting to modbusTCP: {}, next attempt in {:?}", e, backoff);
+                false
+            }
+        }
+    }
+
+    /// Connection is reopened before the next request
+    fn lost(&mut self) {
+        log::warn!("Connection to modbusTCP was lost");
+        self.disconnect();
+    }
+
+    fn disconnect(&mut self) {
+        if self.connected {
+            self.modbus.close();
+            self.connected = false;
+        }
+    }
+
+}
+
+impl ModbusTransport for TcpConnection {
+    /// libmodbus adds MBAP header to the raw request, it is stripped from the confirmation
+    fn transact(&mut self, modbus_id: ModbusSlaveId, request: &[u8]) -> Result<Vec<u8>, ModbusError> {
+        if !self.connect() {
+            return Err(ModbusError::NotConnected(String::from("Could not connect to modbusTCP server")));
+        }
+        if let Err(e) = self.modbus.flush() {
+            log::error!("Error flushing untransmited data: {:?}", e);
+        }
+        let mut raw_request = vec![modbus_id];
+        raw_request.extend_from_slice(request);
+        let length = raw_request.len();
+        let mut response = [0u8; MAX_TCP_ADU];
+        let result = self.modbus.send_raw_request(&mut raw_request, length)
+            .and_then(|_| self.modbus.receive_confirmation(&mut response));
+        match result {
+            Ok(length) if length as usize > MBAP_HEADER => Ok(response[MBAP_HEADER..length as usize].to_vec()),
+            Ok(length) => Err(ModbusError::Garbled(format!("Response of {} bytes has no PDU", length))),
+            Err(e) => {
+                let kind = error_cause(&e).map_or(io::ErrorKind::Other, io::Error::kind);
+                if error_cause(&e).is_some_and(is_connection_error) {
+                    self.lost();
+                }
+                match kind {
+                    io::ErrorKind::TimedOut => Err(ModbusError::Timeout),
+                    kind => Err(ModbusError::Io(io::Error::new(kind, e.to_string())))
                 }
             }
-        }).unwrap();
-    
+        }
+    }
 
-        builder
+    fn end_cycle(&mut self) {
+        if self.mode == ModbusTcpConnectionMode::PerCycle {
+            self.disconnect();
+        }
     }
+}
 
-    fn status(&self) ->  crate::channels::ChannelStatus {
-        todo!()
+fn reconnect_backoff(failures: u32) -> Duration {
+    RECONNECT_BACKOFF.saturating_mul(2u32.saturating_pow(failures)).min(MAX_RECONNECT_BACKOFF)
+}
+
+fn timeout(millis: u64) -> Timeout {
+    Timeout::new((millis / 1000) as u32, ((millis % 1000) * 1000) as u32)
+}
+
+/// errno of the failed libmodbus call, it is captured in the error right after the call.
+/// Protocol errors of libmodbus (EMBBADDATA etc.) have no io::ErrorKind of their own
+fn error_cause(error: &LibmodbusError) -> Option<&io::Error> {
+    match error {
+        LibmodbusError::Client { source, .. } => Some(source),
+        _ => None
+    }
+}
+
+/// Causes which mean the connection was lost. Timeouts keep the connection open,
+/// a slave behind a TCP-to-RTU gateway that does not answer must not drop the connection of the others
+fn is_connection_error(error: &io::Error) -> bool {
+    matches!(error.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+    use super::{reconnect_backoff, is_connection_error, error_cause, LibmodbusError, ModbusTcpEndpoint};
+    use crate::channels::modbus::ModbusClientTcpConfig;
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_max() {
+        assert_eq!(reconnect_backoff(0), Duration::from_secs(1));
+        assert_eq!(reconnect_backoff(2), Duration::from_secs(4));
+        assert_eq!(reconnect_backoff(20), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn connection_errors() {
+        assert!(is_connection_error(&io::Error::from(io::ErrorKind::ConnectionReset)));
+        assert!(is_connection_error(&io::Error::from(io::ErrorKind::BrokenPipe)));
+        assert!(!is_connection_error(&io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(!is_connection_error(&io::Error::from(io::ErrorKind::InvalidData)));
+        let error = LibmodbusError::Client { msg: String::from("receive_confirmation"), source: io::Error::from(io::ErrorKind::ConnectionReset) };
+        assert!(error_cause(&error).is_some_and(is_connection_error));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn slave_endpoint_overrides_channel() {
+        let config: ModbusClientTcpConfig = serde_yaml::from_str(r#"
+name: Channel
+host: "10.0.0.1"
+port: 502
+slaves:
+  - device_name: Meter1
+    modbus_id: 1
+    register_map: map.yml
+  - device_name: Meter2
+    modbus_id: 2
+    host: "10.0.0.2"
+    register_map: map.yml
+  - device_name: Meter3
+    modbus_id: 3
+    host: "10.0.0.2"
+    port: 5020
+    register_map: map.yml
+"#).unwrap();
+        let endpoints: Vec<String> = config.slaves.iter()
+            .map(|slave| ModbusTcpEndpoint::of(&config, slave).to_string())
+            .collect();
+        assert_eq!(endpoints, vec!["10.0.0.1:502", "10.0.0.2:502", "10.0.0.2:5020"]);
+    }
+}
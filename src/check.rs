@@ -0,0 +1,468 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use job_scheduler::Schedule;
+
+use crate::channels::ChannelConfig;
+use crate::channels::modbus::{ModbusClientRtuConfig, ModbusClientRtuSocketConfig, ModbusClientTcpConfig, ModbusDataType, ModbusRegisterGroup, ModbusRegisterMap, ModbusServerTcpConfig, ModbusSlave};
+use crate::channels::modbus::blocks::read_limit;
+use crate::channels::modbus::scheduler::ModbusPollInterval;
+use crate::definitions::{ChannelType, MainConfig, Storage, StorageBackupManagement, StorageSizeManagement};
+use crate::utilities;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+/// Problem found in a configuration file, line starts at 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub severity: Severity,
+    pub file: String,
+    pub line: Option<usize>,
+    pub message: String
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning"
+        };
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}: {}", self.file, line, severity, self.message),
+            None => write!(f, "{}: {}: {}", self.file, severity, self.message)
+        }
+    }
+}
+
+/// Runs `check` subcommand, prints the report and returns exit code:
+/// 0 when no errors were found, 1 otherwise
+pub fn run(root_config: &str) -> i32 {
+    let issues = check_config(root_config);
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    let errors = issues.iter().filter(|issue| issue.severity == Severity::Error).count();
+    println!("{}: {} errors, {} warnings", root_config, errors, issues.len() - errors);
+    if errors > 0 { 1 } else { 0 }
+}
+
+/// Loads root config, every channel config and every register map the same way as the gateway does
+/// and reports everything that would make them fail or behave unexpectedly at runtime
+pub fn check_config(root_config: &str) -> Vec<Issue> {
+    let mut report = Report::default();
+    let raw = match report.read(root_config, None, root_config) {
+        Some(raw) => raw,
+        None => return report.issues
+    };
+    let config: MainConfig = match serde_yaml::from_str(&raw) {
+        Ok(config) => config,
+        Err(e) => {
+            report.parse_error(root_config, &e);
+            return report.issues;
+        }
+    };
+    let mut root = Source::new(root_config, &raw);
+    check_storage(&config.storage, &mut root, &mut report);
+
+    let mut checked_maps = HashSet::new();
+    // Device name and file where it was first configured, RPC is routed by device name
+    let mut device_names: HashMap<String, String> = HashMap::new();
+    let mut channel_files: HashSet<&str> = HashSet::new();
+    // Devices served by server channels with the file and line of their unit
+    let mut served_devices: Vec<(String, String, Option<usize>)> = Vec::new();
+    for definition in &config.channels {
+        let line = root.next_line("file", &definition.file);
+        if !channel_files.insert(&definition.file) {
+            report.error(root_config, line, format!("Channel {} is configured more than once", definition.file));
+            continue;
+        }
+        let raw = match report.read(&definition.file, line, root_config) {
+            Some(raw) => raw,
+            None => continue
+        };
+        if definition._type == ChannelType::ModbusTcpServer {
+            check_server(&definition.file, &raw, &mut checked_maps, &mut served_devices, &mut report);
+            continue;
+        }
+        let slaves = match channel_slaves(&definition._type, raw.clone()) {
+            Ok(slaves) => slaves,
+            Err(e) => {
+                report.parse_error(&definition.file, &e);
+                continue;
+            }
+        };
+        let mut channel = Source::new(&definition.file, &raw);
+        for slave in slaves {
+            let line = channel.next_line("device_name", &slave.device_name);
+            match device_names.get(&slave.device_name) {
+                Some(file) => report.error(&definition.file, line, format!("Device name {} is already used in {}", slave.device_name, file)),
+                None => {
+                    device_names.insert(slave.device_name.clone(), definition.file.clone());
+                }
+            }
+            if let Some(interval) = &slave.poll_interval {
+                check_poll_interval(interval, &mut channel, line, &slave.device_name, &mut report);
+            }
+            if checked_maps.insert(slave.register_map.clone()) {
+                if let Some(raw) = report.read(&slave.register_map, line, &definition.file) {
+                    check_register_map(&slave.register_map, &raw, &mut report);
+                }
+            }
+        }
+    }
+    // Servers may be configured before channels that poll their devices
+    for (device_name, file, line) in served_devices {
+        if !device_names.contains_key(&device_name) {
+            report.warning(&file, line, format!("Device {} is not polled by any channel, its unit is always unavailable", device_name));
+        }
+    }
+    report.issues
+}
+
+/// Parses channel config with the type set in root config and returns its slaves
+fn channel_slaves(channel_type: &ChannelType, raw: String) -> Result<Vec<ModbusSlave>, serde_yaml::Error> {
+    match channel_type {
+        ChannelType::ModbusTcp => ModbusClientTcpConfig::serialize(raw).map(|config| config.slaves),
+        ChannelType::ModbusRtu => ModbusClientRtuConfig::serialize(raw).map(|config| config.slaves),
+        ChannelType::ModbusRtuOverTcp | ChannelType::ModbusUdp => ModbusClientRtuSocketConfig::serialize(raw).map(|config| config.slaves),
+        // Server units are checked by check_server
+        ChannelType::ModbusTcpServer => Ok(Vec::new())
+    }
+}
+
+/// Checks register maps of server units and collects devices they serve
+fn check_server(path: &str, raw: &str, checked_maps: &mut HashSet<String>, served_devices: &mut Vec<(String, String, Option<usize>)>, report: &mut Report) {
+    let config = match ModbusServerTcpConfig::serialize(raw.to_string()) {
+        Ok(config) => config,
+        Err(e) => {
+            report.parse_error(path, &e);
+            return;
+        }
+    };
+    let mut source = Source::new(path, raw);
+    for unit in &config.units {
+        let line = source.next_line("device_name", &unit.device_name);
+        served_devices.push((unit.device_name.clone(), path.to_string(), line));
+        if checked_maps.insert(unit.register_map.clone()) {
+            if let Some(raw) = report.read(&unit.register_map, line, path) {
+                check_register_map(&unit.register_map, &raw, report);
+            }
+        }
+    }
+}
+
+/// Storage jobs are scheduled with cron expressions which are parsed only when storage starts
+fn check_storage(storage: &Storage, root: &mut Source, report: &mut Report) {
+    let Storage::Sqlite { size_management, backup_management, .. } = storage;
+    let StorageSizeManagement::FixedWindow { messages_ttl_check, .. } = size_management;
+    let StorageBackupManagement::Local { backup_interval, .. } = backup_management;
+    for (key, expression) in [("messages_ttl_check", messages_ttl_check), ("backup_interval", backup_interval)] {
+        if let Err(e) = Schedule::from_str(expression) {
+            let line = root.next_line(key, expression);
+            report.error(root.path, line, format!("Invalid cron expression {:?} of {}: {}", expression, key, e));
+        }
+    }
+}
+
+/// Error is reported at the `poll_interval` line, or at `line` of its owner when it is not found
+fn check_poll_interval(interval: &ModbusPollInterval, source: &mut Source, line: Option<usize>, owner: &str, report: &mut Report) {
+    let (value, error) = match interval {
+        ModbusPollInterval::Millis(0) => (String::from("0"), String::from("0 ms")),
+        ModbusPollInterval::Millis(_) => return,
+        ModbusPollInterval::Cron(expression) => match Schedule::from_str(expression) {
+            Ok(_) => return,
+            Err(e) => (expression.clone(), format!("invalid cron expression {:?}: {}", expression, e))
+        }
+    };
+    let line = source.next_line("poll_interval", &value).or(line);
+    report.error(source.path, line, format!("poll_interval of {} is {}", owner, error));
+}
+
+fn check_register_map(path: &str, raw: &str, report: &mut Report) {
+    let register_map = match ModbusRegisterMap::from_yaml(raw) {
+        Ok(register_map) => register_map,
+        Err(e) => {
+            report.parse_error(path, &e);
+            return;
+        }
+    };
+    let mut source = Source::new(path, raw);
+    // Line of the first data point with a key name, values of later ones would overwrite it
+    let mut key_names: HashMap<&str, Option<usize>> = HashMap::new();
+    for group in register_map.attributes.iter().chain(register_map.timeseries.iter()) {
+        let lines: Vec<Option<usize>> = group.data_points.iter()
+            .map(|data_point| source.next_line("key_name", &data_point.key_name))
+            .collect();
+        for (data_point, line) in group.data_points.iter().zip(&lines) {
+            match key_names.get(data_point.key_name.as_str()) {
+                Some(first) => report.error(path, *line, format!("Duplicate key_name {}, first one is at line {}",
+                    data_point.key_name, first.map_or(String::from("?"), |line| line.to_string()))),
+                None => {
+                    key_names.insert(&data_point.key_name, *line);
+                }
+            }
+        }
+        let line = source.next_line("starting_address", &group.starting_address.to_string());
+        check_group(group, line, &lines, &mut source, report);
+    }
+    let mut methods = HashSet::new();
+    for method in &register_map.rpc {
+        let line = source.next_line("method", &method.method);
+        if !methods.insert(&method.method) {
+            report.error(path, line, format!("Duplicate RPC method {}", method.method));
+        }
+    }
+}
+
+/// Checks that group can be read by one request and that its data points fit into read data
+fn check_group(group: &ModbusRegisterGroup, line: Option<usize>, point_lines: &[Option<usize>], source: &mut Source, report: &mut Report) {
+    let path = source.path;
+    let function = group.function;
+    let limit = read_limit(function);
+    if group.elements_count == 0 || group.elements_count > limit {
+        report.error(path, line, format!("Group at {} reads {} elements, one request reads 1 to {}", group.starting_address, group.elements_count, limit));
+    }
+    if group.starting_address as u32 + group.elements_count as u32 > 0x10000 {
+        report.error(path, line, format!("Group at {} with elements_count {} reaches beyond address 65535", group.starting_address, group.elements_count));
+    }
+    if let Some(interval) = &group.poll_interval {
+        check_poll_interval(interval, source, line, &format!("group at {}", group.starting_address), report);
+    }
+
+    // Bytes (or bits) read by every data point, to find data points that overlap
+    let mut ranges = Vec::with_capacity(group.data_points.len());
+    for (data_point, line) in group.data_points.iter().zip(point_lines) {
+        let line = *line;
+        let key = &data_point.key_name;
+        if function.is_bit_function() {
+            if !matches!(data_point.data_type, ModbusDataType::Bool) {
+                report.error(path, line, format!("Data type {:?} of {} can not be read from coils or discrete inputs", data_point.data_type, key));
+            }
+            if data_point.data_offset >= group.elements_count as usize {
+                report.error(path, line, format!("data_offset {} of {} is outside of {} elements of the group", data_point.data_offset, key, group.elements_count));
+            }
+            ranges.push((data_point.data_offset, data_point.data_offset + 1, data_point, line));
+            continue;
+        }
+
+        let size = data_point.size();
+        if matches!(data_point.data_type, ModbusDataType::String) && data_point.register_count == 0 {
+            report.error(path, line, format!("String {} has to be at least 1 register long", key));
+        }
+        if data_point.data_offset + size > group.elements_count as usize * 2 {
+            report.error(path, line, format!("data_offset {} + size {} of {} is more than elements_count * 2 = {} bytes of the group",
+                data_point.data_offset, size, key, group.elements_count as usize * 2));
+        }
+        if data_point.data_offset % 2 != 0 {
+            report.warning(path, line, format!("data_offset {} of {} is not at the start of a register", data_point.data_offset, key));
+        }
+        if let Some(expected) = data_point.data_type.register_count() {
+            if data_point.register_count != expected {
+                report.warning(path, line, format!("register_count {} of {} is ignored, {:?} is {} registers long", data_point.register_count, key, data_point.data_type, expected));
+            }
+        }
+        if matches!(data_point.data_type, ModbusDataType::Bitfield) {
+            let bit_length = data_point.bit_length.unwrap_or(1);
+            match data_point.bit_offset {
+                None => report.error(path, line, format!("Bitfield {} is missing bit_offset", key)),
+                Some(bit_offset) if bit_length == 0 || bit_offset as u32 + bit_length as u32 > 16 =>
+                    report.error(path, line, format!("Bitfield {} with bit_offset {} and bit_length {} does not fit in a register", key, bit_offset, bit_length)),
+                Some(_) => {}
+            }
+        }
+        let scaled = data_point.scale.is_some() || data_point.offset.is_some() || data_point.scale_factor_register.is_some();
+        if scaled && matches!(data_point.data_type, ModbusDataType::String | ModbusDataType::Bool) {
+            report.error(path, line, format!("Data type {:?} of {} can not be scaled", data_point.data_type, key));
+        }
+        if let Some(register) = data_point.scale_factor_register {
+            if register >= group.elements_count as usize {
+                report.error(path, line, format!("scale_factor_register {} of {} is outside of {} registers of the group", register, key, group.elements_count));
+            }
+        }
+        ranges.push((data_point.data_offset, data_point.data_offset + size, data_point, line));
+    }
+
+    // Bitfields share their register on purpose
+    ranges.sort_by_key(|(start, end, _, _)| (*start, *end));
+    for (i, (_, end, first, _)) in ranges.iter().enumerate() {
+        for (start, _, second, line) in &ranges[i + 1..] {
+            if start >= end {
+                break;
+            }
+            if !(matches!(first.data_type, ModbusDataType::Bitfield) && matches!(second.data_type, ModbusDataType::Bitfield)) {
+                report.warning(path, *line, format!("{} overlaps {} in group at {}", second.key_name, first.key_name, group.starting_address));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    issues: Vec<Issue>
+}
+
+impl Report {
+    fn error(&mut self, file: &str, line: Option<usize>, message: String) {
+        self.push(Severity::Error, file, line, message);
+    }
+
+    fn warning(&mut self, file: &str, line: Option<usize>, message: String) {
+        self.push(Severity::Warning, file, line, message);
+    }
+
+    fn push(&mut self, severity: Severity, file: &str, line: Option<usize>, message: String) {
+        self.issues.push(Issue {
+            severity,
+            file: file.to_string(),
+            line,
+            message
+        });
+    }
+
+    /// Error of serde_yaml already contains line and column when it knows them
+    fn parse_error(&mut self, file: &str, e: &serde_yaml::Error) {
+        self.error(file, None, e.to_string());
+    }
+
+    /// Reads file, failure is reported at the line of `referenced_by` that references the file
+    fn read(&mut self, path: &str, line: Option<usize>, referenced_by: &str) -> Option<String> {
+        match utilities::open_and_read(path.to_string()) {
+            Ok((raw, _)) => Some(raw),
+            Err(e) => {
+                self.error(referenced_by, line, format!("Could not read {}: {}", path, e));
+                None
+            }
+        }
+    }
+}
+
+/// Raw configuration file, deserialized values are located in it by their `key: value` lines
+struct Source<'a> {
+    path: &'a str,
+    raw: &'a str,
+    // Occurrences of key and value that were already located
+    located: HashMap<(String, String), usize>
+}
+
+impl<'a> Source<'a> {
+    fn new(path: &'a str, raw: &'a str) -> Self {
+        Self {
+            path,
+            raw,
+            located: HashMap::new()
+        }
+    }
+
+    /// Line of the next occurrence of `key: value`, so that repeated values are located in order
+    fn next_line(&mut self, key: &str, value: &str) -> Option<usize> {
+        let nth = self.located.entry((key.to_string(), value.to_string())).or_insert(0);
+        let line = self.raw.lines()
+            .enumerate()
+            .filter(|(_, line)| contains_entry(line, key, value))
+            .nth(*nth)
+            .map(|(index, _)| index + 1);
+        *nth += 1;
+        line
+    }
+}
+
+/// Whether a block (`key: value`) or flow (`{ key: value, .. }`) mapping on the line has the entry
+fn contains_entry(line: &str, key: &str, value: &str) -> bool {
+    let line = match line.find(" #") {
+        Some(comment) => &line[..comment],
+        None => line
+    };
+    line.split([',', '{', '}'])
+        .filter_map(|entry| entry.trim().trim_start_matches("- ").split_once(':'))
+        .any(|(k, v)| k.trim() == key && v.trim().trim_matches(|c| c == '"' || c == '\'') == value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{check_config, contains_entry, Severity};
+
+    #[test]
+    fn entries_are_found_in_block_and_flow_mappings() {
+        assert!(contains_entry("  - key_name: Voltage # Comment", "key_name", "Voltage"));
+        assert!(contains_entry("- { address: 10, key_name: \"Voltage\" }", "key_name", "Voltage"));
+        assert!(!contains_entry("    key_name: Voltage2", "key_name", "Voltage"));
+        assert!(!contains_entry("    # key_name: Voltage", "key_name", "Voltage"));
+    }
+
+    #[test]
+    fn register_map_issues_are_reported_with_lines() {
+        // Unique per process so that concurrent test runs do not share the files
+        let dir = std::env::temp_dir().join(format!("sts_gateway_check_{}_register_map_issues", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        fs::write(path("root.yml"), format!(r#"
+name: Gateway
+log_config: log.yml
+mqtt: {{ host: localhost, port: 1883, qos: 0 }}
+storage:
+  type: sqlite
+  data_folder: data.db
+  size_management: {{ type: fixed_window, messages_ttl_check: "0 0 * * * *", messages_ttl: 24 }}
+  backup_management: {{ type: local, backup_folder: backup, backup_interval: "not cron", backup_ttl: 1 }}
+channels:
+  - type: modbus_tcp
+    file: {}
+  - type: modbus_udp
+    file: {}
+"#, path("tcp.yml"), path("missing.yml"))).unwrap();
+        fs::write(path("tcp.yml"), format!(r#"
+name: Channel
+host: localhost
+port: 502
+slaves:
+  - device_name: Meter
+    modbus_id: 1
+    poll_interval: "every minute"
+    register_map: {0}
+  - device_name: Meter
+    modbus_id: 2
+    register_map: {0}
+"#, path("map.yml"))).unwrap();
+        fs::write(path("map.yml"), r#"
+attributes: []
+timeseries:
+  - starting_address: 0
+    elements_count: 2
+    data_points:
+      - data_offset: 0
+        register_count: 2
+        data_type: float
+        key_name: Voltage
+      - data_offset: 4
+        register_count: 2
+        data_type: float
+        key_name: Current
+      - data_offset: 2
+        register_count: 1
+        data_type: uint16
+        key_name: Voltage
+"#).unwrap();
+
+        let issues = check_config(&path("root.yml"));
+        let found: Vec<(Severity, String, Option<usize>)> = issues.iter()
+            .map(|issue| (issue.severity, issue.file.rsplit('/').next().unwrap().to_string(), issue.line))
+            .collect();
+        assert_eq!(found, vec![
+            (Severity::Error, String::from("root.yml"), Some(9)), // backup_interval
+            (Severity::Error, String::from("tcp.yml"), Some(8)), // poll_interval
+            (Severity::Error, String::from("map.yml"), Some(18)), // duplicate Voltage
+            (Severity::Error, String::from("map.yml"), Some(14)), // Current does not fit
+            (Severity::Warning, String::from("map.yml"), Some(18)), // Voltage overlaps Voltage
+            (Severity::Error, String::from("tcp.yml"), Some(10)), // device_name
+            (Severity::Error, String::from("root.yml"), Some(14)), // missing.yml
+        ]);
+        assert!(issues[3].message.contains("data_offset 4 + size 4 of Current"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
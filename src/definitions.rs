@@ -1,25 +1,142 @@
 
 use std::collections::HashMap;
+use std::fmt;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use crate::storage::Insert;
+use crate::channels::modbus::scan::ScanProbe;
 
 
-use clap::Parser;
+use clap::{ArgEnum, Parser, Subcommand};
 
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct MainArguments {
     /// Required file to startup the gateway
-    pub root_config: String
+    #[clap(required = true)]
+    pub root_config: Option<String>,
+    #[clap(subcommand)]
+    pub command: Option<MainCommand>
 }
+
+// Tools that run instead of the gateway
+#[derive(Subcommand, Debug)]
+pub enum MainCommand {
+    /// Checks root config, channel configs and register maps and prints every problem with its file and line
+    Check {
+        root_config: String
+    },
+    /// Reads register map of a device once and prints parsed data points with raw data, without storage and MQTT
+    Read {
+        /// Channel config with the device
+        #[clap(long)]
+        channel: String,
+        /// Device name of the slave
+        #[clap(long)]
+        device: String,
+        /// Channel type, modbus_rtu when the channel config has baudrate, modbus_tcp otherwise
+        #[clap(long = "type", parse(try_from_str = parse_config_value))]
+        channel_type: Option<ChannelType>,
+        #[clap(long, arg_enum, default_value = "table")]
+        format: OutputFormat
+    },
+    /// Probes slave ids of a channel and prints which ones answered, with their identification when they send it
+    Scan {
+        /// Channel config with the serial line or TCP endpoint, its slaves are not used
+        #[clap(long)]
+        channel: String,
+        /// Channel type, modbus_rtu when the channel config has baudrate, modbus_tcp otherwise
+        #[clap(long = "type", parse(try_from_str = parse_config_value))]
+        channel_type: Option<ChannelType>,
+        /// First probed slave id
+        #[clap(long, default_value = "1")]
+        from: u8,
+        /// Last probed slave id
+        #[clap(long, default_value = "247")]
+        to: u8,
+        /// Request sent to every slave id: holding_registers, input_registers or identification
+        #[clap(long, default_value = "holding_registers", parse(try_from_str = parse_config_value))]
+        probe: ScanProbe,
+        /// Register read by holding_registers and input_registers probes
+        #[clap(long, default_value = "0")]
+        address: u16,
+        /// Response timeout in milliseconds, overrides the one of the channel
+        #[clap(long)]
+        timeout: Option<u64>,
+        #[clap(long, arg_enum, default_value = "table")]
+        format: OutputFormat
+    },
+    /// Serves register maps as simulated slaves over Modbus TCP and RTU on a pseudo-terminal, for testing without hardware
+    Simulate {
+        /// Simulator config with served units and their values
+        config: String
+    }
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json
+}
+
+/// Value of an argument as it is written in configuration files, eg: channel type
+fn parse_config_value<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_yaml::from_str(value).map_err(|_| format!("Unknown value {}", value))
+}
+/// Value of a data point, serialized as a plain json value
+/// so that numbers and booleans are not sent as strings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum DataPointValue {
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String)
+}
+
+impl DataPointValue {
+    /// Numeric value as f64, None for strings and booleans
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            DataPointValue::Int(v) => Some(*v as f64),
+            DataPointValue::UInt(v) => Some(*v as f64),
+            DataPointValue::Float(v) => Some(*v),
+            DataPointValue::Bool(_) | DataPointValue::String(_) => None
+        }
+    }
+}
+
+impl From<f32> for DataPointValue {
+    /// Converts through the shortest decimal representation of f32,
+    /// otherwise 10.1f32 would be sent as 10.100000381469727
+    fn from(value: f32) -> Self {
+        DataPointValue::Float(value.to_string().parse::<f64>().unwrap_or(value as f64))
+    }
+}
+
+impl fmt::Display for DataPointValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataPointValue::Bool(v) => write!(f, "{}", v),
+            DataPointValue::Int(v) => write!(f, "{}", v),
+            DataPointValue::UInt(v) => write!(f, "{}", v),
+            DataPointValue::Float(v) => write!(f, "{}", v),
+            DataPointValue::String(v) => write!(f, "{}", v)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct OneTelemetry {
     pub ts: i64,
     // Key/Value
-    pub values: HashMap<String, String>
+    pub values: HashMap<String, DataPointValue>
 }
 
-pub type AttributeMessage = (String, HashMap<String, String>);
+pub type AttributeMessage = (String, HashMap<String, DataPointValue>);
 
 // String: device_name
 // Vec: Vec<{ts: i64, value: }>
@@ -32,7 +149,9 @@ pub struct MainConfig {
     pub log_config: String,
     pub channels: Vec<ChannelDefinition>,
     pub storage: Storage,
-    pub mqtt: MqttConfig
+    pub mqtt: MqttConfig,
+    // Seconds between checks for changed configuration files
+    pub reload_interval: Option<u64>
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -59,11 +178,18 @@ pub enum StorageBackupManagement {
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 // #[serde(untagged)]
+#[allow(clippy::enum_variant_names)]
 pub enum ChannelType {
     #[serde(rename = "modbus_tcp")]
     ModbusTcp,
     #[serde(rename = "modbus_rtu")]
-    ModbusRtu
+    ModbusRtu,
+    #[serde(rename = "modbus_rtu_over_tcp")]
+    ModbusRtuOverTcp,
+    #[serde(rename = "modbus_udp")]
+    ModbusUdp,
+    #[serde(rename = "modbus_tcp_server")]
+    ModbusTcpServer
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -80,26 +206,76 @@ pub struct MqttConfig {
     pub host: String,
     pub port: u16,
     pub qos: u8,
-    pub tb_token: Option<String>
+    pub tb_token: Option<String>,
+    // Messages per second replayed from storage after broker outage
+    pub replay_rate: Option<u32>
 }
 
 // These are actions that this gateway sends to any transport medium eg: thingsboard server
 pub enum TransportAction {
-    SendTimeseries(String), // Already parsed json string
-    SendAttributes(String),
+    SendBoth(Insert), // Already parsed json strings, acknowledged to storage once published
+    SendRpcResponse(String),
+    SendConnect(String),
+    SendDisconnect(String),
+    // Disconnects from broker and stops transport thread
+    Stop,
     // SendClientSideRPC
 }
 
+/// Server side RPC request for a device, received by a transport
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcRequest {
+    pub device: String,
+    pub id: i64,
+    pub method: String,
+    pub params: serde_json::Value
+}
+
+/// Result of a RPC request, Err holds a message that is sent back to the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcResponse {
+    pub device: String,
+    pub id: i64,
+    pub result: Result<(), String>
+}
+
 // pub struct DataCombined {
 //     attribute_message: Option<AttributeMessage>,
 //     timeseries_message: TimeseriesMessage
 // }
 pub enum AggregatorAction {
     SendBoth(AttributeMessage, TimeseriesMessage),
+    SendRpcResponse(RpcResponse),
+    // Device name and device type, sent when a device starts answering
+    DeviceConnected(String, Option<String>),
+    // Device name, sent when a device stops answering
+    DeviceDisconnected(String),
+    // Aggregator thread exits after processing actions sent before
+    Stop,
     // SendAttributes(AttributeMessage),
     // SendTimeseries(TimeseriesMessage),
     // SendStatistics(AttributeMessage) // will store some statistics in device attributes
 }
 // pub struct RootConfig {
     
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::{DataPointValue, OneTelemetry};
+
+    #[test]
+    fn telemetry_json_has_typed_values() {
+        let mut values = HashMap::new();
+        values.insert(String::from("voltage"), DataPointValue::from(230.1f32));
+        values.insert(String::from("alarm"), DataPointValue::Bool(true));
+        values.insert(String::from("energy"), DataPointValue::UInt(1234));
+        values.insert(String::from("serial"), DataPointValue::String(String::from("SN1")));
+        let json = serde_json::to_value(OneTelemetry { ts: 1, values }).unwrap();
+
+        assert_eq!(json["values"]["voltage"], serde_json::json!(230.1));
+        assert_eq!(json["values"]["alarm"], serde_json::json!(true));
+        assert_eq!(json["values"]["energy"], serde_json::json!(1234));
+        assert_eq!(json["values"]["serial"], serde_json::json!("SN1"));
+    }
+}
@@ -6,25 +6,27 @@ use definitions::{MainConfig, StorageBackupManagement};
 use job_scheduler::JobScheduler;
 use serde_yaml;
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::path::Path;
 use std::str::FromStr;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Sender, Receiver};
 use std::thread::JoinHandle;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::fs;
 use std::thread;
-use std::sync::{mpsc};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use log4rs::{self};
 use chrono::{self, Utc};
 use chrono_tz;
+use signal_hook::consts::{SIGINT, SIGTERM};
 
 
 use crate::aggregator::Aggregator;
-use crate::channels::modbus::rtu::ModbusRtuChannel;
-use crate::channels::{ChannelConfig, Channel};
-use crate::channels::modbus::{ModbusClientTcpConfig, ModbusRegisterMap, ModbusSlave, ModbusClientRtuConfig};
-use crate::channels::modbus::tcp::ModbusTcpChannel;
-use crate::definitions::{TransportAction, AggregatorAction, ChannelType, Storage, StorageSizeManagement};
+use crate::channels::{ChannelConfig, DeviceRoutes, DeviceValues};
+use crate::channels::controller::{ChannelController, ChannelSetup, ControllerAction};
+use crate::channels::modbus::{ModbusClientTcpConfig, ModbusRegisterMap, ModbusClientRtuConfig, ModbusClientRtuSocketConfig, ModbusServerTcpConfig};
+use crate::definitions::{TransportAction, AggregatorAction, ChannelType, ChannelDefinition, Storage, StorageSizeManagement};
 use crate::storage::SqliteStorageTruncate;
 use crate::transport::MqttTransport;
 
@@ -34,26 +36,54 @@ mod transport;
 mod channels;
 mod utilities;
 mod aggregator;
+mod check;
+mod read;
+mod scan;
+// Pseudo-terminal and its link are unix only
+#[cfg(unix)]
+mod simulate;
 
 // use transport::MqttTransport;
 // This will hold a hash of contents of the file, when we will periodicaly read configuration at runtime 
 // we will compare the hashes and determine which part of the gateway to reload
 
+// Seconds between checks of configuration files when `reload_interval` is not set
+const DEFAULT_RELOAD_INTERVAL: u64 = 30;
+// How often is main thread woken up to check for shutdown signal
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
 
 // #[tokio::main]
 fn main() {
 
     // Read arguments if no arguments panic 
     let args = definitions::MainArguments::parse();
+    let root_config = match &args.command {
+        Some(definitions::MainCommand::Check { root_config }) => std::process::exit(check::run(root_config)),
+        Some(definitions::MainCommand::Read { channel, device, channel_type, format }) =>
+            std::process::exit(read::run(channel, device, channel_type.clone(), *format)),
+        Some(definitions::MainCommand::Scan { channel, channel_type, from, to, probe, address, timeout, format }) => {
+            let options = scan::ScanOptions { from: *from, to: *to, probe: *probe, address: *address, timeout: *timeout };
+            std::process::exit(scan::run(channel, channel_type.clone(), options, *format))
+        },
+        #[cfg(unix)]
+        Some(definitions::MainCommand::Simulate { config }) => std::process::exit(simulate::run(config)),
+        #[cfg(not(unix))]
+        Some(definitions::MainCommand::Simulate { .. }) => {
+            eprintln!("Simulator is only supported on unix");
+            std::process::exit(1)
+        },
+        // Clap requires root_config when there is no subcommand
+        None => args.root_config.clone().unwrap()
+    };
 
     let mut state = MainState::new();
     
     // This should panic if the configuration is wrong...
-    let config_path = state.read_file(args.root_config.clone()).unwrap();
+    let config_path = state.read_file(root_config.clone()).unwrap();
 
     // This should panic if the configuration is wrong...
-    let config: definitions::MainConfig = serde_yaml::from_str(&config_path).unwrap();
-    let backup_config = config.clone();
+    let mut config: definitions::MainConfig = serde_yaml::from_str(&config_path).unwrap();
 
     // This should panic if the configuration is wrong...
     log4rs::init_file(config.log_config.clone(), Default::default()).unwrap();
@@ -62,188 +92,340 @@ fn main() {
     log::info!("Arguments: {:?}", args);
     log::debug!("Config: {:#?}", config.clone()); 
 
+    // First signal starts graceful shutdown, second one terminates the gateway right away
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown.clone()).unwrap();
+        signal_hook::flag::register(signal, shutdown.clone()).unwrap();
+    }
+
 
     let (storage_tx, storage_rx) = mpsc::channel::<storage::SqliteStorageAction>();
-    let config_clone = config.clone();
-    let storage_tx_clone = storage_tx.clone();
 
     // TODO: Move this somewhere more appropriete
-    let storage_handle = thread::spawn(move || {
-        log::info!("Starting storage thread...");
-        let config = config_clone.clone();
-        match config.storage {
-            Storage::Sqlite { data_folder, size_management, backup_management } => {
-                
-
-                let _backup_join = match backup_management {
-                    StorageBackupManagement::Local { backup_folder, backup_interval, backup_ttl } => {
-                        match size_management {
-                            StorageSizeManagement::FixedWindow { messages_ttl_check, messages_ttl } => {
-                                backup_local_fixed_window_scheduler(
-                                    storage_tx_clone.clone(), config_clone.clone(), backup_interval, backup_ttl, backup_folder, messages_ttl_check, messages_ttl)
-                            }
-                        }
-                    }
-                };
-
-
-                match  storage::SqliteStorage::new(data_folder, storage_rx) {
-                    Ok(mut storage) => {
-
-                        loop {
-                            storage.process();
-                        }
-                        _backup_join.join().unwrap();
-
-                    },
-                    Err(e) => {
-                        log::error!("Error creating sqlite storage process... {:?}", e);
-                        panic!("Could not start storage process...");
-                    }
-                }
-
-            }
-
+    let storage = match open_storage(&config) {
+        Ok(storage) => storage,
+        Err(e) => {
+            log::error!("Error creating sqlite storage process... {:?}", e);
+            panic!("Could not start storage process...");
         }
-
-
-    });
+    };
+    let mut storage_handle = Some(run_storage(config.clone(), storage, storage_tx.clone(), storage_rx));
 
     let (transport_tx, transport_rx) = mpsc::channel::<TransportAction>();
 
-    let transport = MqttTransport::new(config.clone(), storage_tx.clone(), transport_rx);
-    let transport_handle = transport.run();
+    // RPC requests received by transport are routed to channels by device name
+    let device_routes: DeviceRoutes = Arc::new(Mutex::new(HashMap::new()));
+    let transport = MqttTransport::new(config.clone(), storage_tx.clone(), transport_rx, device_routes.clone());
+    let mut transport_handle = Some(transport.run());
     // Spawn a Aggregation Channel
     // brief This Sender part of the MPSC will be dispatched to every channel
     // so that it can send data to aggregation Thread 
     let (aggregation_tx, aggregation_rx) = mpsc::channel::<AggregatorAction>();
-    let aggregator = Aggregator::new(aggregation_rx, storage_tx.clone(), transport_tx.clone());
+    // Latest values of every device are collected by aggregator for server channels
+    let device_values: DeviceValues = Arc::new(Mutex::new(HashMap::new()));
+    let aggregator = Aggregator::new(aggregation_rx, storage_tx.clone(), transport_tx.clone(), device_values.clone());
     let aggregator_handle = aggregator.run();
 
-    let mut channel_handles: Vec<JoinHandle<()>> = vec![];
+    // Channel threads are owned by the controller
+    let (controller_tx, controller_rx) = mpsc::channel::<ControllerAction>();
+    let controller = ChannelController::new(controller_rx, aggregation_tx.clone(), device_routes.clone(), device_values);
+    let _controller_handle = controller.run();
 
     // Initialize found channel definitions
-    for channel_definition in config.channels {
+    let mut channels: Vec<ConfiguredChannel> = config.channels.iter()
+        .map(|definition| ConfiguredChannel::new(definition.clone()))
+        .collect();
+    for channel in channels.iter_mut() {
+        reload_channel(channel, &mut state, &controller_tx);
+    }
 
-        // try to read file at specified location in definition
-        let raw = match state.read_file(channel_definition.file.clone()) {
-            Ok(s) => s,
-            Err(e) => {
-                log::error!("Could not read channel config file for channel config: {:?} , error: {:?}", channel_definition, e);
-                continue;
-            }
-        };
+    log::debug!("Loaded Configs with their hashes: {:?}", state.get_configured_hashes());
 
-        match channel_definition._type {
-            ChannelType::ModbusTcp => {
-                let modbus_config = match ModbusClientTcpConfig::serialize(raw) {
-                    Ok(conf) => conf,
-                    Err(e) => {
-                        log::error!("Error serializing ModbusClientTcpConfig: {:?}", e);
-                        continue;
+    // Start new storage thread
+    // This thread schould also schedule compactions
+    // Compaction is a process where we delete old data stored in local database
+    // it is essential to work properly. becouse a faulty compaction could couse that
+    // the system drive where this is running to be full. 
+
+    // Periodicaly compare hashes of configuration files and reload only the affected parts
+    let mut next_reload = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(SHUTDOWN_CHECK_INTERVAL);
+        if Instant::now() < next_reload {
+            continue;
+        }
+        next_reload = Instant::now() + Duration::from_secs(config.reload_interval.unwrap_or(DEFAULT_RELOAD_INTERVAL));
+        let changed = utilities::changed_files(state.get_configured_hashes());
+        if changed.is_empty() {
+            continue;
+        }
+        log::info!("Configuration files changed: {:?}", changed);
+
+        if changed.contains(&root_config) {
+            let new_config = state.read_file(root_config.clone())
+                .map_err(|e| e.to_string())
+                .and_then(|raw| serde_yaml::from_str::<MainConfig>(&raw).map_err(|e| e.to_string()));
+            match new_config {
+                Ok(new_config) => {
+                    if new_config.storage != config.storage || new_config.name != config.name {
+                        match open_storage(&new_config) {
+                            Ok(storage) => {
+                                log::info!("Storage configuration changed, restarting storage...");
+                                storage_tx.send(storage::SqliteStorageAction::CloseDB).ok();
+                                storage_handle = match storage_handle.take().map(|handle| handle.join()) {
+                                    Some(Ok(storage_rx)) => Some(run_storage(new_config.clone(), storage, storage_tx.clone(), storage_rx)),
+                                    _ => {
+                                        log::error!("Storage thread is not running, it can not be restarted");
+                                        None
+                                    }
+                                };
+                            },
+                            Err(e) => log::error!("Error opening storage with new configuration, keeping current storage: {:?}", e)
+                        }
                     }
-                };
-                let mut register_maps: HashMap<ModbusSlave, ModbusRegisterMap> = HashMap::new();
-                
-                // We cannot have a channel that could have a 1 correct register for a device and 1 that is wrong
-                // Either all the register maps are correct or none of them are.
-                // By correct i mean a correct yaml format
-                let mut skip_slave: bool = false;
-                for slave in modbus_config.slaves.clone() {
-                   match state.read_file(slave.register_map.clone()) {
-                       Ok(register_map_raw) => {
-            
-                        let register_map = match serde_yaml::from_str::<ModbusRegisterMap>(&register_map_raw) {
-                            Ok(rm) => rm,
-                            Err(e) => {
-                                log::error!("Error in register maps: {:?}", e);
-                                skip_slave = true;
-                                break;
+                    if new_config.mqtt != config.mqtt || new_config.name != config.name {
+                        log::info!("Mqtt configuration changed, restarting transport...");
+                        transport_tx.send(TransportAction::Stop).ok();
+                        transport_handle = match transport_handle.take().map(|handle| handle.join()) {
+                            Some(Ok(transport_rx)) => {
+                                let transport = MqttTransport::new(new_config.clone(), storage_tx.clone(), transport_rx, device_routes.clone());
+                                Some(transport.run())
+                            },
+                            _ => {
+                                log::error!("Transport thread is not running, it can not be restarted");
+                                None
                             }
                         };
-                            register_maps.insert(slave, register_map);
-            
-                       },
-                       Err(e) => {
-                           log::error!("Error reading file path: {:?}, {:?}", slave.register_map, e );
-                       }
-            
-                   }
-                }
-                if skip_slave { continue };
-                let modbus_channel = ModbusTcpChannel::new(modbus_config, register_maps, aggregation_tx.clone());
-                channel_handles.push(modbus_channel.run());
+                    }
+                    if new_config.log_config != config.log_config {
+                        log::warn!("Changed log_config is applied only after restart of the gateway");
+                    }
+                    config = new_config;
+                },
+                Err(e) => log::error!("Error in root config, keeping current configuration: {}", e)
+            }
+        }
 
+        // Stop channels that were removed from root config
+        let (kept, removed): (Vec<ConfiguredChannel>, Vec<ConfiguredChannel>) = channels.into_iter()
+            .partition(|channel| config.channels.contains(&channel.definition));
+        channels = kept;
+        for channel in removed {
+            log::info!("Channel {} was removed from root config", channel.definition.file);
+            if let Err(e) = controller_tx.send(ControllerAction::Remove(channel.definition.file)) {
+                log::error!("Error sending channel removal to controller: {:?}", e);
+            }
+        }
+        for definition in &config.channels {
+            if !channels.iter().any(|channel| &channel.definition == definition) {
+                channels.push(ConfiguredChannel::new(definition.clone()));
+            }
+        }
+        // New channels were not loaded yet, others only when any of their files changed
+        for channel in channels.iter_mut() {
+            if channel.files.is_empty() || channel.files.iter().any(|file| changed.contains(file)) {
+                reload_channel(channel, &mut state, &controller_tx);
+            }
+        }
+        log_channel_states(&controller_tx);
+    }
 
-            },
-            // TODO: Deduplicate code...
-            ChannelType::ModbusRtu => {
-                let modbus_config = match ModbusClientRtuConfig::serialize(raw) {
-                    Ok(conf) => conf,
+    // Every part is stopped only after everything that sends to it was stopped,
+    // so that actions queued in between are processed before it exits
+    log::info!("Shutting down...");
+    let (reply_tx, reply_rx) = mpsc::channel();
+    match controller_tx.send(ControllerAction::StopAll(reply_tx)) {
+        Ok(_) => if reply_rx.recv().is_err() {
+            log::error!("Channel controller exited before stopping channels");
+        },
+        Err(e) => log::error!("Channel controller is not running: {:?}", e)
+    }
+    aggregation_tx.send(AggregatorAction::Stop).ok();
+    if aggregator_handle.join().is_err() {
+        log::error!("Aggregator thread panicked");
+    }
+    // Transport publishes queued messages and acknowledges them to storage before disconnecting
+    transport_tx.send(TransportAction::Stop).ok();
+    if let Some(Err(_)) = transport_handle.map(|handle| handle.join()) {
+        log::error!("Transport thread panicked");
+    }
+    storage_tx.send(storage::SqliteStorageAction::CloseDB).ok();
+    if let Some(Err(_)) = storage_handle.map(|handle| handle.join()) {
+        log::error!("Storage thread panicked");
+    }
+    log::info!("Gateway stopped");
+}
+
+/// Channel definition of root config with files that were read to load it
+struct ConfiguredChannel {
+    definition: ChannelDefinition,
+    files: Vec<String>,
+    // Channel was sent to ChannelController, later setups restart it
+    started: bool
+}
+
+impl ConfiguredChannel {
+    fn new(definition: ChannelDefinition) -> Self {
+        Self {
+            definition,
+            files: vec![],
+            started: false
+        }
+    }
+}
+
+/// Loads configuration of the channel and (re)starts it with ChannelController,
+/// running channel is kept as is when the new configuration is broken
+fn reload_channel(channel: &mut ConfiguredChannel, state: &mut MainState, controller_tx: &Sender<ControllerAction>) {
+    let mut files = vec![];
+    let setup = load_channel(&channel.definition, state, &mut files);
+    // Files of the broken configuration are watched too, so that fixing them triggers next reload
+    for file in files {
+        if !channel.files.contains(&file) {
+            channel.files.push(file);
+        }
+    }
+    match setup {
+        Some(setup) => {
+            let name = channel.definition.file.clone();
+            let action = match channel.started {
+                true => ControllerAction::Restart(name, setup),
+                false => ControllerAction::Start(name, setup)
+            };
+            match controller_tx.send(action) {
+                Ok(_) => channel.started = true,
+                Err(e) => log::error!("Error sending channel {} to controller: {:?}", channel.definition.file, e)
+            }
+        },
+        None => log::error!("Configuration of channel {} is broken, it keeps running with previous configuration if it was running", channel.definition.file)
+    }
+}
+
+/// Reads channel config and register maps of its slaves, paths of read files are pushed to files
+fn load_channel(channel_definition: &ChannelDefinition, state: &mut MainState, files: &mut Vec<String>) -> Option<ChannelSetup> {
+    // try to read file at specified location in definition
+    files.push(channel_definition.file.clone());
+    let raw = match state.read_file(channel_definition.file.clone()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Could not read channel config file for channel config: {:?} , error: {:?}", channel_definition, e);
+            return None;
+        }
+    };
+
+    match channel_definition._type {
+        ChannelType::ModbusTcp => {
+            let modbus_config: ModbusClientTcpConfig = parse_channel_config(raw)?;
+            let register_maps = load_register_maps(&modbus_config.slaves, |slave| &slave.register_map, state, files)?;
+            Some(ChannelSetup::ModbusTcp(modbus_config, register_maps))
+        },
+        ChannelType::ModbusRtu => {
+            let modbus_config: ModbusClientRtuConfig = parse_channel_config(raw)?;
+            let register_maps = load_register_maps(&modbus_config.slaves, |slave| &slave.register_map, state, files)?;
+            Some(ChannelSetup::ModbusRtu(modbus_config, register_maps))
+        },
+        ChannelType::ModbusRtuOverTcp => {
+            let modbus_config: ModbusClientRtuSocketConfig = parse_channel_config(raw)?;
+            let register_maps = load_register_maps(&modbus_config.slaves, |slave| &slave.register_map, state, files)?;
+            Some(ChannelSetup::ModbusRtuOverTcp(modbus_config, register_maps))
+        },
+        ChannelType::ModbusUdp => {
+            let modbus_config: ModbusClientRtuSocketConfig = parse_channel_config(raw)?;
+            let register_maps = load_register_maps(&modbus_config.slaves, |slave| &slave.register_map, state, files)?;
+            Some(ChannelSetup::ModbusUdp(modbus_config, register_maps))
+        },
+        ChannelType::ModbusTcpServer => {
+            let server_config: ModbusServerTcpConfig = parse_channel_config(raw)?;
+            let register_maps = load_register_maps(&server_config.units, |unit| &unit.register_map, state, files)?;
+            Some(ChannelSetup::ModbusTcpServer(server_config, register_maps))
+        }
+    }
+}
+
+fn parse_channel_config<C: ChannelConfig>(raw: String) -> Option<C> {
+    match C::serialize(raw) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            log::error!("Error serializing {}: {:?}", std::any::type_name::<C>(), e);
+            None
+        }
+    }
+}
+
+/// Register maps of channel slaves or server units, `path` is the register map file of one of them
+fn load_register_maps<T: Clone + Eq + Hash>(slaves: &[T], path: impl Fn(&T) -> &String, state: &mut MainState, files: &mut Vec<String>) -> Option<HashMap<T, ModbusRegisterMap>> {
+    let mut register_maps: HashMap<T, ModbusRegisterMap> = HashMap::new();
+    // We cannot have a channel that could have a 1 correct register for a device and 1 that is wrong
+    // Either all the register maps are correct or none of them are.
+    // By correct i mean a correct yaml format
+    for slave in slaves {
+        let register_map_path = path(slave);
+        files.push(register_map_path.clone());
+        match state.read_file(register_map_path.clone()) {
+            Ok(register_map_raw) => {
+                match ModbusRegisterMap::from_yaml(&register_map_raw) {
+                    Ok(register_map) => {
+                        register_maps.insert(slave.clone(), register_map);
+                    },
                     Err(e) => {
-                        log::error!("Error serializing ModbusClientRtuConfig: {:?}", e);
-                        continue;
+                        log::error!("Error in register maps: {:?}", e);
+                        return None;
                     }
                 };
-                let mut register_maps: HashMap<ModbusSlave, ModbusRegisterMap> = HashMap::new();
-                
-                // We cannot have a channel that could have a 1 correct register for a device and 1 that is wrong
-                // Either all the register maps are correct or none of them are.
-                // By correct i mean a correct yaml format
-                let mut skip_slave: bool = false;
-                for slave in modbus_config.slaves.clone() {
-
-                   match state.read_file(slave.register_map.clone()) {
-                       Ok(register_map_raw) => {
-            
-                        let register_map = match serde_yaml::from_str::<ModbusRegisterMap>(&register_map_raw) {
-                            Ok(rm) => rm,
-                            Err(e) => {
-                                log::error!("Error in register maps: {:?}", e);
-                                skip_slave = true;
-                                break;
-                            }
-                        };
-                            register_maps.insert(slave, register_map);
-            
-                       },
-                       Err(e) => {
-                           log::error!("Error reading file path: {:?}, {:?}", slave.register_map, e );
-                       }
-            
-                   }
-                }
-                
-                if skip_slave { continue };
-                let modbus_channel = ModbusRtuChannel::new(modbus_config, register_maps, aggregation_tx.clone());
-                channel_handles.push(modbus_channel.run());
+            },
+            Err(e) => {
+                log::error!("Error reading file path: {:?}, {:?}", register_map_path, e );
             }
         }
     }
+    Some(register_maps)
+}
 
-    log::debug!("Loaded Configs with their hashes: {:?}", state.get_configured_hashes());
-    // For testing purposes...
-    // let modbus_raw = state.read_file("./dist/modbus.yml".to_string()).unwrap();
-    // let modbus_config = ModbusClientTcpConfig::serialize(modbus_raw).unwrap();
-
-    // l
+fn log_channel_states(controller_tx: &Sender<ControllerAction>) {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    if controller_tx.send(ControllerAction::Status(reply_tx)).is_err() {
+        log::error!("Channel controller is not running");
+        return;
+    }
+    match reply_rx.recv_timeout(Duration::from_secs(10)) {
+        Ok(states) => for state in states {
+            log::info!("Channel {}: {:?}, restarts: {}, last error: {:?}", state.name, state.status, state.restarts, state.last_error);
+        },
+        Err(e) => log::error!("Channel controller did not reply: {:?}", e)
+    }
+}
 
-    // let mqtt_config = config.mqtt.clone();
+fn open_storage(config: &MainConfig) -> Result<storage::SqliteStorage, rusqlite::Error> {
+    match &config.storage {
+        Storage::Sqlite { data_folder, .. } => storage::SqliteStorage::new(data_folder.clone())
+    }
+}
 
+/// Runs storage with its backup and truncation jobs, thread returns storage_rx
+/// after storage was closed so that it can be started again without losing actions
+fn run_storage(
+        config: MainConfig,
+        mut storage: storage::SqliteStorage,
+        storage_tx: Sender<storage::SqliteStorageAction>,
+        storage_rx: Receiver<storage::SqliteStorageAction>) -> JoinHandle<Receiver<storage::SqliteStorageAction>> {
+    thread::spawn(move || {
+        log::info!("Starting storage thread...");
+        let stop_jobs = Arc::new(AtomicBool::new(false));
+        let Storage::Sqlite { size_management, backup_management, .. } = config.storage.clone();
+        let backup_join = backup_local_fixed_window_scheduler(storage_tx, config.clone(), backup_management, size_management, stop_jobs.clone());
 
-    // Start MPSC channel that we can pass wto storage thread
+        while storage.process(&storage_rx) {}
+        storage.close();
 
-    // Start new storage thread
-    // This thread schould also schedule compactions
-    // Compaction is a process where we delete old data stored in local database
-    // it is essential to work properly. becouse a faulty compaction could couse that
-    // the system drive where this is running to be full. 
-    
-    storage_handle.join().unwrap();
-    aggregator_handle.join().unwrap();
-    transport_handle.join().unwrap();
-    // modbus_handle.join().unwrap();
+        stop_jobs.store(true, Ordering::SeqCst);
+        if backup_join.join().is_err() {
+            log::error!("Backup scheduler thread panicked");
+        }
+        storage_rx
+    })
 }
+
 fn truncate_fixed_window(storage_tx: Sender<storage::SqliteStorageAction>, config: MainConfig, messages_ttl_check: String, messeges_ttl: i32) -> JoinHandle<()> {
     thread::spawn(move || {
         let mut scheduler = job_scheduler::JobScheduler::new();
@@ -254,11 +436,11 @@ fn truncate_fixed_window(storage_tx: Sender<storage::SqliteStorageAction>, confi
 // TODO: 
 fn backup_local_fixed_window_scheduler(storage_tx: Sender<storage::SqliteStorageAction>,
         config: MainConfig,
-        backup_interval: String,
-        backup_ttl: i32,
-        backup_folder: String,
-        messages_ttl_check: String,
-        messages_ttl: i32) -> JoinHandle<()> {
+        backup_management: StorageBackupManagement,
+        size_management: StorageSizeManagement,
+        stop: Arc<AtomicBool>) -> JoinHandle<()> {
+    let StorageBackupManagement::Local { backup_folder, backup_interval, backup_ttl } = backup_management;
+    let StorageSizeManagement::FixedWindow { messages_ttl_check, messages_ttl } = size_management;
 
     let backup_storage_tx = storage_tx.clone();
     let backup_messages_ttl_clone = messages_ttl.clone();
@@ -341,7 +523,7 @@ fn backup_local_fixed_window_scheduler(storage_tx: Sender<storage::SqliteStorage
         scheduler.add(backup_job);
         scheduler.add(truncate_job);
 
-        loop {
+        while !stop.load(Ordering::SeqCst) {
             scheduler.tick();
             thread::sleep(Duration::from_millis(500));
         }
@@ -0,0 +1,231 @@
+use std::fs;
+
+use serde_json::{json, Value};
+
+use crate::channels::{ChannelConfig, DataPoint};
+use crate::channels::modbus::{ModbusClientRtuConfig, ModbusClientRtuSocketConfig, ModbusClientTcpConfig, ModbusGroupData, ModbusRegisterGroup, ModbusRegisterMap, ModbusSlave, ModbusSlaveId};
+use crate::channels::modbus::engine::{read_group, ModbusError, ModbusTransport};
+use crate::channels::modbus::socket::RtuSocketProtocol;
+use crate::channels::modbus::{rtu, socket, tcp};
+use crate::definitions::{ChannelType, OutputFormat};
+
+/// Register group read once, data points are parsed only when the group was read
+pub struct GroupReading<'a> {
+    pub section: &'static str, // attributes or timeseries
+    pub group: &'a ModbusRegisterGroup,
+    pub data: Result<ModbusGroupData, ModbusError>,
+    // Key name of every data point of the group, None when it could not be parsed
+    pub data_points: Vec<(String, Option<DataPoint>)>
+}
+
+/// Channel config of any modbus channel type, loaded by command line tools
+pub enum ChannelConfigFile {
+    Tcp(ModbusClientTcpConfig),
+    Rtu(ModbusClientRtuConfig),
+    Socket(RtuSocketProtocol, ModbusClientRtuSocketConfig)
+}
+
+impl ChannelConfigFile {
+    /// Channel type is guessed from the config when it is not set
+    pub fn load(channel_file: &str, channel_type: Option<ChannelType>) -> Result<Self, String> {
+        let raw = fs::read_to_string(channel_file).map_err(|e| format!("Could not read {}: {}", channel_file, e))?;
+        let channel_type = channel_type.unwrap_or_else(|| guess_channel_type(&raw));
+        let config = match channel_type {
+            ChannelType::ModbusTcp => ModbusClientTcpConfig::serialize(raw).map(ChannelConfigFile::Tcp),
+            ChannelType::ModbusRtu => ModbusClientRtuConfig::serialize(raw).map(ChannelConfigFile::Rtu),
+            ChannelType::ModbusRtuOverTcp => ModbusClientRtuSocketConfig::serialize(raw).map(|config| ChannelConfigFile::Socket(RtuSocketProtocol::Tcp, config)),
+            ChannelType::ModbusUdp => ModbusClientRtuSocketConfig::serialize(raw).map(|config| ChannelConfigFile::Socket(RtuSocketProtocol::Udp, config)),
+            ChannelType::ModbusTcpServer => return Err(format!("{} is a server channel, it has no slaves", channel_file))
+        };
+        config.map_err(|e| format!("Error in channel config {}: {}", channel_file, e))
+    }
+
+    pub fn slaves(&self) -> &[ModbusSlave] {
+        match self {
+            ChannelConfigFile::Tcp(config) => &config.slaves,
+            ChannelConfigFile::Rtu(config) => &config.slaves,
+            ChannelConfigFile::Socket(_, config) => &config.slaves
+        }
+    }
+
+    pub fn set_response_timeout(&mut self, millis: u64) {
+        match self {
+            ChannelConfigFile::Tcp(config) => config.response_timeout = Some(millis),
+            ChannelConfigFile::Rtu(config) => config.response_timeout = Some(millis),
+            ChannelConfigFile::Socket(_, config) => config.response_timeout = Some(millis)
+        }
+    }
+
+    /// Transport of the channel, TCP slaves may have their own endpoint
+    pub fn open_transport(&self, slave: Option<&ModbusSlave>) -> Result<Box<dyn ModbusTransport>, String> {
+        match self {
+            ChannelConfigFile::Tcp(config) => tcp::open_transport(config, slave),
+            ChannelConfigFile::Rtu(config) => rtu::open_transport(config),
+            ChannelConfigFile::Socket(protocol, config) => Ok(Box::new(socket::open_transport(*protocol, config)))
+        }
+    }
+}
+
+/// Runs `read` subcommand: reads every register group of the device once and prints
+/// parsed data points with raw data. Returns exit code, 1 when no group could be read
+pub fn run(channel_file: &str, device: &str, channel_type: Option<ChannelType>, format: OutputFormat) -> i32 {
+    let (slave, register_map, mut transport) = match open_device(channel_file, device, channel_type) {
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let readings = read_groups(transport.as_mut(), slave.modbus_id, &register_map);
+    transport.end_cycle();
+    match format {
+        OutputFormat::Table => print!("{}", render_table(&slave, &readings)),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&render_json(&slave, &readings)).unwrap())
+    }
+    if readings.iter().any(|reading| reading.data.is_ok()) { 0 } else { 1 }
+}
+
+/// Loads register map of the device and prepares transport to it
+fn open_device(channel_file: &str, device: &str, channel_type: Option<ChannelType>) -> Result<(ModbusSlave, ModbusRegisterMap, Box<dyn ModbusTransport>), String> {
+    let config = ChannelConfigFile::load(channel_file, channel_type)?;
+    let slave = config.slaves().iter()
+        .find(|slave| slave.device_name == device)
+        .cloned()
+        .ok_or_else(|| format!("Device {} is not configured in {}", device, channel_file))?;
+    let transport = config.open_transport(Some(&slave))?;
+    let register_map = fs::read_to_string(&slave.register_map)
+        .map_err(|e| format!("Could not read {}: {}", slave.register_map, e))
+        .and_then(|raw| ModbusRegisterMap::from_yaml(&raw).map_err(|e| format!("Error in register map {}: {}", slave.register_map, e)))?;
+    Ok((slave, register_map, transport))
+}
+
+/// Serial channels are the only ones with baudrate, sockets of RTU converters have to be set with --type
+fn guess_channel_type(raw: &str) -> ChannelType {
+    match serde_yaml::from_str::<Value>(raw) {
+        Ok(config) if config.get("baudrate").is_some() => ChannelType::ModbusRtu,
+        _ => ChannelType::ModbusTcp
+    }
+}
+
+/// Reads attribute and timeseries groups of the register map one after another
+pub fn read_groups<'a, T: ModbusTransport + ?Sized>(transport: &mut T, modbus_id: ModbusSlaveId, register_map: &'a ModbusRegisterMap) -> Vec<GroupReading<'a>> {
+    let groups = register_map.attributes.iter().map(|group| ("attributes", group))
+        .chain(register_map.timeseries.iter().map(|group| ("timeseries", group)));
+    groups.map(|(section, group)| {
+        let data = read_group(transport, modbus_id, group);
+        let data_points = group.data_points.iter()
+            .map(|reader| (reader.key_name.clone(), data.as_ref().ok().and_then(|data| reader.parse_data(data))))
+            .collect();
+        GroupReading { section, group, data, data_points }
+    }).collect()
+}
+
+pub fn render_table(slave: &ModbusSlave, readings: &[GroupReading]) -> String {
+    let width = readings.iter()
+        .flat_map(|reading| reading.data_points.iter().map(|(key, _)| key.len()))
+        .max()
+        .unwrap_or(0);
+    let mut table = format!("{} (modbus_id {})\n", slave.device_name, slave.modbus_id);
+    for reading in readings {
+        let group = reading.group;
+        table.push_str(&format!("\n{}: function {}, address {}, count {}\n", reading.section, group.function.code(), group.starting_address, group.elements_count));
+        match &reading.data {
+            Ok(ModbusGroupData::Registers(registers)) => {
+                let raw: Vec<String> = registers.iter().map(|register| format!("{:04X}", register)).collect();
+                table.push_str(&format!("  raw: {}\n", raw.join(" ")));
+            },
+            Ok(ModbusGroupData::Bits(bits)) => {
+                let raw: String = bits.iter().map(|bit| if *bit { '1' } else { '0' }).collect();
+                table.push_str(&format!("  raw: {}\n", raw));
+            },
+            Err(e) => {
+                table.push_str(&format!("  error: {}\n", e));
+                continue;
+            }
+        }
+        for (key, point) in &reading.data_points {
+            match point {
+                Some(point) => table.push_str(format!("  {:width$}  {} {}\n", key, point.value, point.unit.as_deref().unwrap_or(""), width = width).trim_end()),
+                None => table.push_str(&format!("  {:width$}  could not be parsed", key, width = width))
+            }
+            table.push('\n');
+        }
+    }
+    table
+}
+
+pub fn render_json(slave: &ModbusSlave, readings: &[GroupReading]) -> Value {
+    let groups: Vec<Value> = readings.iter().map(|reading| {
+        let (raw, error) = match &reading.data {
+            Ok(ModbusGroupData::Registers(registers)) => (json!(registers), Value::Null),
+            Ok(ModbusGroupData::Bits(bits)) => (json!(bits), Value::Null),
+            Err(e) => (Value::Null, json!(e.to_string()))
+        };
+        let data_points: Vec<Value> = reading.data_points.iter().map(|(key, point)| json!({
+            "key": key,
+            "value": point.as_ref().map(|point| json!(point.value)),
+            "unit": point.as_ref().and_then(|point| point.unit.clone())
+        })).collect();
+        json!({
+            "section": reading.section,
+            "function": reading.group.function.code(),
+            "starting_address": reading.group.starting_address,
+            "elements_count": reading.group.elements_count,
+            "raw": raw,
+            "error": error,
+            "data_points": data_points
+        })
+    }).collect();
+    json!({
+        "device": slave.device_name,
+        "modbus_id": slave.modbus_id,
+        "groups": groups
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::{read_groups, render_json, render_table, guess_channel_type};
+    use crate::channels::modbus::{ModbusRegisterMap, ModbusSlave, ModbusSlaveId};
+    use crate::channels::modbus::engine::{ModbusError, ModbusTransport};
+    use crate::definitions::ChannelType;
+
+    // Answers reads of holding registers starting at 0, others time out
+    struct FakeTransport;
+
+    impl ModbusTransport for FakeTransport {
+        fn transact(&mut self, _modbus_id: ModbusSlaveId, request: &[u8]) -> Result<Vec<u8>, ModbusError> {
+            match request {
+                [3, 0, 0, 0, 3] => Ok(vec![3, 6, 0x43, 0x66, 0x00, 0x00, 0x00, 0x07]),
+                _ => Err(ModbusError::Timeout)
+            }
+        }
+    }
+
+    #[test]
+    fn device_is_read_once() {
+        let register_map = ModbusRegisterMap::from_yaml(r#"
+            timeseries_points:
+              - { address: 0, data_type: float, key_name: Voltage, unit: V }
+              - { address: 2, data_type: uint16, key_name: Status }
+              - { address: 100, data_type: uint16, key_name: Energy }
+        "#).unwrap();
+        let slave: ModbusSlave = serde_yaml::from_str("{ device_name: Meter1, modbus_id: 1, register_map: map.yml }").unwrap();
+        let readings = read_groups(&mut FakeTransport, 1, &register_map);
+
+        assert_eq!(render_table(&slave, &readings), "Meter1 (modbus_id 1)\n\
+            \ntimeseries: function 3, address 0, count 3\n  raw: 4366 0000 0007\n  Voltage  230 V\n  Status   7\n\
+            \ntimeseries: function 3, address 100, count 1\n  error: No response within timeout\n");
+        let json = render_json(&slave, &readings);
+        assert_eq!(json["groups"][0]["raw"], json!([0x4366, 0, 7]));
+        assert_eq!(json["groups"][0]["data_points"][0], json!({"key": "Voltage", "value": 230.0, "unit": "V"}));
+        assert_eq!(json["groups"][1]["error"], json!("No response within timeout"));
+    }
+
+    #[test]
+    fn serial_channel_is_recognized() {
+        assert_eq!(guess_channel_type("port: /dev/ttyS1\nbaudrate: 9600"), ChannelType::ModbusRtu);
+        assert_eq!(guess_channel_type("host: 127.0.0.1\nport: 502"), ChannelType::ModbusTcp);
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::Write;
+
+use serde_json::{json, Value};
+
+use crate::channels::modbus::ModbusSlaveId;
+use crate::channels::modbus::engine::exception_name;
+use crate::channels::modbus::scan::{object_name, scan_slaves, DiscoveredSlave, ProbeAnswer, ScanProbe};
+use crate::definitions::{ChannelType, OutputFormat};
+use crate::read::ChannelConfigFile;
+
+/// Slave ids and request of the `scan` subcommand
+pub struct ScanOptions {
+    pub from: ModbusSlaveId,
+    pub to: ModbusSlaveId,
+    pub probe: ScanProbe,
+    pub address: u16,
+    pub timeout: Option<u64>
+}
+
+/// Runs `scan` subcommand on the line or endpoint of the channel, progress is printed to stderr.
+/// Returns exit code, 1 when no slave answered
+pub fn run(channel_file: &str, channel_type: Option<ChannelType>, options: ScanOptions, format: OutputFormat) -> i32 {
+    let mut config = match ChannelConfigFile::load(channel_file, channel_type) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    if let Some(timeout) = options.timeout {
+        config.set_response_timeout(timeout);
+    }
+    let mut transport = match config.open_transport(None) {
+        Ok(transport) => transport,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let progress = |modbus_id| {
+        eprint!("\rProbing slave id {} of {}-{}", modbus_id, options.from, options.to);
+        std::io::stderr().flush().ok();
+    };
+    let result = scan_slaves(transport.as_mut(), options.from..=options.to, options.probe, options.address, progress);
+    eprintln!();
+    let discovered = match result {
+        Ok(discovered) => discovered,
+        Err(e) => {
+            eprintln!("Scan stopped: {}", e);
+            return 1;
+        }
+    };
+    match format {
+        OutputFormat::Table => print!("{}", render_table(&discovered)),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&render_json(&discovered)).unwrap())
+    }
+    if discovered.is_empty() { 1 } else { 0 }
+}
+
+pub fn render_table(discovered: &[DiscoveredSlave]) -> String {
+    let mut table = String::new();
+    for slave in discovered {
+        let mut line = format!("{:>3}: {}", slave.modbus_id, describe_answer(&slave.answer));
+        for (id, value) in &slave.identification {
+            line.push_str(&format!(", {} {}", object_name(*id), value));
+        }
+        table.push_str(&line);
+        table.push('\n');
+    }
+    table.push_str(&format!("{} slaves answered\n", discovered.len()));
+    table
+}
+
+pub fn render_json(discovered: &[DiscoveredSlave]) -> Value {
+    let slaves: Vec<Value> = discovered.iter().map(|slave| {
+        let identification: serde_json::Map<String, Value> = slave.identification.iter()
+            .map(|(id, value)| (object_name(*id), json!(value)))
+            .collect();
+        json!({
+            "modbus_id": slave.modbus_id,
+            "answer": describe_answer(&slave.answer),
+            "identification": identification
+        })
+    }).collect();
+    json!(slaves)
+}
+
+fn describe_answer(answer: &ProbeAnswer) -> String {
+    match answer {
+        ProbeAnswer::Data => String::from("answered"),
+        ProbeAnswer::Exception(code) => format!("answered with exception {}: {}", code, exception_name(*code)),
+        ProbeAnswer::Garbled(e) => format!("garbled answer: {}", e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::render_table;
+    use crate::channels::modbus::scan::{DiscoveredSlave, ProbeAnswer};
+
+    #[test]
+    fn discovered_slaves_are_listed() {
+        let mut identification = BTreeMap::new();
+        identification.insert(0, String::from("Acme"));
+        identification.insert(1, String::from("X100"));
+        let discovered = vec![
+            DiscoveredSlave { modbus_id: 2, answer: ProbeAnswer::Data, identification },
+            DiscoveredSlave { modbus_id: 17, answer: ProbeAnswer::Exception(2), identification: BTreeMap::new() }
+        ];
+        assert_eq!(render_table(&discovered), "  2: answered, vendor_name Acme, product_code X100\n 17: answered with exception 2: Illegal data address\n2 slaves answered\n");
+    }
+}
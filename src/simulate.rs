@@ -0,0 +1,127 @@
+use std::fs;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use crate::channels::modbus::server::{serve_rtu, serve_tcp, ModbusServerUnits};
+use crate::channels::modbus::simulator::{open_pseudo_terminal, Simulator, SimulatorConfig, DEFAULT_UPDATE_INTERVAL};
+
+/// Runs `simulate` subcommand: serves simulated units over Modbus TCP and RTU on a pseudo-terminal
+/// until it is interrupted. Returns exit code, 1 when the simulator could not be started
+pub fn run(config_file: &str) -> i32 {
+    let config = match fs::read_to_string(config_file)
+        .map_err(|e| format!("Could not read {}: {}", config_file, e))
+        .and_then(|raw| serde_yaml::from_str::<SimulatorConfig>(&raw).map_err(|e| format!("Error in simulator config {}: {}", config_file, e))) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    if config.tcp.is_none() && config.rtu.is_none() {
+        eprintln!("Nothing to serve, simulator config {} has neither tcp nor rtu", config_file);
+        return 1;
+    }
+    let mut simulator = match Simulator::load(&config) {
+        Ok(simulator) => simulator,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let tables = match simulator.data_tables() {
+        Ok(tables) => tables,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let mut ids: Vec<_> = tables.keys().copied().collect();
+    ids.sort_unstable();
+    let units: ModbusServerUnits = Arc::new(Mutex::new(tables));
+
+    let running = Arc::new(AtomicBool::new(true));
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, stop.clone()).unwrap();
+    }
+
+    if let Some(tcp) = &config.tcp {
+        let listener = match TcpListener::bind((tcp.host.as_str(), tcp.port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Could not listen on {}:{}: {}", tcp.host, tcp.port, e);
+                return 1;
+            }
+        };
+        println!("Serving units {:?} over Modbus TCP on {}:{}", ids, tcp.host, tcp.port);
+        let (units, running) = (units.clone(), running.clone());
+        thread::spawn(move || {
+            if let Err(e) = serve_tcp(listener, units, running) {
+                eprintln!("Modbus TCP server stopped: {}", e);
+            }
+        });
+    }
+
+    let mut link = None;
+    if let Some(rtu) = &config.rtu {
+        let terminal = match open_pseudo_terminal() {
+            Ok(terminal) => terminal,
+            Err(e) => {
+                eprintln!("Could not open pseudo-terminal: {}", e);
+                return 1;
+            }
+        };
+        if let Some(path) = &rtu.link {
+            if let Err(e) = link_terminal(&terminal.path, path) {
+                eprintln!("Could not link {} to {}: {}", path, terminal.path, e);
+                return 1;
+            }
+            link = Some(path.clone());
+        }
+        println!("Serving units {:?} over Modbus RTU on {}", ids, rtu.link.as_deref().unwrap_or(&terminal.path));
+        let units = units.clone();
+        thread::spawn(move || {
+            // Slave side of the terminal has to stay open while it is served
+            let mut terminal = terminal;
+            if let Err(e) = serve_rtu(&mut terminal.master, &units) {
+                eprintln!("Modbus RTU server stopped: {}", e);
+            }
+        });
+    }
+
+    let interval = Duration::from_millis(config.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL));
+    let started = Instant::now();
+    let mut next_update = started + interval;
+    let mut code = 0;
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(next_update.saturating_duration_since(Instant::now()).min(Duration::from_millis(200)));
+        if Instant::now() < next_update {
+            continue;
+        }
+        next_update += interval;
+        let mut tables = units.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = simulator.update(&mut tables, started.elapsed()) {
+            eprintln!("{}", e);
+            code = 1;
+            break;
+        }
+    }
+    running.store(false, Ordering::Relaxed);
+    if let Some(path) = link {
+        fs::remove_file(path).ok();
+    }
+    code
+}
+
+/// Replaces an old link of a previous run, other files are not removed
+fn link_terminal(terminal: &str, path: &str) -> std::io::Result<()> {
+    if fs::symlink_metadata(path).map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false) {
+        fs::remove_file(path)?;
+    }
+    std::os::unix::fs::symlink(terminal, path)
+}
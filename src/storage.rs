@@ -14,12 +14,10 @@ use zstd;
 
 use crate::definitions::{Storage, StorageSizeManagement};
 
-// fn insert_message(message: Value, ts: Option<i64> , device_name: &str, con: &Connection) -> SqliteResult<> {
-
-// }
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Insert {
+    // Row of the stored message, None until it was stored
+    pub rowid: Option<i64>,
     pub ts: i64,
     pub device_name: String,
     // In the final form, that we can store in DB
@@ -34,17 +32,21 @@ pub enum SqliteStorageTruncate {
 pub enum SqliteStorageAction {
     InsertAttributes(Insert),
     InsertTimeseries(Insert),
-    InsertBoth(Insert),
+    // Stored insert with its rowid is sent back to reply, rowid stays None when it could not be stored
+    InsertBoth {insert: Insert, reply: mpsc::Sender<Insert>},
     CloseDB,
     // BackupDB need a string  that is the destination of backup db
     BackupDB(String),
     Truncate(SqliteStorageTruncate), // Start 
+    // Marks message with rowid as delivered to transport
+    Acknowledge(i64),
+    // Undelivered messages older than `before`, oldest first
+    SelectUndelivered {before: i64, limit: u32, reply: mpsc::Sender<Vec<Insert>>},
     Timeout
 }
 
 pub struct SqliteStorage {
     connection: Connection,
-    rx: mpsc::Receiver<SqliteStorageAction>,
     data_dir: PathBuf
 }
 
@@ -52,10 +54,7 @@ impl SqliteStorage{
 
     /// Expects parameter: 
     /// path: String  - path to folder where it will store database and backups 
-    /// rx: mpsc::Receiver<SqliteStorageAction> - Receiver so that we can send Actions to do somethings
-    pub fn new(
-        data_folder: String,
-        rx: mpsc::Receiver<SqliteStorageAction>) -> SqliteResult<Self> {
+    pub fn new(data_folder: String) -> SqliteResult<Self> {
         let mut data_path = PathBuf::new();
         let mut data_dir = PathBuf::new();
         let path = data_folder;
@@ -84,30 +83,35 @@ impl SqliteStorage{
         // }
 
         let con = Connection::open(data_path)?;
-        match con.execute(r#"CREATE TABLE IF NOT EXISTS 
-            messages(ts INTEGER, device_name TEXT, timeseries_message TEXT, attributes_message TEXT)"#, []) {
-                Ok(modified_rows) => {
-                    log::debug!("Created table \"messages\" in database! Affected rows: {}", modified_rows);
-                },
-                Err(e) => {
-                    log::error!("Could not create table to store messages with rusqlite, Error: {:?}", e);
-                }
+        match create_messages_table(&con) {
+            Ok(_) => log::debug!("Created table \"messages\" in database!"),
+            Err(e) => {
+                log::error!("Could not create table to store messages with rusqlite, Error: {:?}", e);
             }
+        }
         
         Ok(Self {
             connection: con,
-            rx,
             data_dir
         })
     }
 
     /// TABLE COLUMNS:
-    ///    ts: i64 | device_name: TEXT | timeseries_message | attributes_message
-
-    pub fn process(&mut self) {
-        match self.rx.recv().unwrap() {
+    ///    ts: i64 | device_name: TEXT | timeseries_message | attributes_message | delivered: 0/1
+    ///
+    /// Processes one action from rx, returns false once the database was closed or all senders were dropped
+    /// Receiver is owned by the caller, so that actions are kept when storage is restarted
+    pub fn process(&mut self, rx: &mpsc::Receiver<SqliteStorageAction>) -> bool {
+        let action = match rx.recv() {
+            Ok(action) => action,
+            Err(_) => {
+                log::info!("Storage actions are no longer sent, closing DB...");
+                return false;
+            }
+        };
+        match action {
 
-            SqliteStorageAction::InsertBoth(insert) => {
+            SqliteStorageAction::InsertBoth { mut insert, reply } => {
                 
                 log::debug!("Inserting message: {:?} to Database", insert);
                 
@@ -115,16 +119,11 @@ impl SqliteStorage{
                     Ok(mut t) => {
                         t.set_drop_behavior(rusqlite::DropBehavior::Commit);
                         
-                        match t.execute(r#"INSERT INTO messages
-                            (ts, device_name, timeseries_message, attributes_message)
-                            VALUES(?1, ?2, ?3, ?4)"#, params![
-                                 insert.ts,
-                                 insert.device_name,
-                                 // These are safe unwraps, at least i hope so
-                                 insert.timeseries_message.unwrap(),
-                                 insert.attributes_message.unwrap()
-                             ]) {
-                                 Ok(_rows_affected) => log::debug!("Wrote {} rows to DB", _rows_affected),
+                        match insert_message(&t, &insert) {
+                                 Ok(_rows_affected) => {
+                                     log::debug!("Wrote {} rows to DB", _rows_affected);
+                                     insert.rowid = Some(t.last_insert_rowid());
+                                 },
                                  Err(e) => log::error!("Error executing SQL: {:?}", e)
                              };
 
@@ -138,6 +137,9 @@ impl SqliteStorage{
                     },
                     Err(e) => log::error!("SqliteTransactionBegin Error: {:?}", e)
                 };
+                if reply.send(insert).is_err() {
+                    log::error!("Error replying with stored message, aggregator is not waiting for it");
+                }
 
             },
             SqliteStorageAction::BackupDB(path) => {
@@ -156,18 +158,93 @@ impl SqliteStorage{
                     }
                 }
             }
+            SqliteStorageAction::Acknowledge(rowid) => {
+                match acknowledge_message(&self.connection, rowid) {
+                    Ok(_) => log::trace!("Message {} was delivered", rowid),
+                    Err(e) => log::error!("Error acknowledging message {}: {:?}", rowid, e)
+                }
+            },
+            SqliteStorageAction::SelectUndelivered { before, limit, reply } => {
+                let undelivered = match select_undelivered(&self.connection, before, limit) {
+                    Ok(undelivered) => undelivered,
+                    Err(e) => {
+                        log::error!("Error selecting undelivered messages: {:?}", e);
+                        vec![]
+                    }
+                };
+                if let Err(e) = reply.send(undelivered) {
+                    log::error!("Error replying with undelivered messages: {:?}", e);
+                }
+            },
             SqliteStorageAction::CloseDB => {
                 log::info!("Closing DB...");
-
+                return false;
             },
             SqliteStorageAction::Timeout => {
                 log::trace!("Recv timed out");
             },
             _ => {}
         };
+        true
+    }
+
+    /// Every insert is commited right away, so only the connection is left to close
+    pub fn close(self) {
+        match self.connection.close() {
+            Ok(_) => log::info!("Closed database"),
+            Err((_, e)) => log::error!("Error closing database: {:?}", e)
+        }
     }
 }
 
+/// Creates messages table, databases created before delivery tracking
+/// get the `delivered` column with their old rows treated as delivered
+pub fn create_messages_table(con: &Connection) -> SqliteResult<()> {
+    con.execute(r#"CREATE TABLE IF NOT EXISTS 
+        messages(ts INTEGER, device_name TEXT, timeseries_message TEXT, attributes_message TEXT, delivered INTEGER NOT NULL DEFAULT 1)"#, [])?;
+    let has_delivered: i64 = con.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('messages') WHERE name = 'delivered'", [], |row| row.get(0))?;
+    if has_delivered == 0 {
+        log::info!("Adding delivery status to messages table...");
+        con.execute("ALTER TABLE messages ADD COLUMN delivered INTEGER NOT NULL DEFAULT 1", [])?;
+    }
+    con.execute("CREATE INDEX IF NOT EXISTS messages_delivered ON messages(delivered, ts)", [])?;
+    Ok(())
+}
+
+/// Inserts new message, it stays undelivered until acknowledged by transport
+pub fn insert_message(con: &Connection, insert: &Insert) -> SqliteResult<usize> {
+    con.execute(r#"INSERT INTO messages
+        (ts, device_name, timeseries_message, attributes_message, delivered)
+        VALUES(?1, ?2, ?3, ?4, 0)"#, params![
+            insert.ts,
+            insert.device_name,
+            insert.timeseries_message,
+            insert.attributes_message
+        ])
+}
+
+pub fn acknowledge_message(con: &Connection, rowid: i64) -> SqliteResult<usize> {
+    con.execute("UPDATE messages SET delivered = 1 WHERE rowid = ?1", params![rowid])
+}
+
+pub fn select_undelivered(con: &Connection, before: i64, limit: u32) -> SqliteResult<Vec<Insert>> {
+    let mut statement = con.prepare(r#"
+        SELECT rowid, ts, device_name, timeseries_message, attributes_message FROM messages
+        WHERE delivered = 0 AND ts < ?1 ORDER BY ts, rowid LIMIT ?2
+    "#)?;
+    let rows = statement.query_map(params![before, limit], |row| {
+        Ok(Insert {
+            rowid: Some(row.get(0)?),
+            ts: row.get(1)?,
+            device_name: row.get(2)?,
+            timeseries_message: row.get(3)?,
+            attributes_message: row.get(4)?
+        })
+    })?;
+    rows.collect()
+}
+
 pub fn truncate_fixed_window(con: &Connection, older_than: chrono::Duration) -> SqliteResult<()> {
     let now = Utc::now();
     let old = now - older_than;
@@ -221,4 +298,62 @@ pub fn backup_db(
             Err(e)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use super::{create_messages_table, insert_message, acknowledge_message, select_undelivered, Insert};
+
+    fn insert(ts: i64, device_name: &str) -> Insert {
+        Insert {
+            rowid: None,
+            ts,
+            device_name: device_name.to_string(),
+            timeseries_message: Some(format!("{{\"{}\": []}}", device_name)),
+            attributes_message: Some(format!("{{\"{}\": {{}}}}", device_name))
+        }
+    }
+
+    fn stored(rowid: i64, insert: Insert) -> Insert {
+        Insert { rowid: Some(rowid), ..insert }
+    }
+
+    #[test]
+    fn replay_undelivered_in_order() {
+        let con = Connection::open_in_memory().unwrap();
+        create_messages_table(&con).unwrap();
+        insert_message(&con, &insert(3, "Meter1")).unwrap();
+        insert_message(&con, &insert(1, "Meter1")).unwrap();
+        insert_message(&con, &insert(2, "Meter2")).unwrap();
+        insert_message(&con, &insert(10, "Meter2")).unwrap();
+        acknowledge_message(&con, 2).unwrap();
+
+        let undelivered = select_undelivered(&con, 10, 100).unwrap();
+        assert_eq!(undelivered, vec![stored(3, insert(2, "Meter2")), stored(1, insert(3, "Meter1"))]);
+        assert_eq!(select_undelivered(&con, 10, 1).unwrap(), vec![stored(3, insert(2, "Meter2"))]);
+    }
+
+    #[test]
+    fn messages_with_same_ts_are_acknowledged_separately() {
+        let con = Connection::open_in_memory().unwrap();
+        create_messages_table(&con).unwrap();
+        insert_message(&con, &insert(1, "Meter1")).unwrap();
+        let first = con.last_insert_rowid();
+        insert_message(&con, &insert(1, "Meter1")).unwrap();
+        acknowledge_message(&con, first).unwrap();
+
+        assert_eq!(select_undelivered(&con, 10, 100).unwrap(), vec![stored(first + 1, insert(1, "Meter1"))]);
+    }
+
+    #[test]
+    fn old_messages_table_is_migrated() {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute("CREATE TABLE messages(ts INTEGER, device_name TEXT, timeseries_message TEXT, attributes_message TEXT)", []).unwrap();
+        con.execute("INSERT INTO messages VALUES(1, 'Meter1', '{}', '{}')", []).unwrap();
+        create_messages_table(&con).unwrap();
+        insert_message(&con, &insert(2, "Meter1")).unwrap();
+
+        assert_eq!(select_undelivered(&con, 10, 100).unwrap(), vec![stored(2, insert(2, "Meter1"))]);
+    }
+}
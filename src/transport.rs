@@ -1,20 +1,40 @@
-use std::sync::mpsc::{Sender, Receiver};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
 use std::thread::JoinHandle;
 use std::thread;
-use std::time::Duration;
-use crate::definitions::{TransportAction, MainConfig};
-use crate::storage::SqliteStorageAction;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use serde_json::{json, Value};
+use crate::channels::{ChannelAction, DeviceRoutes};
+use crate::definitions::{TransportAction, MainConfig, RpcRequest};
+use crate::storage::{Insert, SqliteStorageAction};
 
 const TB_DEVICE_ATTRIBUTES_TOPIC: &str = "v1/gateway/attributes";
 const TB_DEVICE_TELEMETRI_TOPIC: &str = "v1/gateway/telemetry";
-// const TB_DEVICE_CONNECT_TOPIC: &str = "v1/gateway/connect";
+const TB_GATEWAY_RPC_TOPIC: &str = "v1/gateway/rpc";
+const TB_DEVICE_CONNECT_TOPIC: &str = "v1/gateway/connect";
+const TB_DEVICE_DISCONNECT_TOPIC: &str = "v1/gateway/disconnect";
+
+// Messages per second replayed from storage when `replay_rate` is not set
+const DEFAULT_REPLAY_RATE: u32 = 10;
+// Wait after failed publish before messages are replayed from storage
+const REPLAY_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+// Storage may not be running eg: while it is restarted with new configuration
+const STORAGE_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// How often is connection checked when there is nothing to send
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+// Broker that is down must not block the transport, unconfirmed messages stay undelivered in storage
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
 
 use paho_mqtt as mqtt;
 
 pub struct MqttTransport {
     pub config: MainConfig,
     pub storage_tx: Sender<SqliteStorageAction>,
-    pub transport_rx: Receiver<TransportAction>
+    pub transport_rx: Receiver<TransportAction>,
+    pub device_routes: DeviceRoutes
 }
 
 
@@ -22,15 +42,19 @@ impl MqttTransport {
     pub fn new(
             config: MainConfig,
             storage_tx: Sender<SqliteStorageAction>,
-            transport_rx: Receiver<TransportAction>
+            transport_rx: Receiver<TransportAction>,
+            device_routes: DeviceRoutes
         ) -> Self {
         Self {
             config,
             storage_tx,
-            transport_rx
+            transport_rx,
+            device_routes
         }
     }
-    pub fn run(self) -> JoinHandle<()> {
+    /// Thread returns transport_rx after it was stopped, so that transport
+    /// can be started again with new configuration without losing actions
+    pub fn run(self) -> JoinHandle<Receiver<TransportAction>> {
 
         let qos = match self.config.mqtt.qos {
             0 => 0_i32,
@@ -55,11 +79,42 @@ impl MqttTransport {
             connection_options.user_name(token);
         }
         let connection_options = connection_options.finalize();
+        let replay_rate = self.config.mqtt.replay_rate.unwrap_or(DEFAULT_REPLAY_RATE).max(1);
 
+        let device_routes = self.device_routes.clone();
+        // Device name -> connect message of devices that are currently connected,
+        // broker forgets them with clean session so they are announced again after reconnect
+        let connected_devices: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+        // Set on every (re)connect, messages stored during outage are replayed afterwards
+        let reconnected = Arc::new(AtomicBool::new(false));
         let handle = thread::spawn(move || {
-            let client = mqtt::AsyncClient::new(client_options).unwrap_or_else(|e| {
-                log::error!("Error creating MQTT Client: {:?}", e);
-                panic!("Error creating MQTT Client: {:?}", e)
+            let mut client = match mqtt::AsyncClient::new(client_options) {
+                Ok(client) => client,
+                Err(e) => {
+                    log::error!("Error creating MQTT Client: {:?}", e);
+                    return self.transport_rx;
+                }
+            };
+            // Session is clean, so we have to subscribe after every (re)connect
+            let announced_devices = connected_devices.clone();
+            let reconnected_flag = reconnected.clone();
+            client.set_connected_callback(move |cli| {
+                log::info!("Connected to mqtt broker, subscribing to: {}", TB_GATEWAY_RPC_TOPIC);
+                reconnected_flag.store(true, Ordering::SeqCst);
+                cli.subscribe(TB_GATEWAY_RPC_TOPIC, qos);
+                match announced_devices.lock() {
+                    Ok(devices) => for connect_message in devices.values() {
+                        cli.publish(mqtt::Message::new(TB_DEVICE_CONNECT_TOPIC, connect_message.as_bytes(), qos));
+                    },
+                    Err(e) => log::error!("Connected devices are poisoned: {:?}", e)
+                }
+            });
+            client.set_message_callback(move |cli, msg| {
+                if let Some(msg) = msg {
+                    if msg.topic() == TB_GATEWAY_RPC_TOPIC {
+                        route_rpc_request(cli, &device_routes, &msg.payload_str(), qos);
+                    }
+                }
             });
             // TODO: Implement other topics to use eg: RPC request topics
             // let mut client_clone = client.clone();
@@ -69,8 +124,28 @@ impl MqttTransport {
             // }
             while let Err(ct) = client.connect(connection_options.clone()).wait() {
                 log::error!("Could not connect to mqtt broker: {:?}", ct);
-                thread::sleep(Duration::from_secs(10));
+                // Wait before next attempt, transport can still be stopped in the meantime
+                match self.transport_rx.recv_timeout(Duration::from_secs(10)) {
+                    Ok(TransportAction::Stop) => return self.transport_rx,
+                    Ok(TransportAction::SendConnect(connect_message)) => {
+                        // Announced after connecting
+                        if let (Some(device_name), Ok(mut devices)) = (message_device(&connect_message), connected_devices.lock()) {
+                            devices.insert(device_name, connect_message);
+                        }
+                    },
+                    Ok(TransportAction::SendDisconnect(disconnect_message)) => {
+                        if let (Some(device_name), Ok(mut devices)) = (message_device(&disconnect_message), connected_devices.lock()) {
+                            devices.remove(&device_name);
+                        }
+                    },
+                    // Messages are stored and replayed after connecting
+                    Ok(_) => log::warn!("Not connected to mqtt broker, dropping transport action"),
+                    Err(_) => {}
+                }
             }
+            // Messages stored before this ts are replayed, None when there is nothing to replay
+            let mut replay_before: Option<i64> = None;
+            let mut next_replay = Instant::now();
             loop {
                 // if !client.is_connected() {
                 //     client.reconnect_with_callbacks(
@@ -81,27 +156,85 @@ impl MqttTransport {
                 //         log::error!("Could not reconnect to broker... {:?}", mqtt::error_message(e));
                 //     });
                 // }
-                match self.transport_rx.recv() {
+                if reconnected.swap(false, Ordering::SeqCst) {
+                    replay_before = Some(Utc::now().timestamp_millis());
+                    next_replay = Instant::now();
+                }
+                // Replay one batch at a time, so that live messages are not held back
+                if let Some(before) = replay_before {
+                    if Instant::now() >= next_replay && client.is_connected() {
+                        match replay_undelivered(&client, &self.storage_tx, before, replay_rate, qos) {
+                            Ok(0) => {
+                                log::info!("All stored messages were delivered");
+                                replay_before = None;
+                            },
+                            Ok(count) => log::info!("Replayed {} stored messages", count),
+                            Err(e) => {
+                                log::error!("Error replaying stored messages: {}", e);
+                                next_replay = Instant::now() + REPLAY_RETRY_INTERVAL;
+                            }
+                        }
+                    }
+                }
+                let timeout = match replay_before {
+                    Some(_) => next_replay.saturating_duration_since(Instant::now()),
+                    None => IDLE_INTERVAL
+                };
+                match self.transport_rx.recv_timeout(timeout) {
+                    Err(RecvTimeoutError::Timeout) => {},
                     Err(e) => log::error!("Receiver error: {:?}", e),
                     Ok(action) => match action {
-                        TransportAction::SendTimeseries(telemetry) => {
-                            let msg = mqtt::Message::new(TB_DEVICE_TELEMETRI_TOPIC, telemetry.as_bytes(), qos);
-                            match client.publish(msg).wait() {
-                                Ok(_) => log::debug!("Successfuly sent message!"),
-                                Err(e) => log::error!("Error sending message: {:?}", e)
+                        TransportAction::SendBoth(insert) => {
+                            match publish_insert(&client, &insert, qos) {
+                                Ok(_) => {
+                                    log::debug!("Successfuly sent message!");
+                                    acknowledge_insert(&self.storage_tx, &insert);
+                                },
+                                Err(e) => {
+                                    log::error!("Error sending message, it will be replayed from storage: {:?}", e);
+                                    replay_before = Some(Utc::now().timestamp_millis());
+                                    next_replay = Instant::now() + REPLAY_RETRY_INTERVAL;
+                                }
+                            }
+                        },
+                        TransportAction::SendRpcResponse(response) => {
+                            let msg = mqtt::Message::new(TB_GATEWAY_RPC_TOPIC, response.as_bytes(), qos);
+                            match client.publish(msg).wait_for(PUBLISH_TIMEOUT) {
+                                Ok(_) => log::debug!("Successfuly sent RPC response!"),
+                                Err(e) => log::error!("Error sending RPC response: {:?}", e)
                             }
-
                         },
-                        TransportAction::SendAttributes(attributes) => {
-                            let msg = mqtt::Message::new(TB_DEVICE_ATTRIBUTES_TOPIC, attributes.as_bytes(), qos);
-                            match client.publish(msg).wait() {
-                                Ok(_) => log::debug!("Successfuly sent message!"),
-                                Err(e) => log::error!("Error sending message: {:?}", e)
+                        TransportAction::SendConnect(connect_message) => {
+                            if let (Some(device_name), Ok(mut devices)) = (message_device(&connect_message), connected_devices.lock()) {
+                                devices.insert(device_name, connect_message.clone());
+                            }
+                            let msg = mqtt::Message::new(TB_DEVICE_CONNECT_TOPIC, connect_message.as_bytes(), qos);
+                            match client.publish(msg).wait_for(PUBLISH_TIMEOUT) {
+                                Ok(_) => log::debug!("Successfuly sent device connect!"),
+                                Err(e) => log::error!("Error sending device connect: {:?}", e)
                             }
                         },
+                        TransportAction::SendDisconnect(disconnect_message) => {
+                            if let (Some(device_name), Ok(mut devices)) = (message_device(&disconnect_message), connected_devices.lock()) {
+                                devices.remove(&device_name);
+                            }
+                            let msg = mqtt::Message::new(TB_DEVICE_DISCONNECT_TOPIC, disconnect_message.as_bytes(), qos);
+                            match client.publish(msg).wait_for(PUBLISH_TIMEOUT) {
+                                Ok(_) => log::debug!("Successfuly sent device disconnect!"),
+                                Err(e) => log::error!("Error sending device disconnect: {:?}", e)
+                            }
+                        },
+                        TransportAction::Stop => {
+                            log::info!("Stopping transport...");
+                            if let Err(e) = client.disconnect(None).wait() {
+                                log::error!("Error disconnecting from mqtt broker: {:?}", e);
+                            }
+                            break;
+                        },
                     }
                 }
             }
+            self.transport_rx
         });
             
 
@@ -110,4 +243,110 @@ impl MqttTransport {
 
         handle
     }
-}
\ No newline at end of file
+}
+
+/// Parses ThingsBoard gateway RPC request
+/// eg: {"device": "Meter1", "data": {"id": 1, "method": "setValue", "params": {...}}}
+pub fn parse_rpc_request(payload: &str) -> Option<RpcRequest> {
+    let value: Value = serde_json::from_str(payload).ok()?;
+    Some(RpcRequest {
+        device: value["device"].as_str()?.to_string(),
+        id: value["data"]["id"].as_i64()?,
+        method: value["data"]["method"].as_str()?.to_string(),
+        params: value["data"]["params"].clone()
+    })
+}
+
+/// Publishes messages of a single poll, message is delivered only when both were published
+fn publish_insert(client: &mqtt::AsyncClient, insert: &Insert, qos: i32) -> Result<(), mqtt::Error> {
+    if let Some(timeseries) = &insert.timeseries_message {
+        client.publish(mqtt::Message::new(TB_DEVICE_TELEMETRI_TOPIC, timeseries.as_bytes(), qos)).wait_for(PUBLISH_TIMEOUT)?;
+    }
+    if let Some(attributes) = &insert.attributes_message {
+        client.publish(mqtt::Message::new(TB_DEVICE_ATTRIBUTES_TOPIC, attributes.as_bytes(), qos)).wait_for(PUBLISH_TIMEOUT)?;
+    }
+    Ok(())
+}
+
+/// Messages that were not stored have no rowid, there is nothing to acknowledge
+fn acknowledge_insert(storage_tx: &Sender<SqliteStorageAction>, insert: &Insert) {
+    if let Some(rowid) = insert.rowid {
+        if let Err(e) = storage_tx.send(SqliteStorageAction::Acknowledge(rowid)) {
+            log::error!("Error acknowledging message to storage: {:?}", e);
+        }
+    }
+}
+
+/// Publishes up to `replay_rate` undelivered messages stored before `before`, oldest first,
+/// spread over a second. Returns number of replayed messages, 0 when all were delivered
+fn replay_undelivered(client: &mqtt::AsyncClient, storage_tx: &Sender<SqliteStorageAction>, before: i64, replay_rate: u32, qos: i32) -> Result<usize, String> {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    storage_tx.send(SqliteStorageAction::SelectUndelivered { before, limit: replay_rate, reply: reply_tx })
+        .map_err(|e| format!("Storage is not running: {:?}", e))?;
+    let undelivered = reply_rx.recv_timeout(STORAGE_REPLY_TIMEOUT).map_err(|e| format!("Storage did not reply: {:?}", e))?;
+    let delay = Duration::from_secs(1) / replay_rate;
+    for insert in &undelivered {
+        publish_insert(client, insert, qos).map_err(|e| format!("Error sending message: {:?}", e))?;
+        // Acknowledged before the next select, storage processes actions in order
+        acknowledge_insert(storage_tx, insert);
+        thread::sleep(delay);
+    }
+    Ok(undelivered.len())
+}
+
+/// Device name of a gateway connect/disconnect message eg: {"device": "Meter1"}
+fn message_device(message: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(message).ok()?;
+    Some(value["device"].as_str()?.to_string())
+}
+
+/// Sends RPC request to the channel that owns the device,
+/// requests that can not be routed are answered with an error right away
+fn route_rpc_request(client: &mqtt::AsyncClient, device_routes: &DeviceRoutes, payload: &str, qos: i32) {
+    log::debug!("Received RPC request: {}", payload);
+    let request = match parse_rpc_request(payload) {
+        Some(r) => r,
+        None => {
+            log::error!("Could not parse RPC request: {}", payload);
+            return;
+        }
+    };
+    let error = match device_routes.lock() {
+        Ok(routes) => match routes.get(&request.device) {
+            Some(channel_tx) => match channel_tx.send(ChannelAction::Rpc(request.clone())) {
+                Ok(_) => {
+                    log::debug!("Routed RPC request {} to device {}", request.id, request.device);
+                    return;
+                },
+                Err(e) => format!("Channel of device {} is not running: {:?}", request.device, e)
+            },
+            None => format!("Unknown device: {}", request.device)
+        },
+        Err(e) => format!("Device routes are poisoned: {:?}", e)
+    };
+    log::error!("Could not route RPC request {}: {}", request.id, error);
+    let response = json!({
+        "device": request.device,
+        "id": request.id,
+        "data": {"success": false, "error": error}
+    }).to_string();
+    client.publish(mqtt::Message::new(TB_GATEWAY_RPC_TOPIC, response.as_bytes(), qos));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::parse_rpc_request;
+
+    #[test]
+    fn parse_thingsboard_rpc_request() {
+        let request = parse_rpc_request(r#"{"device": "Meter1", "data": {"id": 7, "method": "setRelay", "params": {"value": true}}}"#).unwrap();
+        assert_eq!(request.device, "Meter1");
+        assert_eq!(request.id, 7);
+        assert_eq!(request.method, "setRelay");
+        assert_eq!(request.params, json!({"value": true}));
+
+        assert!(parse_rpc_request(r#"{"device": "Meter1"}"#).is_none());
+        assert!(parse_rpc_request("not json").is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io::Error;
 // use hex_literal::hex;
@@ -12,4 +13,43 @@ pub fn open_and_read(path: String) -> Result<(String, String), Error> {
     log::debug!("Hashed path: {} with hash: {:?}", path, content_hash);
 
     Ok((data, content_hash.encode_hex::<String>()))
-}
\ No newline at end of file
+}
+/// Paths whose content hash differs from the recorded one,
+/// files that can not be read are reported and treated as unchanged
+pub fn changed_files(hashes: &HashMap<String, String>) -> Vec<String> {
+    let mut changed = vec![];
+    for (path, hash) in hashes {
+        match open_and_read(path.clone()) {
+            Ok((_, new_hash)) => {
+                if &new_hash != hash {
+                    changed.push(path.clone());
+                }
+            },
+            Err(e) => log::warn!("Could not read configuration file: {}, {:?}", path, e)
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use super::{open_and_read, changed_files};
+
+    #[test]
+    fn detect_changed_files() {
+        // Unique per process so that concurrent test runs do not share the file
+        let path = std::env::temp_dir().join(format!("sts_gateway_changed_files_{}.yml", std::process::id())).display().to_string();
+        fs::write(&path, "name: a").unwrap();
+        let (_, hash) = open_and_read(path.clone()).unwrap();
+        let mut hashes = HashMap::new();
+        hashes.insert(path.clone(), hash);
+        hashes.insert(String::from("/nonexistent/sts_gateway.yml"), String::from("0"));
+        assert!(changed_files(&hashes).is_empty());
+
+        fs::write(&path, "name: b").unwrap();
+        assert_eq!(changed_files(&hashes), vec![path.clone()]);
+        fs::remove_file(&path).unwrap();
+    }
+}